@@ -12,12 +12,8 @@ fn eval_benchmark(c: &mut Criterion) {
         Ok(x) => x,
         Err(err) => panic!("failed to run code: {}", err),
     };
-    let resource = Resource { 
-        fft: &complex,
-        modulation: &vec![],
-        beat: 0.0,
-        second: 0.0,
-    };
+    let modulation = vec![];
+    let resource = Resource::new(&complex, &modulation);
     let data = (resource, code_value);
 
     c.bench_with_input(BenchmarkId::new("mutate", "1024"), &data, |b, (r, c)| {
@@ -27,4 +27,50 @@ pub const DEFAULT_OVERLAP_TIMES: usize = 1 << DEFAULT_OVERLAP_ORDER; // 16
 pub const MAX_OVERLAP_ORDER: usize = 5;
 
 #[allow(dead_code)]
-pub const MAX_OVERLAP_TIMES: usize = 1 << MAX_OVERLAP_ORDER; // 32
\ No newline at end of file
+pub const MAX_OVERLAP_TIMES: usize = 1 << MAX_OVERLAP_ORDER; // 32
+pub const NUM_MODULATION: usize = 16;
+
+/// The most past frames a program can read, each channel keeps this many spectra of
+/// `MAX_WINDOW_SIZE` allocated
+pub const MAX_HISTORY_FRAMES: usize = 32;
+
+pub const DEFAULT_HISTORY_FRAMES: usize = 8;
+
+/// How far a modulation value can drift before a staged program is specialised again
+pub const SPECIALISE_PARAM_TOLERANCE: f32 = 1e-3;
+
+/// How far the transport can move, in beats, before a staged program is specialised again
+pub const SPECIALISE_BEAT_TOLERANCE: f64 = 1.0 / 64.0;
+
+/// How far the transport can move, in seconds, before a staged program is specialised again
+pub const SPECIALISE_SECOND_TOLERANCE: f64 = 0.01;
+
+/// How long the safety limiter takes to recover from gain reduction, in seconds
+pub const LIMITER_RELEASE_SECONDS: f32 = 0.05;
+
+/// How long feedback read by `out_prev` takes at most to fade by 60 dB once the input stops, in
+/// seconds, which is also the tail reported for programs using it
+pub const FEEDBACK_DECAY_SECONDS: f32 = 10.0;
+
+/// How many cepstral coefficients the envelope read by `env` and `fine` keeps by default, which
+/// smooths over the harmonics of voices up to about 1 kHz at 44.1 kHz
+pub const DEFAULT_ENVELOPE_ORDER: usize = 40;
+
+/// Most cepstral coefficients the envelope can keep
+pub const MAX_ENVELOPE_ORDER: usize = 400;
+
+/// How many frames the median finding harmonic content runs over by default
+pub const DEFAULT_HPSS_FRAMES: usize = 9;
+
+/// The most frames the median finding harmonic content can run over, each channel keeps the
+/// magnitudes of this many frames of `MAX_WINDOW_SIZE` allocated
+pub const MAX_HPSS_FRAMES: usize = 31;
+
+/// How many bands the median finding percussive content runs over by default
+pub const DEFAULT_HPSS_BANDS: usize = 17;
+
+/// The most bands the median finding percussive content can run over
+pub const MAX_HPSS_BANDS: usize = 63;
+
+/// The largest value of the `Seed` param keying `noise`, `rand_phase` and `rand`
+pub const MAX_SEED: usize = 9999;
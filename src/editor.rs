@@ -3,6 +3,7 @@ use nih_plug::prelude::Editor;
 use nih_plug_vizia::vizia::prelude::*;
 use nih_plug_vizia::widgets::*;
 use nih_plug_vizia::{assets, create_vizia_editor, ViziaState, ViziaTheming};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use crate::PluginParams;
@@ -32,7 +33,7 @@ impl Model for Data {
 
 // Makes sense to also define this here, makes it a bit easier to keep track of
 pub(crate) fn default_state() -> Arc<ViziaState> {
-    ViziaState::new(|| (600, 360))
+    ViziaState::new(|| (600, 480))
 }
 
 pub(crate) fn create(
@@ -87,6 +88,33 @@ pub(crate) fn create(
             .width(Percentage(75.0))
             .bottom(Stretch(1.0));
 
+            // Safety indicator
+            Label::new(
+                cx,
+                Data::plugin_state.map(|st| {
+                    if st.sanitised.load(Ordering::Relaxed) {
+                        "Output clipped/sanitised"
+                    } else {
+                        ""
+                    }
+                    .to_string()
+                }),
+            )
+            .width(Percentage(75.0))
+            .bottom(Stretch(1.0));
+
+            // Safety params
+            GenericUi::new(cx, Data::params.map(|p| p.safety.clone())).bottom(Stretch(1.0));
+
+            // Native compilation status
+            #[cfg(feature = "jit")]
+            Label::new(
+                cx,
+                Data::plugin_state.map(|st| st.jit.status.lock().unwrap().to_string()),
+            )
+            .width(Percentage(75.0))
+            .bottom(Stretch(1.0));
+
             // Error message
             Label::new(
                 cx,
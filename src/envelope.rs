@@ -0,0 +1,126 @@
+use realfft::num_complex::Complex32;
+use realfft::{ComplexToReal, RealToComplex};
+
+/// Bins this far below the loudest one are raised to it, so that silent bins don't drag the
+/// envelope down to nothing (-100 dB)
+const FLOOR: f32 = 1e-5;
+
+/// Estimates the spectral envelope of a frame by cepstral liftering. The log magnitudes are
+/// transformed into a cepstrum, which is cut off after its first coefficients and transformed
+/// back, keeping slow changes across bins like formants and dropping fast ones like harmonics.
+pub struct Envelope {
+    /// The log magnitudes, then the smoothed log magnitudes
+    log: Vec<Complex32>,
+    /// The cepstrum of the log magnitudes
+    cepstrum: Vec<f32>,
+}
+
+impl Envelope {
+    /// All buffers are allocated with `max_window_size` capacity, so smaller windows won't
+    /// allocate
+    pub fn new(max_window_size: usize) -> Self {
+        Self {
+            log: Vec::with_capacity(max_window_size / 2 + 1),
+            cepstrum: Vec::with_capacity(max_window_size),
+        }
+    }
+
+    /// Write the envelope of `spectrum` into `out`, keeping `order` cepstral coefficients. The
+    /// plans must be for the window size of `spectrum`. Silent and non-finite spectra have a
+    /// silent envelope.
+    pub fn estimate(
+        &mut self,
+        spectrum: &[Complex32],
+        order: usize,
+        r2c: &dyn RealToComplex<f32>,
+        c2r: &dyn ComplexToReal<f32>,
+        out: &mut [f32],
+    ) {
+        let window_size = c2r.len();
+        let peak = spectrum.iter().map(|bin| bin.norm()).fold(0.0, f32::max);
+        if !(peak > 0.0 && peak.is_finite()) || spectrum.len() != window_size / 2 + 1 {
+            out.fill(0.0);
+            return;
+        }
+        let floor = (peak * FLOOR).max(f32::MIN_POSITIVE);
+        self.log.clear();
+        self.log
+            .extend(spectrum.iter().map(|bin| Complex32::new(bin.norm().max(floor).ln(), 0.0)));
+        self.cepstrum.clear();
+        self.cepstrum.resize(window_size, 0.0);
+        if c2r
+            .process_with_scratch(&mut self.log, &mut self.cepstrum, &mut [])
+            .is_err()
+        {
+            out.fill(0.0);
+            return;
+        }
+
+        // The cepstrum is symmetric, both ends are kept
+        let order = order.clamp(1, window_size / 2 + 1);
+        for (n, coefficient) in self.cepstrum.iter_mut().enumerate() {
+            if n >= order && n + order <= window_size {
+                *coefficient = 0.0;
+            }
+        }
+        if r2c
+            .process_with_scratch(&mut self.cepstrum, &mut self.log, &mut [])
+            .is_err()
+        {
+            out.fill(0.0);
+            return;
+        }
+
+        // Both transforms are unnormalised
+        let scale = (window_size as f32).recip();
+        for (out, log) in out.iter_mut().zip(&self.log) {
+            *out = (log.re * scale).exp();
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_envelope {
+    use super::*;
+    use realfft::RealFftPlanner;
+
+    #[test]
+    fn test_envelope_follows_formants() {
+        let window_size = 512;
+        let len = window_size / 2 + 1;
+        let mut planner = RealFftPlanner::<f32>::new();
+        let (r2c, c2r) = (
+            planner.plan_fft_forward(window_size),
+            planner.plan_fft_inverse(window_size),
+        );
+
+        // Harmonics every 8 bins, 60 dB above the noise between them, under a smooth formant
+        let formant = |k: usize| (2.0 * (std::f32::consts::PI * k as f32 / (len - 1) as f32).cos()).exp();
+        let spectrum: Vec<Complex32> = (0..len)
+            .map(|k| {
+                let harmonic = if k % 8 == 0 { 1.0 } else { 1e-3 };
+                Complex32::from_polar(formant(k) * harmonic, k as f32)
+            })
+            .collect();
+        let mut envelope = Envelope::new(1024);
+        let mut out = vec![0.0; len];
+        envelope.estimate(&spectrum, 20, &*r2c, &*c2r, &mut out);
+
+        // The envelope is the formant, scaled by the mean log level of the harmonics
+        let ratios: Vec<f32> = (0..len).map(|k| (out[k] / formant(k)).ln()).collect();
+        let (min, max) = ratios
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), r| (min.min(*r), max.max(*r)));
+        assert!(max - min < 0.05, "log ratios from {} to {}", min, max);
+        assert!((ratios[0] - 1e-3f32.ln() * 7.0 / 8.0).abs() < 0.05);
+
+        // Silence has a silent envelope, keeping every coefficient gives the magnitudes back, and
+        // the buffers don't grow
+        envelope.estimate(&vec![Complex32::default(); len], 20, &*r2c, &*c2r, &mut out);
+        assert!(out.iter().all(|x| *x == 0.0));
+        envelope.estimate(&spectrum, 10_000, &*r2c, &*c2r, &mut out);
+        assert!((out[8] - spectrum[8].norm()).abs() < 1e-3 * spectrum[8].norm());
+        assert_eq!(envelope.cepstrum.capacity(), 1024);
+    }
+}
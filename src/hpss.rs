@@ -0,0 +1,139 @@
+use realfft::num_complex::Complex32;
+
+/// Separates harmonic from percussive content by median filtering magnitudes. Harmonics are
+/// steady over time and percussion is spread over frequency, so each is what survives a median
+/// along its own axis. Every buffer is allocated up front, so separating never allocates.
+#[derive(Default)]
+pub struct Hpss {
+    /// Magnitudes of recent frames, a ring buffer where each frame has capacity for the longest
+    /// spectrum
+    frames: Vec<Vec<f32>>,
+    /// Index of the most recent frame
+    head: usize,
+    /// Number of frames pushed since the last clear, at most the number allocated
+    filled: usize,
+    /// Values of the kernel currently being filtered
+    kernel: Vec<f32>,
+}
+
+impl Hpss {
+    /// Up to `max_frames` frames and `max_bands` bands can be filtered over
+    pub fn new(max_frames: usize, max_bands: usize, max_bins: usize) -> Self {
+        Self {
+            frames: vec![Vec::with_capacity(max_bins); max_frames],
+            head: 0,
+            filled: 0,
+            kernel: Vec::with_capacity(max_frames.max(max_bands)),
+        }
+    }
+
+    pub fn max_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Forget all frames, like after the window size changes
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.filled = 0;
+    }
+
+    /// Record the magnitudes of the current frame, which must not be longer than the spectra the
+    /// buffer was allocated for
+    pub fn push(&mut self, spectrum: &[Complex32]) {
+        if self.frames.is_empty() {
+            return;
+        }
+        self.head = (self.head + 1) % self.frames.len();
+        self.filled = (self.filled + 1).min(self.frames.len());
+        let frame = &mut self.frames[self.head];
+        frame.clear();
+        frame.extend(spectrum.iter().map(|bin| bin.norm()));
+    }
+
+    /// Write the fraction of each bin of the last pushed frame that is harmonic into `out`, with
+    /// medians over `frames` frames up to the last one and over `bands` bands centred on each bin.
+    /// Bins where both medians are silent count as harmonic.
+    pub fn separate(&mut self, frames: usize, bands: usize, out: &mut [f32]) {
+        if self.filled == 0 {
+            out.fill(1.0);
+            return;
+        }
+        let frames = frames.clamp(1, self.filled);
+        let bands = bands.min(self.kernel.capacity()).max(1);
+        let len = self.frames.len();
+        let current = &self.frames[self.head];
+        for (k, out) in out.iter_mut().enumerate() {
+            let Some(magnitude) = current.get(k) else {
+                *out = 1.0;
+                continue;
+            };
+            self.kernel.clear();
+            self.kernel.extend(
+                (0..frames).filter_map(|j| self.frames[(self.head + len - j) % len].get(k)),
+            );
+            let harmonic = median(&mut self.kernel).unwrap_or(*magnitude);
+
+            // The kernel is cut short at both ends of the spectrum
+            let start = k.saturating_sub(bands / 2);
+            let end = (k + bands / 2 + 1).min(current.len());
+            self.kernel.clear();
+            self.kernel.extend_from_slice(&current[start..end]);
+            let percussive = median(&mut self.kernel).unwrap_or(*magnitude);
+
+            // A soft mask, so that the two parts always sum back to the input
+            let (harmonic, percussive) = (harmonic * harmonic, percussive * percussive);
+            *out = if harmonic + percussive > 0.0 {
+                harmonic / (harmonic + percussive)
+            } else {
+                1.0
+            };
+        }
+    }
+}
+
+/// Median of some values, reordering them. Non-finite values sort by their bits.
+fn median(values: &mut [f32]) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    let middle = values.len() / 2;
+    Some(*values.select_nth_unstable_by(middle, f32::total_cmp).1)
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_hpss {
+    use super::*;
+
+    #[test]
+    fn test_hpss_separates() {
+        let len = 32;
+        let mut hpss = Hpss::new(8, 9, len);
+        let mut out = vec![0.0; len];
+        hpss.separate(5, 5, &mut out);
+        assert!(out.iter().all(|x| *x == 1.0));
+
+        // A steady tone at bin 10, and a click spread over every bin on the last frame
+        for t in 0..6 {
+            let frame: Vec<Complex32> = (0..len)
+                .map(|k| {
+                    let tone = if k == 10 { 4.0 } else { 0.0 };
+                    let click = if t == 5 { 1.0 } else { 0.0 };
+                    Complex32::new(tone + click, 0.0)
+                })
+                .collect();
+            hpss.push(&frame);
+        }
+        hpss.separate(5, 5, &mut out);
+        assert!(out[10] > 0.9, "tone is {}", out[10]);
+        assert!(out[20] < 0.1, "click is {}", out[20]);
+
+        // Only the frames that were pushed are filtered over, and the buffers don't grow
+        hpss.clear();
+        hpss.push(&vec![Complex32::new(1.0, 0.0); len]);
+        hpss.separate(5, 5, &mut out);
+        assert!(out.iter().all(|x| *x == 0.5));
+        hpss.separate(100, 100, &mut out);
+        assert_eq!(hpss.kernel.capacity(), 9);
+    }
+}
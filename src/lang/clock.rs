@@ -0,0 +1,207 @@
+use std::f32::consts::TAU;
+
+/// Tempo of the internal clock, used when the host doesn't send one
+pub const DEFAULT_TEMPO: f64 = 120.0;
+
+/// A value of the transport, read as a symbol
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timing {
+    /// Beats per minute
+    Tempo,
+    /// Number of the current bar, from 0
+    Bar,
+    /// Beats since the start of the current bar
+    BarBeat,
+    /// Beats per bar
+    Numerator,
+    /// Note value of a beat
+    Denominator,
+    /// Start of the loop in beats, 0 without a loop
+    LoopStart,
+    /// End of the loop in beats, 0 without a loop
+    LoopEnd,
+}
+
+impl Timing {
+    /// Every timing, indexed by `Timing as usize`
+    pub const ALL: [Timing; 7] = [
+        Timing::Tempo,
+        Timing::Bar,
+        Timing::BarBeat,
+        Timing::Numerator,
+        Timing::Denominator,
+        Timing::LoopStart,
+        Timing::LoopEnd,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Timing::Tempo => "tempo",
+            Timing::Bar => "bar",
+            Timing::BarBeat => "bar_beat",
+            Timing::Numerator => "time_sig_num",
+            Timing::Denominator => "time_sig_den",
+            Timing::LoopStart => "loop_start",
+            Timing::LoopEnd => "loop_end",
+        }
+    }
+}
+
+/// Shape of a tempo-synced LFO
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wave {
+    Sin,
+    Saw,
+    Square,
+}
+
+impl Wave {
+    pub const ALL: [Wave; 3] = [Wave::Sin, Wave::Saw, Wave::Square];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Wave::Sin => "lfo_sin",
+            Wave::Saw => "lfo_saw",
+            Wave::Square => "lfo_square",
+        }
+    }
+}
+
+/// The host transport of a block, or an internal clock standing in for it. Positions are in
+/// quarter notes, like `beat`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Clock {
+    pub tempo: f64,
+    pub playing: bool,
+    pub numerator: i32,
+    pub denominator: i32,
+    /// Number of the current bar, from 0
+    pub bar: i32,
+    /// Position of the start of the current bar
+    pub bar_start: f64,
+    /// `None` without a loop
+    pub loop_range: Option<(f64, f64)>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(DEFAULT_TEMPO, false, 4, 4, 0.0)
+    }
+}
+
+impl Clock {
+    /// A clock at `beat`, with bars counted from the start as if the time signature never
+    /// changed. Time signatures that aren't positive count as 4/4.
+    pub fn new(tempo: f64, playing: bool, numerator: i32, denominator: i32, beat: f64) -> Self {
+        let (numerator, denominator) = match (numerator, denominator) {
+            (1.., 1..) => (numerator, denominator),
+            _ => (4, 4),
+        };
+        let bar_length = numerator as f64 * 4.0 / denominator as f64;
+        let bar = (beat / bar_length).floor();
+        Self {
+            tempo,
+            playing,
+            numerator,
+            denominator,
+            bar: bar as i32,
+            bar_start: bar * bar_length,
+            loop_range: None,
+        }
+    }
+
+    /// The value of a timing at `beat`
+    pub fn get(&self, timing: Timing, beat: f64) -> f32 {
+        match timing {
+            Timing::Tempo => self.tempo as f32,
+            Timing::Bar => self.bar as f32,
+            Timing::BarBeat => (beat - self.bar_start) as f32,
+            Timing::Numerator => self.numerator as f32,
+            Timing::Denominator => self.denominator as f32,
+            Timing::LoopStart => self.loop_range.map_or(0.0, |(start, _)| start as f32),
+            Timing::LoopEnd => self.loop_range.map_or(0.0, |(_, end)| end as f32),
+        }
+    }
+}
+
+/// A wave from -1 to 1 at `beat`, starting a cycle every `period` beats from the start of the
+/// song. Periods that aren't positive give 0.
+pub fn lfo(wave: Wave, beat: f64, period: f32) -> f32 {
+    if period.is_nan() || period <= 0.0 {
+        return 0.0;
+    }
+    let phase = (beat / period as f64).rem_euclid(1.0) as f32;
+    match wave {
+        Wave::Sin => (TAU * phase).sin(),
+        Wave::Saw => phase * 2.0 - 1.0,
+        Wave::Square => {
+            if phase < 0.5 {
+                1.0
+            } else {
+                -1.0
+            }
+        }
+    }
+}
+
+/// Index of the current step at `beat` of a sequence of `steps` steps, each `step_beats` beats
+/// long, counting from 0. Sequences without a whole step or with steps that aren't positive give
+/// 0.
+pub fn step_seq(beat: f64, steps: f32, step_beats: f32) -> f32 {
+    if !(steps >= 1.0 && step_beats > 0.0) {
+        return 0.0;
+    }
+    (beat / step_beats as f64).floor().rem_euclid(steps.floor() as f64) as f32
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_clock {
+    use super::*;
+
+    #[test]
+    fn test_clock_bars() {
+        let clock = Clock::new(90.0, true, 3, 4, 7.5);
+        assert_eq!((clock.bar, clock.bar_start), (2, 6.0));
+        assert_eq!(clock.get(Timing::BarBeat, 7.5), 1.5);
+        assert_eq!(clock.get(Timing::Tempo, 7.5), 90.0);
+
+        // Eighth notes are half a beat
+        let clock = Clock::new(120.0, true, 7, 8, 7.5);
+        assert_eq!((clock.bar, clock.bar_start), (2, 7.0));
+        let clock = Clock::new(120.0, true, 0, 4, 7.5);
+        assert_eq!((clock.numerator, clock.bar), (4, 1));
+
+        // Loops read as 0 without one
+        let clock = Clock {
+            loop_range: Some((4.0, 12.0)),
+            ..Clock::default()
+        };
+        assert_eq!(clock.get(Timing::LoopStart, 0.0), 4.0);
+        assert_eq!(clock.get(Timing::LoopEnd, 0.0), 12.0);
+        assert_eq!(Clock::default().get(Timing::LoopEnd, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_lfo() {
+        assert!(lfo(Wave::Sin, 0.0, 2.0).abs() < 1e-6);
+        assert!((lfo(Wave::Sin, 0.5, 2.0) - 1.0).abs() < 1e-6);
+        assert!((lfo(Wave::Sin, 1000.5, 2.0) - 1.0).abs() < 1e-4);
+        assert_eq!(lfo(Wave::Saw, 3.0, 4.0), 0.5);
+        assert_eq!(lfo(Wave::Saw, -1.0, 4.0), 0.5);
+        assert_eq!(lfo(Wave::Square, 1.5, 4.0), 1.0);
+        assert_eq!(lfo(Wave::Square, 2.5, 4.0), -1.0);
+        for period in [0.0, -1.0, f32::NAN] {
+            assert_eq!(lfo(Wave::Sin, 1.0, period), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_step_seq() {
+        let steps: Vec<f32> = (0..10).map(|k| step_seq(k as f64 * 0.5, 4.0, 0.5)).collect();
+        assert_eq!(steps, [0.0, 1.0, 2.0, 3.0, 0.0, 1.0, 2.0, 3.0, 0.0, 1.0]);
+        assert_eq!(step_seq(-0.25, 4.0, 0.5), 3.0);
+        assert_eq!(step_seq(1.0, 0.5, 0.5), 0.0);
+        assert_eq!(step_seq(1.0, 4.0, 0.0), 0.0);
+    }
+}
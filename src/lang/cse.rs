@@ -0,0 +1,230 @@
+use super::*;
+
+/// Bind sub-terms that occur more than once with `Term::Let`, so they are evaluated only once.
+/// `ctx` holds the types of the variables in scope, and will only be temporarily mutated.
+pub fn cse(term: Term, ctx: &mut Vec<ValueType>) -> Term {
+    // Eliminate inside nested scopes first, their variables differ from ours
+    let term = match term {
+        Term::Func(param_type, name, body) => {
+            ctx.push(*param_type.clone());
+            let body = cse(*body, ctx);
+            ctx.pop();
+            return Term::Func(param_type, name, body.into());
+        }
+        other => descend(other, ctx),
+    };
+
+    // Bind the smallest duplicate first, so that larger ones are found on its variable
+    let mut counts: Vec<(Term, usize)> = Vec::new();
+    count(&term, &mut counts);
+    let duplicate = counts
+        .into_iter()
+        .filter(|(_, n)| *n > 1)
+        .filter_map(|(t, _)| match type_of(&t, ctx) {
+            Ok(ValueType::Func(_, _)) | Err(_) => None,
+            Ok(value_type) => Some((t, value_type)),
+        })
+        .min_by_key(|(t, _)| size(t));
+    match duplicate {
+        Some((duplicate, value_type)) => {
+            let next = replace(shift(term, 0, 1), &shift(duplicate.clone(), 0, 1), 0);
+            ctx.push(value_type.clone());
+            let next = cse(next, ctx);
+            ctx.pop();
+            Term::Let(value_type.into(), "".into(), duplicate.into(), next.into())
+        }
+        None => term,
+    }
+}
+
+/// Eliminate inside the scopes introduced by `Let` and `Func` within a term
+fn descend(term: Term, ctx: &mut Vec<ValueType>) -> Term {
+    match term {
+        Term::Tuple(terms) => Term::Tuple(terms.into_iter().map(|t| descend(t, ctx)).collect()),
+        Term::Apply(func, arg) => Term::Apply(descend(*func, ctx).into(), descend(*arg, ctx).into()),
+        Term::Func(_, _, _) => cse(term, ctx),
+        Term::Let(value_type, name, value, next) => {
+            let value = descend(*value, ctx);
+            ctx.push(*value_type.clone());
+            let next = cse(*next, ctx);
+            ctx.pop();
+            Term::Let(value_type, name, value.into(), next.into())
+        }
+        Term::Alt(cond, then, else_) => Term::Alt(
+            descend(*cond, ctx).into(),
+            descend(*then, ctx).into(),
+            descend(*else_, ctx).into(),
+        ),
+        other => other,
+    }
+}
+
+/// Count compound sub-terms in the current scope, not looking into binders
+fn count(term: &Term, counts: &mut Vec<(Term, usize)>) {
+    match term {
+        Term::Tuple(terms) => terms.iter().for_each(|t| count(t, counts)),
+        Term::Apply(func, arg) => {
+            count(func, counts);
+            count(arg, counts);
+        }
+        Term::Let(_, _, value, _) => count(value, counts),
+        Term::Alt(cond, then, else_) => {
+            count(cond, counts);
+            count(then, counts);
+            count(else_, counts);
+        }
+        _ => return,
+    }
+    if matches!(term, Term::Apply(_, _)) && !contains_binder(term) {
+        match counts.iter_mut().find(|(t, _)| t == term) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((term.clone(), 1)),
+        }
+    }
+}
+
+fn contains_binder(term: &Term) -> bool {
+    match term {
+        Term::Func(_, _, _) | Term::Let(_, _, _, _) => true,
+        Term::Tuple(terms) => terms.iter().any(contains_binder),
+        Term::Apply(func, arg) => contains_binder(func) || contains_binder(arg),
+        Term::Alt(cond, then, else_) => {
+            contains_binder(cond) || contains_binder(then) || contains_binder(else_)
+        }
+        _ => false,
+    }
+}
+
+fn size(term: &Term) -> usize {
+    match term {
+        Term::Tuple(terms) => 1 + terms.iter().map(size).sum::<usize>(),
+        Term::Apply(func, arg) => 1 + size(func) + size(arg),
+        Term::Func(_, _, body) => 1 + size(body),
+        Term::Let(_, _, value, next) => 1 + size(value) + size(next),
+        Term::Alt(cond, then, else_) => 1 + size(cond) + size(then) + size(else_),
+        _ => 1,
+    }
+}
+
+/// Shift variables at or above `cutoff` by `by`
+pub fn shift(term: Term, cutoff: Index, by: Index) -> Term {
+    match term {
+        Term::Var(v) if v >= cutoff => Term::Var(v + by),
+        Term::Tuple(terms) => Term::Tuple(terms.into_iter().map(|t| shift(t, cutoff, by)).collect()),
+        Term::Apply(func, arg) => Term::Apply(
+            shift(*func, cutoff, by).into(),
+            shift(*arg, cutoff, by).into(),
+        ),
+        Term::Func(param_type, name, body) => {
+            Term::Func(param_type, name, shift(*body, cutoff + 1, by).into())
+        }
+        Term::Let(value_type, name, value, next) => Term::Let(
+            value_type,
+            name,
+            shift(*value, cutoff, by).into(),
+            shift(*next, cutoff + 1, by).into(),
+        ),
+        Term::Alt(cond, then, else_) => Term::Alt(
+            shift(*cond, cutoff, by).into(),
+            shift(*then, cutoff, by).into(),
+            shift(*else_, cutoff, by).into(),
+        ),
+        other => other,
+    }
+}
+
+/// Replace occurrences of `target` with `Var(depth)`, not looking into binders
+fn replace(term: Term, target: &Term, depth: Index) -> Term {
+    if term == *target {
+        return Term::Var(depth);
+    }
+    match term {
+        Term::Tuple(terms) => {
+            Term::Tuple(terms.into_iter().map(|t| replace(t, target, depth)).collect())
+        }
+        Term::Apply(func, arg) => Term::Apply(
+            replace(*func, target, depth).into(),
+            replace(*arg, target, depth).into(),
+        ),
+        Term::Let(value_type, name, value, next) => Term::Let(
+            value_type,
+            name,
+            replace(*value, target, depth).into(),
+            next,
+        ),
+        Term::Alt(cond, then, else_) => Term::Alt(
+            replace(*cond, target, depth).into(),
+            replace(*then, target, depth).into(),
+            replace(*else_, target, depth).into(),
+        ),
+        other => other,
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_cse {
+    use super::*;
+
+    fn fft(i: Index) -> Term {
+        Term::Apply(Term::Lib(Lib::Fft).into(), Term::Var(i).into())
+    }
+
+    fn apply(lib: Lib, arg: Term) -> Term {
+        Term::Apply(Term::Lib(lib).into(), arg.into())
+    }
+
+    #[test]
+    fn test_cse_shared() {
+        let program = run("(i: Float) => (fft(i).norm * param(i), fft(i).norm + fft(i).angle)").unwrap();
+        let complex = ValueType::Tuple(vec![ValueType::Float, ValueType::Float]);
+        assert_eq!(
+            program.body,
+            Term::Let(
+                complex.into(),
+                "".into(),
+                fft(0).into(),
+                Term::Let(
+                    ValueType::Float.into(),
+                    "".into(),
+                    apply(Lib::Norm, Term::Var(0)).into(),
+                    Term::Tuple(vec![
+                        Term::Apply(
+                            apply(Lib::Mul, Term::Var(0)).into(),
+                            apply(Lib::Param, Term::Var(2)).into(),
+                        ),
+                        Term::Apply(
+                            apply(Lib::Add, Term::Var(0)).into(),
+                            apply(Lib::Angle, Term::Var(1)).into(),
+                        ),
+                    ])
+                    .into(),
+                )
+                .into(),
+            )
+        );
+    }
+
+    #[test]
+    fn test_cse_unique() {
+        let program = run("(i: Float) => (fft(i).norm, fft(i * 2).angle)").unwrap();
+        assert!(!matches!(program.body, Term::Let(_, _, _, _)));
+    }
+
+    #[test]
+    fn test_shift() {
+        let term = Term::Func(
+            ValueType::Float.into(),
+            "".into(),
+            Term::Tuple(vec![Term::Var(0), Term::Var(1)]).into(),
+        );
+        assert_eq!(
+            shift(term, 0, 2),
+            Term::Func(
+                ValueType::Float.into(),
+                "".into(),
+                Term::Tuple(vec![Term::Var(0), Term::Var(3)]).into(),
+            )
+        );
+    }
+}
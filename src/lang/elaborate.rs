@@ -73,6 +73,47 @@ pub fn check(syntax: Syntax, ctx: Ctx, expected: ValueType, env_len: Level) -> R
     Err(format!("Type mismatch: {} != {}", inferred_type, expected))
 }
 
+/// Infer the type of an elaborated term, `ctx` holding the types of bound variables
+/// `ctx` will only be temporarily mutated
+pub fn type_of(term: &Term, ctx: &mut Vec<ValueType>) -> Result<ValueType, ElaborateError> {
+    match term {
+        Term::Float(_) => Ok(ValueType::Float),
+        Term::Bool(_) => Ok(ValueType::Bool),
+        Term::Var(v) => usize::try_from(*v)
+            .ok()
+            .and_then(|v| ctx.len().checked_sub(v + 1))
+            .map(|i| ctx[i].clone())
+            .ok_or_else(|| format!("Variable not found: {}", v)),
+        Term::Lib(lib) => Ok(lib.clone().into()),
+        Term::Tuple(terms) => Ok(ValueType::Tuple(
+            terms.iter().map(|t| type_of(t, ctx)).collect::<Result<_, _>>()?,
+        )),
+        Term::Apply(func, arg) => match type_of(func, ctx)? {
+            ValueType::Func(param_type, ret_type) => {
+                let arg_type = type_of(arg, ctx)?;
+                if arg_type != *param_type {
+                    return Err(format!("Type mismatch: {} != {}", arg_type, param_type));
+                }
+                Ok(*ret_type)
+            }
+            func_type => Err(format!("Not a function: {}", func_type)),
+        },
+        Term::Func(param_type, _, body) => {
+            ctx.push(*param_type.clone());
+            let body_type = type_of(body, ctx);
+            ctx.pop();
+            Ok(ValueType::Func(param_type.clone(), Box::new(body_type?)))
+        }
+        Term::Let(value_type, _, _, next) => {
+            ctx.push(*value_type.clone());
+            let next_type = type_of(next, ctx);
+            ctx.pop();
+            next_type
+        }
+        Term::Alt(_, then, else_) => unify(type_of(then, ctx)?, type_of(else_, ctx)?),
+    }
+}
+
 pub fn unify(t1: ValueType, t2: ValueType) -> Result<ValueType, ElaborateError> {
     match (t1, t2) {
         (ValueType::Float, ValueType::Float) => Ok(ValueType::Float),
@@ -197,6 +238,32 @@ pub mod tests_elaborate {
         }
     }
 
+    #[test]
+    fn test_type_of() {
+        let code = Syntax::Func(
+            Box::new(ValueType::Float),
+            "x".to_string(),
+            Box::new(Syntax::Let(
+                Box::new(ValueType::Bool),
+                "y".to_string(),
+                Box::new(Syntax::Bool(true)),
+                Box::new(Syntax::Alt(
+                    Box::new(Syntax::Var("y".to_string())),
+                    Box::new(Syntax::Apply(
+                        Box::new(Syntax::Lib(Lib::Sin)),
+                        Box::new(Syntax::Var("x".to_string())),
+                    )),
+                    Box::new(Syntax::Float(90.0)),
+                )),
+            )),
+        );
+        let ctx = Ctx::new();
+        match infer(code.clone(), ctx, 0) {
+            Ok((term, value_type)) => assert_eq!(type_of(&term, &mut Vec::new()), Ok(value_type)),
+            Err(err) => panic!("failed to infer {:?}: {}", code, err),
+        }
+    }
+
     #[test]
     fn test_tuple() {
         let code = Syntax::Tuple(vec![
@@ -2,69 +2,118 @@ use super::*;
 
 pub type Env = Vec<Value>;
 
+/// An error during evaluation. It doesn't allocate, so it can be raised on the audio thread.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvalError {
+    /// A library function was applied to a value it doesn't accept
+    Argument(&'static str),
+    NotBool,
+    NotFloat,
+    NotComplex,
+    /// A variable is missing from the environment
+    Unbound(Index),
+    /// Integer arithmetic went out of range
+    Overflow,
+    /// A program with state didn't give its next state and output
+    NotStep,
+}
+
+impl std::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::Argument(name) => write!(f, "lib function {} got a bad argument", name),
+            EvalError::NotBool => write!(f, "value is not a boolean"),
+            EvalError::NotFloat => write!(f, "value is not a float"),
+            EvalError::NotComplex => write!(f, "value is not a complex number"),
+            EvalError::Unbound(v) => write!(f, "variable {} is not bound", v),
+            EvalError::Overflow => write!(f, "integer overflow"),
+            EvalError::NotStep => write!(f, "step didn't give the next state and an output"),
+        }
+    }
+}
+
+/// Look up a de Bruijn index in an environment
+fn lookup(env: &Env, v: Index) -> Result<usize, EvalError> {
+    (env.len() as Index)
+        .checked_sub(v + 1)
+        .filter(|i| *i >= 0 && v >= 0)
+        .map(|i| i as usize)
+        .ok_or(EvalError::Unbound(v))
+}
+
 /// Evaluate a reference to a term
-/// `term` and `env` will only be temporarily mutated
-pub fn eval(term: &mut Term, env: &mut Env, res: &Resource) -> Value {
+/// `env` will only be temporarily mutated
+pub fn eval(term: &Term, env: &mut Env, res: &Resource) -> Result<Value, EvalError> {
     match term {
-        Term::Float(x) => Value::Float(*x),
-        Term::Bool(x) => Value::Bool(*x),
-        Term::Var(v) => env
-            .get(env.len() - *v as usize - 1)
-            .unwrap()
-            .clone(),
-        Term::Apply(func, arg) => eval(func, env, res).apply(eval(arg, env, res), res),
-        Term::Lib(x) => x.clone().to_value(res),
-        Term::Tuple(terms) => Value::Tuple(terms.iter_mut().map(|t| eval(t, env, res)).collect()),
-        Term::Func(return_type, name, body) => Value::Func(
+        Term::Float(x) => Ok(Value::Float(*x)),
+        Term::Bool(x) => Ok(Value::Bool(*x)),
+        Term::Var(v) => Ok(env[lookup(env, *v)?].clone()),
+        Term::Apply(func, arg) => {
+            let mut func = eval(func, env, res)?;
+            func.apply(eval(arg, env, res)?, res)
+        }
+        Term::Lib(x) => Ok(x.clone().to_value(res)),
+        Term::Tuple(terms) => Ok(Value::Tuple(
+            terms
+                .iter()
+                .map(|t| eval(t, env, res))
+                .collect::<Result<_, _>>()?,
+        )),
+        Term::Func(return_type, name, body) => Ok(Value::Func(
             return_type.clone(),
             Closure(body.clone(), env.clone(), name.clone()),
-        ),
+        )),
         Term::Let(_, _, body, next) => {
-            let value = eval(body, env, res);
+            let value = eval(body, env, res)?;
             env.push(value);
             let result = eval(next, env, res);
             env.pop();
             result
         }
-        Term::Alt(cond, then, else_) => match eval(cond, env, res) {
+        Term::Alt(cond, then, else_) => match eval(cond, env, res)? {
             Value::Bool(true) => eval(then, env, res),
             Value::Bool(false) => eval(else_, env, res),
-            other => panic!("{} is not a boolean", other),
+            _ => Err(EvalError::NotBool),
         },
     }
 }
 
 /// Partially evaluate a term
 /// `env` will only be temporarily mutated
-pub fn peval(term: Term, env: &mut Env) -> Value {
+pub fn peval(term: Term, env: &mut Env) -> Result<Value, EvalError> {
     match term {
-        Term::Float(x) => Value::Float(x),
-        Term::Bool(x) => Value::Bool(x),
-        Term::Var(v) => env
-            .get(env.len() - v as usize - 1)
-            .unwrap()
-            .clone(),
-        Term::Apply(func, arg) => peval(*func, env).papply(peval(*arg, env)),
-        Term::Lib(x) => Value::Lib(x),
-        Term::Tuple(terms) => Value::Tuple(terms.into_iter().map(|t| peval(t, env)).collect()),
-        Term::Func(return_type, name, body) => Value::Func(
+        Term::Float(x) => Ok(Value::Float(x)),
+        Term::Bool(x) => Ok(Value::Bool(x)),
+        Term::Var(v) => Ok(env[lookup(env, v)?].clone()),
+        Term::Apply(func, arg) => {
+            let func = peval(*func, env)?;
+            func.papply(peval(*arg, env)?)
+        }
+        Term::Lib(x) => Ok(Value::Lib(x)),
+        Term::Tuple(terms) => Ok(Value::Tuple(
+            terms
+                .into_iter()
+                .map(|t| peval(t, env))
+                .collect::<Result<_, _>>()?,
+        )),
+        Term::Func(return_type, name, body) => Ok(Value::Func(
             return_type,
             Closure(body, env.clone(), name),
-        ),
+        )),
         Term::Let(_, _, body, next) => {
-            let value = peval(*body, env);
+            let value = peval(*body, env)?;
             env.push(value);
             let result = peval(*next, env);
             env.pop();
             result
         }
-        Term::Alt(cond, then, else_) => match peval(*cond, env) {
+        Term::Alt(cond, then, else_) => match peval(*cond, env)? {
             Value::Bool(true) => peval(*then, env),
             Value::Bool(false) => peval(*else_, env),
             other => {
-                let then = peval(*then, env);
-                let else_ = peval(*else_, env);
-                Value::Alt(other.into(), then.into(), else_.into())
+                let then = peval(*then, env)?;
+                let else_ = peval(*else_, env)?;
+                Ok(Value::Alt(other.into(), then.into(), else_.into()))
             }
         },
     }
@@ -72,33 +121,38 @@ pub fn peval(term: Term, env: &mut Env) -> Value {
 
 /// Partially evaluate a closure (which includes owned env)
 /// Consumes the environment
-pub fn peval_closure(term: Term, mut env: Env) -> Value {
+pub fn peval_closure(term: Term, mut env: Env) -> Result<Value, EvalError> {
     match term {
-        Term::Float(x) => Value::Float(x),
-        Term::Bool(x) => Value::Bool(x),
-        Term::Var(v) => env.swap_remove(env.len() - v as usize - 1),
+        Term::Float(x) => Ok(Value::Float(x)),
+        Term::Bool(x) => Ok(Value::Bool(x)),
+        Term::Var(v) => Ok(env.swap_remove(lookup(&env, v)?)),
         Term::Apply(func, arg) => {
-            let arg = peval(*arg, &mut env);
-            peval_closure(*func, env).papply(arg)
+            let arg = peval(*arg, &mut env)?;
+            peval_closure(*func, env)?.papply(arg)
         },
-        Term::Lib(x) => Value::Lib(x),
-        Term::Tuple(terms) => Value::Tuple(terms.into_iter().map(|t| peval(t, &mut env)).collect()),
-        Term::Func(return_type, name, body) => Value::Func(
+        Term::Lib(x) => Ok(Value::Lib(x)),
+        Term::Tuple(terms) => Ok(Value::Tuple(
+            terms
+                .into_iter()
+                .map(|t| peval(t, &mut env))
+                .collect::<Result<_, _>>()?,
+        )),
+        Term::Func(return_type, name, body) => Ok(Value::Func(
             return_type,
             Closure(body, env, name),
-        ),
+        )),
         Term::Let(_, _, body, next) => {
-            let value = peval(*body, &mut env);
+            let value = peval(*body, &mut env)?;
             env.push(value);
             peval_closure(*next, env)
         }
-        Term::Alt(cond, then, else_) => match peval(*cond, &mut env) {
+        Term::Alt(cond, then, else_) => match peval(*cond, &mut env)? {
             Value::Bool(true) => peval_closure(*then, env),
             Value::Bool(false) => peval_closure(*else_, env),
             other => {
-                let then = peval(*then, &mut env);
-                let else_ = peval_closure(*else_, env);
-                Value::Alt(other.into(), then.into(), else_.into())
+                let then = peval(*then, &mut env)?;
+                let else_ = peval_closure(*else_, env)?;
+                Ok(Value::Alt(other.into(), then.into(), else_.into()))
             }
         },
     }
@@ -113,7 +167,7 @@ pub mod tests_eval {
     fn test_minimal() {
         let code = Term::Float(80.0);
         let mut env = Env::new();
-        match peval(code.clone(), &mut env) {
+        match peval(code.clone(), &mut env).unwrap() {
             Value::Float(x) => assert_eq!(x, 80.0),
             result => panic!("result of {} is not float: {}", code, result),
         }
@@ -135,7 +189,7 @@ pub mod tests_eval {
             ).into(),
         );
         let mut env = Env::new();
-        match peval(code.clone(), &mut env) {
+        match peval(code.clone(), &mut env).unwrap() {
             Value::Float(x) => assert_eq!(x, 7.0),
             result => panic!("result of {} is not float: {}", code, result),
         }
@@ -152,7 +206,7 @@ pub mod tests_eval {
             Box::new(Term::Float(1.4)),
         );
         let mut env = Env::new();
-        match peval(code.clone(), &mut env) {
+        match peval(code.clone(), &mut env).unwrap() {
             Value::Float(x) => assert_eq!(x, 1.4),
             result => panic!("result of {} is not float: {}", code, result),
         }
@@ -167,7 +221,7 @@ pub mod tests_eval {
             Box::new(Term::Var(0)),
         );
         let mut env = Env::new();
-        match peval(code.clone(), &mut env) {
+        match peval(code.clone(), &mut env).unwrap() {
             Value::Float(x) => assert_eq!(x, 80.0),
             result => panic!("result of {} is not float: {}", code, result),
         }
@@ -181,7 +235,7 @@ pub mod tests_eval {
             Box::new(Term::Float(90.0)),
         );
         let mut env = Env::new();
-        match peval(code.clone(), &mut env) {
+        match peval(code.clone(), &mut env).unwrap() {
             Value::Float(x) => assert_eq!(x, 80.0),
             result => panic!("result of {} is not float: {}", code, result),
         }
@@ -194,7 +248,7 @@ pub mod tests_eval {
             Term::Float(90.0),
         ]);
         let mut env = Env::new();
-        match peval(code.clone(), &mut env) {
+        match peval(code.clone(), &mut env).unwrap() {
             Value::Tuple(mut values) => {
                 assert_eq!(values.len(), 2);
                 match values.pop().unwrap() {
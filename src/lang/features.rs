@@ -0,0 +1,211 @@
+use realfft::num_complex::Complex32;
+
+use super::*;
+
+/// Fraction of the energy of a frame below its rolloff
+const ROLLOFF: f32 = 0.85;
+
+/// A descriptor of a whole frame, read as a symbol
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+    /// Centre of mass of the magnitudes, in bins
+    Centroid,
+    /// Centre of mass of the magnitudes, in Hz
+    CentroidHz,
+    /// Geometric over arithmetic mean of the power, from 0 for a single tone to 1 for white noise
+    Flatness,
+    /// Sum of how much each magnitude rose since the previous frame
+    Flux,
+    /// Lowest bin below which 85% of the energy lies
+    Rolloff,
+    /// Sum of the power of every bin
+    Energy,
+    /// Root mean square of the magnitudes
+    Rms,
+    /// Bin with the largest magnitude
+    PeakBin,
+    /// Fundamental frequency, in Hz
+    F0,
+    /// How sure the fundamental is, from 0 to 1
+    F0Conf,
+    /// How far the spectral flux rose above its recent median, from 0 to 1
+    OnsetStrength,
+    /// Seconds since the last onset
+    SinceOnset,
+}
+
+impl Feature {
+    /// Every feature, indexed by `Feature as usize`
+    pub const ALL: [Feature; 12] = [
+        Feature::Centroid,
+        Feature::CentroidHz,
+        Feature::Flatness,
+        Feature::Flux,
+        Feature::Rolloff,
+        Feature::Energy,
+        Feature::Rms,
+        Feature::PeakBin,
+        Feature::F0,
+        Feature::F0Conf,
+        Feature::OnsetStrength,
+        Feature::SinceOnset,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Centroid => "centroid",
+            Feature::CentroidHz => "centroid_hz",
+            Feature::Flatness => "flatness",
+            Feature::Flux => "flux",
+            Feature::Rolloff => "rolloff",
+            Feature::Energy => "energy",
+            Feature::Rms => "rms",
+            Feature::PeakBin => "peak_bin",
+            Feature::F0 => "f0",
+            Feature::F0Conf => "f0_conf",
+            Feature::OnsetStrength => "onset_strength",
+            Feature::SinceOnset => "since_onset",
+        }
+    }
+}
+
+/// Descriptors of a frame, computed once before it is evaluated. Every descriptor of a silent
+/// frame is 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Features {
+    /// In bins
+    pub centroid: f32,
+    pub flatness: f32,
+    pub flux: f32,
+    /// In bins
+    pub rolloff: f32,
+    pub energy: f32,
+    pub rms: f32,
+    pub peak_bin: f32,
+    pub pitch: Pitch,
+    /// Left for an `OnsetDetector` to fill in, as it depends on earlier frames
+    pub onset: Onset,
+}
+
+impl Features {
+    /// Describe `spectrum`, with the flux measured against the magnitudes of the previous frame.
+    /// Bins missing from `previous` count as silent.
+    pub fn analyse(spectrum: &[Complex32], previous: &[f32]) -> Self {
+        let mut features = Self::default();
+        let (mut total, mut weighted, mut log_power, mut peak) = (0.0, 0.0, 0.0, 0.0);
+        for (k, bin) in spectrum.iter().enumerate() {
+            let magnitude = bin.norm();
+            let power = magnitude * magnitude;
+            total += magnitude;
+            weighted += magnitude * k as f32;
+            features.energy += power;
+            log_power += power.max(f32::MIN_POSITIVE).ln();
+            features.flux += (magnitude - previous.get(k).copied().unwrap_or(0.0)).max(0.0);
+            if magnitude > peak {
+                peak = magnitude;
+                features.peak_bin = k as f32;
+            }
+        }
+        if features.energy == 0.0 {
+            return Self::default();
+        }
+
+        features.pitch = Pitch::estimate(spectrum);
+        let len = spectrum.len() as f32;
+        features.centroid = weighted / total;
+        features.flatness = ((log_power / len).exp() / (features.energy / len)).min(1.0);
+        features.rms = (features.energy / len).sqrt();
+        let mut below = 0.0;
+        for (k, bin) in spectrum.iter().enumerate() {
+            below += bin.norm_sqr();
+            if below >= ROLLOFF * features.energy {
+                features.rolloff = k as f32;
+                break;
+            }
+        }
+        features
+    }
+
+    /// The value of a feature, given the width of a bin in Hz
+    pub fn get(&self, feature: Feature, bin_width: f32) -> f32 {
+        match feature {
+            Feature::Centroid => self.centroid,
+            Feature::CentroidHz => self.centroid * bin_width,
+            Feature::Flatness => self.flatness,
+            Feature::Flux => self.flux,
+            Feature::Rolloff => self.rolloff,
+            Feature::Energy => self.energy,
+            Feature::Rms => self.rms,
+            Feature::PeakBin => self.peak_bin,
+            Feature::F0 => self.pitch.f0 * bin_width,
+            Feature::F0Conf => self.pitch.confidence,
+            Feature::OnsetStrength => self.onset.strength,
+            Feature::SinceOnset => self.onset.since,
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_features {
+    use super::*;
+
+    /// A spectrum with the given magnitudes and arbitrary phases
+    fn spectrum(magnitudes: &[f32]) -> Vec<Complex32> {
+        magnitudes
+            .iter()
+            .enumerate()
+            .map(|(k, magnitude)| Complex32::from_polar(*magnitude, k as f32))
+            .collect()
+    }
+
+    #[test]
+    fn test_centroid() {
+        let features = Features::analyse(&spectrum(&[0.0, 1.0, 0.0, 3.0, 0.0]), &[]);
+        assert!((features.centroid - 2.5).abs() < 1e-5);
+        assert!((features.get(Feature::CentroidHz, 10.0) - 25.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_flatness() {
+        let noise = Features::analyse(&spectrum(&[2.0; 16]), &[]);
+        assert!((noise.flatness - 1.0).abs() < 1e-5);
+        let mut tone = [0.0; 16];
+        tone[4] = 1.0;
+        assert!(Features::analyse(&spectrum(&tone), &[]).flatness < 1e-6);
+        let half = Features::analyse(&spectrum(&[1.0, 2.0]), &[]);
+        assert!((half.flatness - 2.0 / 2.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_flux() {
+        let features = Features::analyse(&spectrum(&[1.0, 3.0, 2.0]), &[2.0, 1.0]);
+        assert!((features.flux - 4.0).abs() < 1e-5);
+        assert_eq!(Features::analyse(&spectrum(&[0.0; 3]), &[2.0, 1.0]).flux, 0.0);
+    }
+
+    #[test]
+    fn test_rolloff() {
+        let features = Features::analyse(&spectrum(&[1.0, 1.0, 3.0, 1.0, 0.0]), &[]);
+        assert_eq!(features.rolloff, 2.0);
+        let features = Features::analyse(&spectrum(&[3.0, 1.0, 1.0, 1.0, 1.0]), &[]);
+        assert_eq!(features.rolloff, 3.0);
+    }
+
+    #[test]
+    fn test_energy() {
+        let features = Features::analyse(&spectrum(&[3.0, 4.0, 0.0, 0.0]), &[]);
+        assert!((features.energy - 25.0).abs() < 1e-4);
+        assert!((features.rms - 2.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_peak_bin() {
+        let features = Features::analyse(&spectrum(&[1.0, 0.5, 4.0, 4.0, 2.0]), &[]);
+        assert_eq!(features.peak_bin, 2.0);
+
+        // Silence has every descriptor at 0
+        assert_eq!(Features::analyse(&spectrum(&[0.0; 8]), &[]), Features::default());
+        assert_eq!(Features::analyse(&[], &[]), Features::default());
+    }
+}
@@ -0,0 +1,129 @@
+use realfft::num_complex::Complex32;
+
+use super::*;
+
+/// A ring buffer of past spectra. Every frame is allocated up front, so changing the depth or
+/// the length of the spectra never allocates.
+#[derive(Default)]
+pub struct History {
+    /// Each frame has capacity for the longest spectrum
+    frames: Vec<Vec<Complex32>>,
+    /// Number of frames kept, at most the number allocated
+    depth: usize,
+    /// Index of the most recent frame
+    head: usize,
+    /// Number of frames pushed since the last clear, at most `depth`
+    filled: usize,
+}
+
+impl History {
+    pub fn new(max_frames: usize, max_bins: usize) -> Self {
+        Self {
+            frames: vec![Vec::with_capacity(max_bins); max_frames],
+            depth: max_frames,
+            head: 0,
+            filled: 0,
+        }
+    }
+
+    pub fn max_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Change the number of frames kept, forgetting all of them
+    pub fn set_depth(&mut self, depth: usize) {
+        self.depth = depth.min(self.frames.len());
+        self.clear();
+    }
+
+    /// Forget all frames, like after the window size changes
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.filled = 0;
+    }
+
+    /// Record the spectrum of a frame that has just been processed, which must not be longer
+    /// than the spectra the history was allocated for
+    pub fn push(&mut self, spectrum: &[Complex32]) {
+        if self.depth == 0 {
+            return;
+        }
+        self.head = (self.head + 1) % self.depth;
+        self.filled = (self.filled + 1).min(self.depth);
+        let frame = &mut self.frames[self.head];
+        frame.clear();
+        frame.extend_from_slice(spectrum);
+    }
+
+    /// The spectrum `k` frames before the current one, empty if it hasn't been recorded
+    pub fn frame(&self, k: usize) -> &[Complex32] {
+        if k == 0 || k > self.filled {
+            return &[];
+        }
+        &self.frames[(self.head + self.depth - (k - 1)) % self.depth]
+    }
+}
+
+/// Look up the spectrum `k` frames before `fft` at a fractional band, interpolating linearly
+/// between frames. Frames that haven't been recorded are silent.
+pub fn history_at(fft: &[Complex32], history: Option<&History>, k: f32, f: f32) -> Complex32 {
+    let depth = history.map_or(0, |history| history.depth());
+    let k = k.max(0.0);
+    if k >= depth as f32 + 1.0 {
+        return Complex32::default();
+    }
+    let frame = |k: usize| match (k, history) {
+        (0, _) => fft,
+        (k, Some(history)) => history.frame(k),
+        (_, None) => &[],
+    };
+    let floor = k.floor() as usize;
+    let fraction = k - k.floor();
+    let lower = fft_at(frame(floor), f);
+    if fraction == 0.0 {
+        lower
+    } else {
+        lower + (fft_at(frame(floor + 1), f) - lower) * fraction
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_history {
+    use super::*;
+
+    #[test]
+    fn test_history_ring() {
+        let mut history = History::new(4, 8);
+        history.set_depth(3);
+        let frame = |x: f32| vec![Complex32::new(x, 0.0); 8];
+        for x in 1..=5 {
+            history.push(&frame(x as f32));
+        }
+        assert_eq!(history.frame(1)[0].re, 5.0);
+        assert_eq!(history.frame(3)[0].re, 3.0);
+        assert!(history.frame(4).is_empty());
+
+        // Fractional frames interpolate, and the current frame is `fft`
+        let fft = frame(6.0);
+        assert_eq!(history_at(&fft, Some(&history), 0.0, 2.0).re, 6.0);
+        assert_eq!(history_at(&fft, Some(&history), 1.5, 2.0).re, 4.5);
+        assert_eq!(history_at(&fft, Some(&history), 3.5, 2.0).re, 1.5);
+        assert_eq!(history_at(&fft, Some(&history), 4.0, 2.0).re, 0.0);
+        assert_eq!(history_at(&fft, Some(&history), f32::INFINITY, 2.0).re, 0.0);
+        assert_eq!(history_at(&fft, None, 1.0, 2.0).re, 0.0);
+
+        // Resizing forgets every frame without reallocating
+        let capacity = history.frames[0].capacity();
+        history.set_depth(10);
+        assert_eq!(history.depth(), 4);
+        assert!(history.frame(1).is_empty());
+        history.push(&frame(7.0)[..4]);
+        assert_eq!(history.frame(1).len(), 4);
+        assert_eq!(history.frames[0].capacity(), capacity);
+    }
+}
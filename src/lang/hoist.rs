@@ -0,0 +1,235 @@
+use super::*;
+
+/// Split the body of a program into terms that don't depend on the bin, which can be evaluated
+/// once per frame, and a body reading their values through variables.
+/// `body` is under a single binder for the bin, of `param_type`, hoisted values are bound outside
+/// of it.
+pub fn hoist(body: Term, param_type: ValueType) -> (Vec<Term>, Term) {
+    let mut prologue = Vec::new();
+    let body = extract(body, &mut vec![param_type], &mut prologue);
+    let len = prologue.len();
+    (prologue, relink(body, 1, len))
+}
+
+/// Replace invariant terms with placeholders `Var(-1 - k)` pointing at `prologue[k]`
+fn extract(term: Term, ctx: &mut Vec<ValueType>, prologue: &mut Vec<Term>) -> Term {
+    if is_invariant(&term, ctx) {
+        let k = match prologue.iter().position(|t| *t == term) {
+            Some(k) => k,
+            None => {
+                prologue.push(term);
+                prologue.len() - 1
+            }
+        };
+        return Term::Var(-1 - k as Index);
+    }
+    match term {
+        Term::Tuple(terms) => Term::Tuple(
+            terms
+                .into_iter()
+                .map(|t| extract(t, ctx, prologue))
+                .collect(),
+        ),
+        Term::Apply(func, arg) => Term::Apply(
+            extract(*func, ctx, prologue).into(),
+            extract(*arg, ctx, prologue).into(),
+        ),
+        Term::Func(param_type, name, body) => {
+            ctx.push(*param_type.clone());
+            let body = extract(*body, ctx, prologue);
+            ctx.pop();
+            Term::Func(param_type, name, body.into())
+        }
+        Term::Let(value_type, name, value, next) => {
+            let value = extract(*value, ctx, prologue);
+            ctx.push(*value_type.clone());
+            let next = extract(*next, ctx, prologue);
+            ctx.pop();
+            Term::Let(value_type, name, value.into(), next.into())
+        }
+        Term::Alt(cond, then, else_) => Term::Alt(
+            extract(*cond, ctx, prologue).into(),
+            extract(*then, ctx, prologue).into(),
+            extract(*else_, ctx, prologue).into(),
+        ),
+        other => other,
+    }
+}
+
+/// Point placeholders at hoisted values, which are bound right outside of the bin
+fn relink(term: Term, depth: Index, len: usize) -> Term {
+    match term {
+        Term::Var(v) if v < 0 => Term::Var(depth + len as Index - 1 + (v + 1)),
+        Term::Tuple(terms) => Term::Tuple(
+            terms
+                .into_iter()
+                .map(|t| relink(t, depth, len))
+                .collect(),
+        ),
+        Term::Apply(func, arg) => Term::Apply(
+            relink(*func, depth, len).into(),
+            relink(*arg, depth, len).into(),
+        ),
+        Term::Func(param_type, name, body) => {
+            Term::Func(param_type, name, relink(*body, depth + 1, len).into())
+        }
+        Term::Let(value_type, name, value, next) => Term::Let(
+            value_type,
+            name,
+            relink(*value, depth, len).into(),
+            relink(*next, depth + 1, len).into(),
+        ),
+        Term::Alt(cond, then, else_) => Term::Alt(
+            relink(*cond, depth, len).into(),
+            relink(*then, depth, len).into(),
+            relink(*else_, depth, len).into(),
+        ),
+        other => other,
+    }
+}
+
+/// Check if a term is worth evaluating once per frame instead of once per bin
+fn is_invariant(term: &Term, ctx: &mut Vec<ValueType>) -> bool {
+    matches!(
+        term,
+        Term::Tuple(_) | Term::Apply(_, _) | Term::Let(_, _, _, _) | Term::Alt(_, _, _)
+    ) && is_closed(term, 0)
+        && has_symbol(term)
+        && !reads_bin(term)
+        && matches!(type_of(term, ctx), Ok(t) if !matches!(t, ValueType::Func(_, _)))
+}
+
+/// Check if a term only refers to variables bound inside of it
+pub fn is_closed(term: &Term, binders: Index) -> bool {
+    match term {
+        Term::Var(v) => *v < binders,
+        Term::Tuple(terms) => terms.iter().all(|t| is_closed(t, binders)),
+        Term::Apply(func, arg) => is_closed(func, binders) && is_closed(arg, binders),
+        Term::Func(_, _, body) => is_closed(body, binders + 1),
+        Term::Let(_, _, value, next) => is_closed(value, binders) && is_closed(next, binders + 1),
+        Term::Alt(cond, then, else_) => {
+            is_closed(cond, binders) && is_closed(then, binders) && is_closed(else_, binders)
+        }
+        _ => true,
+    }
+}
+
+/// Check if a term mentions a library symbol, otherwise it's already folded
+fn has_symbol(term: &Term) -> bool {
+    match term {
+        Term::Lib(lib) => lib.is_symbol(),
+        Term::Tuple(terms) => terms.iter().any(has_symbol),
+        Term::Apply(func, arg) => has_symbol(func) || has_symbol(arg),
+        Term::Func(_, _, body) => has_symbol(body),
+        Term::Let(_, _, value, next) => has_symbol(value) || has_symbol(next),
+        Term::Alt(cond, then, else_) => has_symbol(cond) || has_symbol(then) || has_symbol(else_),
+        _ => false,
+    }
+}
+
+/// Check if a term calls a library function that depends on the bin, so it can't be shared by
+/// every bin even with closed arguments
+fn reads_bin(term: &Term) -> bool {
+    match term {
+        Term::Lib(lib) => lib.reads_bin(),
+        Term::Tuple(terms) => terms.iter().any(reads_bin),
+        Term::Apply(func, arg) => reads_bin(func) || reads_bin(arg),
+        Term::Func(_, _, body) => reads_bin(body),
+        Term::Let(_, _, value, next) => reads_bin(value) || reads_bin(next),
+        Term::Alt(cond, then, else_) => reads_bin(cond) || reads_bin(then) || reads_bin(else_),
+        _ => false,
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_hoist {
+    use super::*;
+
+    fn param_times(index: f32, factor: f32) -> Term {
+        Term::Apply(
+            Term::Apply(
+                Term::Lib(Lib::Mul).into(),
+                Term::Apply(Term::Lib(Lib::Param).into(), Term::Float(index).into()).into(),
+            )
+            .into(),
+            Term::Float(factor).into(),
+        )
+    }
+
+    #[test]
+    fn test_hoist_param() {
+        let program = run("(i: Float) => (fft(i).norm * (param(3) * 2000), fft(i).angle).polar").unwrap();
+        assert_eq!(program.prologue, vec![param_times(3.0, 2000.0)]);
+    }
+
+    #[test]
+    fn test_hoist_shared() {
+        let program = run("(i: Float) => (i * (param(3) * 2000), param(3) * 2000)").unwrap();
+        assert_eq!(program.prologue, vec![param_times(3.0, 2000.0)]);
+        assert_eq!(
+            program.body,
+            Term::Tuple(vec![
+                Term::Apply(
+                    Term::Apply(Term::Lib(Lib::Mul).into(), Term::Var(0).into()).into(),
+                    Term::Var(1).into(),
+                ),
+                Term::Var(1),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_hoist_order() {
+        let program = run("(i: Float) => (sin(beat * 6.28), i * sec)").unwrap();
+        assert_eq!(program.prologue.len(), 1);
+        assert_eq!(
+            program.body,
+            Term::Tuple(vec![
+                Term::Var(1),
+                Term::Apply(
+                    Term::Apply(Term::Lib(Lib::Mul).into(), Term::Var(0).into()).into(),
+                    Term::Lib(Lib::Sec).into(),
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_hoist_state() {
+        // Each bin keeps its own value even when the input is the same for all of them, only
+        // the arguments are hoisted
+        let program = run("(i: Float) => (follow(0.1, 0.5, param(3) * 2000), 0)").unwrap();
+        assert_eq!(
+            program.prologue,
+            vec![Term::Tuple(vec![Term::Float(0.1), Term::Float(0.5), param_times(3.0, 2000.0)])]
+        );
+        assert_eq!(
+            program.body,
+            Term::Tuple(vec![
+                Term::Apply(Term::Lib(Lib::State(StateOp::Follow, 0)).into(), Term::Var(1).into()),
+                Term::Float(0.0),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_hoist_synth() {
+        // The phase of each bin advances on its own, so a constant `synth` stays in the body
+        let program = run("(i: Float) => synth(0.5, 40)").unwrap();
+        assert!(program.prologue.is_empty());
+        assert_eq!(
+            program.body,
+            Term::Apply(
+                Term::Lib(Lib::Synth).into(),
+                Term::Tuple(vec![Term::Float(0.5), Term::Float(40.0)]).into(),
+            )
+        );
+    }
+
+    #[test]
+    fn test_hoist_nothing() {
+        let program = run("(i: Float) => fft(i * 2)").unwrap();
+        assert!(program.prologue.is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+use std::f32::consts::PI;
+use std::ops::{Add, Mul, Sub};
+
+/// Number of bins on each side that windowed-sinc interpolation reads
+const SINC_RADIUS: i64 = 8;
+
+/// How to look up a value between two bins
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interp {
+    Nearest,
+    Linear,
+    /// Raised cosine, used by `fft` and `param`
+    Cosine,
+    /// Catmull-Rom cubic Hermite spline
+    Cubic,
+    /// Lanczos windowed sinc
+    Sinc,
+}
+
+impl Interp {
+    /// Every mode, indexed by `Interp as usize`
+    pub const ALL: [Interp; 5] = [
+        Interp::Nearest,
+        Interp::Linear,
+        Interp::Cosine,
+        Interp::Cubic,
+        Interp::Sinc,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interp::Nearest => "nearest",
+            Interp::Linear => "linear",
+            Interp::Cosine => "cosine",
+            Interp::Cubic => "cubic",
+            Interp::Sinc => "sinc",
+        }
+    }
+}
+
+/// Look up `values` at a fractional index, treating values outside of the slice as zero
+pub fn interpolate<T>(values: &[T], f: f32, mode: Interp) -> T
+where
+    T: Copy + Default + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    if !f.is_finite() {
+        return T::default();
+    }
+    let at = |k: i64| {
+        usize::try_from(k)
+            .ok()
+            .and_then(|k| values.get(k))
+            .copied()
+            .unwrap_or_default()
+    };
+    let k = f.floor() as i64;
+    let t = f - f.floor();
+    match mode {
+        Interp::Nearest => at(f.round() as i64),
+        Interp::Linear => at(k) + (at(k + 1) - at(k)) * t,
+        Interp::Cosine => at(k) + (at(k + 1) - at(k)) * ((1.0 - (t * PI).cos()) * 0.5),
+        Interp::Cubic => {
+            let (p0, p1, p2, p3) = (at(k - 1), at(k), at(k + 1), at(k + 2));
+            let a = p3 * 0.5 - p0 * 0.5 + (p1 - p2) * 1.5;
+            let b = p0 + p2 * 2.0 - p1 * 2.5 - p3 * 0.5;
+            let c = (p2 - p0) * 0.5;
+            ((a * t + b) * t + c) * t + p1
+        }
+        Interp::Sinc if t == 0.0 => at(k),
+        Interp::Sinc => (k - SINC_RADIUS + 1..=k + SINC_RADIUS).fold(T::default(), |sum, j| {
+            let x = f - j as f32;
+            sum + at(j) * (sinc(x) * sinc(x / SINC_RADIUS as f32))
+        }),
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_interp {
+    use super::*;
+
+    #[test]
+    fn test_interp_integer() {
+        let values = [1.0, -2.0, 3.0, 0.5, 4.0];
+        for mode in Interp::ALL {
+            for (i, value) in values.iter().enumerate() {
+                let result = interpolate(&values, i as f32, mode);
+                assert!((result - value).abs() < 1e-5, "{} at {}: {}", mode.name(), i, result);
+            }
+            assert_eq!(interpolate(&values, 10.0, mode), 0.0);
+            assert_eq!(interpolate(&values, f32::NAN, mode), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_interp_linear() {
+        let values: Vec<f32> = (0..8).map(|i| i as f32 * 2.0).collect();
+        assert_eq!(interpolate(&values, 2.4, Interp::Nearest), 4.0);
+        assert!((interpolate(&values, 2.25, Interp::Linear) - 4.5).abs() < 1e-5);
+        assert!((interpolate(&values, 3.5, Interp::Cosine) - 7.0).abs() < 1e-5);
+        assert!((interpolate(&values, 3.25, Interp::Cubic) - 6.5).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,1022 @@
+use std::mem;
+
+use cranelift_codegen::ir::condcodes::FloatCC;
+use cranelift_codegen::ir::{
+    types, AbiParam, FuncRef, InstBuilder, MemFlags, StackSlotData, StackSlotKind, Type,
+    UserFuncName, Value as IrValue,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+use realfft::num_complex::Complex32;
+
+use super::*;
+
+pub type JitError = String;
+
+/// Most values the prologue of a compiled program can give, counting each float and boolean
+const MAX_PROLOGUE_VALUES: usize = 64;
+
+/// Resource passed to native code, mirrors `Resource` with a C layout
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ResourceAbi {
+    pub fft: *const Complex32,
+    pub fft_len: usize,
+    pub modulation: *const f32,
+    pub modulation_len: usize,
+    pub beat: f64,
+    pub second: f64,
+    pub sample_rate: f32,
+    pub window_size: f32,
+    pub hop: f32,
+    pub spectra: [*const Complex32; 6],
+    pub spectra_len: [usize; 6],
+    pub channel: f32,
+    pub is_mid: u8,
+    pub is_side: u8,
+    pub onset: u8,
+    pub playing: u8,
+    pub looping: u8,
+    /// Null if past frames aren't recorded
+    pub history: *const History,
+    /// Null if every stateful call is stateless
+    pub state: *const State,
+    /// Empty if the envelope isn't estimated
+    pub envelope: *const f32,
+    pub envelope_len: usize,
+    /// Empty if harmonic content isn't separated
+    pub harmonic: *const f32,
+    pub harmonic_len: usize,
+    /// Indexed by `Feature as usize`
+    pub features: [f32; 12],
+    /// Fundamental of the frame in bins, 0 without one
+    pub f0: f32,
+    pub frame_seed: u64,
+    /// Indexed by `Timing as usize`
+    pub timing: [f32; 7],
+    /// Values of the prologue in the current frame, set while evaluating bins
+    pub prologue: *const f32,
+}
+
+impl ResourceAbi {
+    /// Borrow pointers from a resource, which must outlive every call made with this
+    pub fn new(res: &Resource) -> Self {
+        Self {
+            fft: res.fft.as_ptr(),
+            fft_len: res.fft.len(),
+            modulation: res.modulation.as_ptr(),
+            modulation_len: res.modulation.len(),
+            beat: res.beat,
+            second: res.second,
+            sample_rate: res.sample_rate,
+            window_size: res.window_size as f32,
+            hop: res.hop() as f32,
+            spectra: Source::ALL.map(|source| res.spectrum(source).as_ptr()),
+            spectra_len: Source::ALL.map(|source| res.spectrum(source).len()),
+            channel: res.channel() as f32,
+            is_mid: res.is_mid() as u8,
+            is_side: res.is_side() as u8,
+            onset: res.features.onset.detected as u8,
+            playing: res.clock.playing as u8,
+            looping: res.clock.loop_range.is_some() as u8,
+            history: res.history.map_or(std::ptr::null(), |history| history as *const History),
+            state: res.state.map_or(std::ptr::null(), |state| state as *const State),
+            envelope: res.envelope.as_ptr(),
+            envelope_len: res.envelope.len(),
+            harmonic: res.harmonic.as_ptr(),
+            harmonic_len: res.harmonic.len(),
+            features: Feature::ALL.map(|feature| res.feature(feature)),
+            f0: res.features.pitch.f0,
+            frame_seed: res.frame_seed,
+            timing: Timing::ALL.map(|timing| res.clock.get(timing, res.beat)),
+            prologue: std::ptr::null(),
+        }
+    }
+
+    fn fft(&self) -> &[Complex32] {
+        unsafe { std::slice::from_raw_parts(self.fft, self.fft_len) }
+    }
+
+    fn modulation(&self) -> &[f32] {
+        unsafe { std::slice::from_raw_parts(self.modulation, self.modulation_len) }
+    }
+
+    fn spectrum(&self, source: usize) -> &[Complex32] {
+        unsafe { std::slice::from_raw_parts(self.spectra[source], self.spectra_len[source]) }
+    }
+
+    fn history(&self) -> Option<&History> {
+        unsafe { self.history.as_ref() }
+    }
+
+    fn state(&self) -> Option<&State> {
+        unsafe { self.state.as_ref() }
+    }
+
+    fn envelope(&self) -> &[f32] {
+        unsafe { std::slice::from_raw_parts(self.envelope, self.envelope_len) }
+    }
+
+    fn harmonic(&self) -> &[f32] {
+        unsafe { std::slice::from_raw_parts(self.harmonic, self.harmonic_len) }
+    }
+}
+
+/// Signature of a compiled program, evaluating one bin into `out`
+pub type JitFn = extern "C" fn(bin: f32, res: *const ResourceAbi, out: *mut Complex32);
+
+/// Signature of a compiled prologue, evaluating the hoisted values of a frame into `values`
+pub type PrologueFn = extern "C" fn(res: *const ResourceAbi, values: *mut f32);
+
+/// A program compiled to native code
+///
+/// Native code can't fail: it computes integers in `f32`, so where the interpreter stops with
+/// `EvalError::Overflow` it gives an inexact value instead. The other errors can't happen in a
+/// well-typed program.
+pub struct JitProgram {
+    module: Option<JITModule>,
+    prologue: PrologueFn,
+    func: JitFn,
+}
+
+// The module is never touched after finalization, only the function pointer is shared
+unsafe impl Send for JitProgram {}
+unsafe impl Sync for JitProgram {}
+
+impl JitProgram {
+    /// Compile a `Float -> (Float, Float)` program, evaluating its prologue once per frame
+    pub fn compile(program: &Program) -> Result<Self, JitError> {
+        if program.machine.is_some() {
+            return Err("Programs with state are not supported".into());
+        }
+        let Term::Func(param_type, _, _) = &program.term else {
+            return Err(format!("Program is not a function: {}", program.pretty_term()));
+        };
+        if **param_type != ValueType::Float {
+            return Err(format!("Unsupported parameter type: {}", param_type.pretty_term()));
+        }
+        if program.output == Output::Stereo {
+            return Err("Stereo output is not supported".into());
+        }
+
+        let mut flag_builder = settings::builder();
+        flag_builder.set("use_colocated_libcalls", "false").map_err(|e| e.to_string())?;
+        flag_builder.set("is_pic", "false").map_err(|e| e.to_string())?;
+        flag_builder.set("opt_level", "speed").map_err(|e| e.to_string())?;
+        let isa = cranelift_native::builder()
+            .map_err(|e| format!("Host machine is not supported: {}", e))?
+            .finish(settings::Flags::new(flag_builder))
+            .map_err(|e| e.to_string())?;
+        let mut jit_builder = JITBuilder::with_isa(isa, default_libcall_names());
+        for (name, ptr) in HELPERS {
+            jit_builder.symbol(*name, *ptr);
+        }
+        let mut module = JITModule::new(jit_builder);
+
+        // Compile into the module, which frees its memory on failure
+        match Self::define(&mut module, program) {
+            Ok((prologue, func)) => {
+                let prologue = module.get_finalized_function(prologue);
+                let prologue = unsafe { mem::transmute::<*const u8, PrologueFn>(prologue) };
+                let func = module.get_finalized_function(func);
+                let func = unsafe { mem::transmute::<*const u8, JitFn>(func) };
+                Ok(Self { module: Some(module), prologue, func })
+            }
+            Err(err) => {
+                unsafe { module.free_memory() };
+                Err(err)
+            }
+        }
+    }
+
+    fn define(module: &mut JITModule, program: &Program) -> Result<(FuncId, FuncId), JitError> {
+        let ptr = module.target_config().pointer_type();
+        let mut ctx = module.make_context();
+        let mut func_ctx = FunctionBuilderContext::new();
+
+        // The prologue stores every float and boolean it gives, bins load them again
+        let mut sig = module.make_signature();
+        sig.params.push(AbiParam::new(ptr));
+        sig.params.push(AbiParam::new(ptr));
+        let prologue_id = module
+            .declare_function("prologue", Linkage::Local, &sig)
+            .map_err(|e| e.to_string())?;
+        ctx.func.signature = sig;
+        ctx.func.name = UserFuncName::user(0, prologue_id.as_u32());
+
+        let mut shapes = Vec::new();
+        {
+            let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let res = builder.block_params(entry)[0];
+            let values = builder.block_params(entry)[1];
+
+            let mut lower = Lower::new(module, &mut builder, ptr, res)?;
+            let mut leaves = Vec::new();
+            for term in &program.prologue {
+                let value = lower.term(term, &mut Vec::new())?;
+                flatten(&value, &mut leaves)?;
+                shapes.push(value);
+            }
+            if leaves.len() > MAX_PROLOGUE_VALUES {
+                return Err(format!("Too many hoisted values: {}", leaves.len()));
+            }
+            for (k, leaf) in leaves.into_iter().enumerate() {
+                let leaf = lower.store_leaf(leaf);
+                lower.builder.ins().store(MemFlags::trusted(), leaf, values, 4 * k as i32);
+            }
+            builder.ins().return_(&[]);
+            builder.seal_all_blocks();
+            builder.finalize();
+        }
+
+        module.define_function(prologue_id, &mut ctx).map_err(|e| e.to_string())?;
+        module.clear_context(&mut ctx);
+
+        let mut sig = module.make_signature();
+        sig.params.push(AbiParam::new(types::F32));
+        sig.params.push(AbiParam::new(ptr));
+        sig.params.push(AbiParam::new(ptr));
+        let id = module
+            .declare_function("program", Linkage::Local, &sig)
+            .map_err(|e| e.to_string())?;
+        ctx.func.signature = sig;
+        ctx.func.name = UserFuncName::user(0, id.as_u32());
+
+        {
+            let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let bin = builder.block_params(entry)[0];
+            let res = builder.block_params(entry)[1];
+            let out = builder.block_params(entry)[2];
+
+            // The body sees the prologue values and then the bin
+            let mut lower = Lower::new(module, &mut builder, ptr, res)?;
+            let offset = mem::offset_of!(ResourceAbi, prologue) as i32;
+            let values = lower.builder.ins().load(ptr, MemFlags::trusted(), res, offset);
+            let mut offset = 0;
+            let mut env: Vec<JitValue> = shapes
+                .iter()
+                .map(|shape| lower.load_leaves(shape, values, &mut offset))
+                .collect();
+            env.push(JitValue::Float(bin));
+            let result = lower.term(&program.body, &mut env)?;
+            let [re, im] = lower.complex(result)?;
+            builder.ins().store(MemFlags::trusted(), re, out, 0);
+            builder.ins().store(MemFlags::trusted(), im, out, 4);
+            builder.ins().return_(&[]);
+            builder.seal_all_blocks();
+            builder.finalize();
+        }
+
+        module.define_function(id, &mut ctx).map_err(|e| e.to_string())?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions().map_err(|e| e.to_string())?;
+        Ok((prologue_id, id))
+    }
+
+    /// Evaluate the prologue of the frame in `res`, then `f` with its values
+    fn frame(&self, res: &ResourceAbi, f: impl FnOnce(&ResourceAbi)) {
+        let mut values = [0.0; MAX_PROLOGUE_VALUES];
+        (self.prologue)(res, values.as_mut_ptr());
+        f(&ResourceAbi {
+            prologue: values.as_ptr(),
+            ..*res
+        });
+    }
+
+    /// Evaluate a single bin
+    pub fn call(&self, bin: f32, res: &ResourceAbi) -> Complex32 {
+        if let Some(state) = res.state() {
+            state.set_bin(bin as usize);
+        }
+        let mut out = Complex32::default();
+        self.frame(res, |res| (self.func)(bin, res, &mut out));
+        out
+    }
+
+    /// Evaluate every bin of `out`, which must not alias the spectrum in `res`
+    pub fn collect_into(&self, res: &ResourceAbi, out: &mut [Complex32]) {
+        self.frame(res, |res| {
+            for (i, value) in out.iter_mut().enumerate() {
+                if let Some(state) = res.state() {
+                    state.set_bin(i);
+                }
+                (self.func)(i as f32, res, value);
+            }
+        });
+    }
+}
+
+impl Drop for JitProgram {
+    fn drop(&mut self) {
+        // The module leaks its code on drop, and no pointer into it outlives `self`
+        if let Some(module) = self.module.take() {
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+// Helpers called from native code, sharing their semantics with `Lib::apply`
+
+extern "C" fn dusk_fft(res: *const ResourceAbi, f: f32, out: *mut Complex32) {
+    unsafe { *out = fft_at((*res).fft(), f) }
+}
+
+extern "C" fn dusk_param(res: *const ResourceAbi, f: f32) -> f32 {
+    unsafe { param_at((*res).modulation(), f) }
+}
+
+extern "C" fn dusk_fft_with(res: *const ResourceAbi, mode: i32, f: f32, out: *mut Complex32) {
+    unsafe { *out = interpolate((*res).fft(), f, Interp::ALL[mode as usize]) }
+}
+
+extern "C" fn dusk_param_with(res: *const ResourceAbi, mode: i32, f: f32) -> f32 {
+    unsafe { interpolate((*res).modulation(), f, Interp::ALL[mode as usize]) }
+}
+
+extern "C" fn dusk_fft_of(res: *const ResourceAbi, source: i32, f: f32, out: *mut Complex32) {
+    unsafe { *out = fft_at((*res).spectrum(source as usize), f) }
+}
+
+extern "C" fn dusk_hist(res: *const ResourceAbi, k: f32, f: f32, out: *mut Complex32) {
+    unsafe { *out = history_at((*res).fft(), (*res).history(), k, f) }
+}
+
+extern "C" fn dusk_state(res: *const ResourceAbi, op: i32, slot: i32, p0: f32, p1: f32, x: f32) -> f32 {
+    unsafe {
+        let dt = (*res).hop / (*res).sample_rate;
+        state_step((*res).state(), StateOp::ALL[op as usize], slot as usize, [p0, p1], x, dt)
+    }
+}
+
+extern "C" fn dusk_mag(res: *const ResourceAbi, f: f32) -> f32 {
+    unsafe { mag_at((*res).fft(), f) }
+}
+
+extern "C" fn dusk_inst_freq(res: *const ResourceAbi, f: f32) -> f32 {
+    unsafe {
+        let (hop, window_size) = ((*res).hop as usize, (*res).window_size as usize);
+        let phases = (*res).state().map(State::phases);
+        let bin_width = (*res).sample_rate / (*res).window_size;
+        inst_freq_at((*res).fft(), phases, f, hop, window_size) * bin_width
+    }
+}
+
+extern "C" fn dusk_synth(res: *const ResourceAbi, mag: f32, freq: f32, out: *mut Complex32) {
+    unsafe {
+        let (hop, window_size) = ((*res).hop as usize, (*res).window_size as usize);
+        let bin_width = (*res).sample_rate / (*res).window_size;
+        *out = synth((*res).state(), mag, freq / bin_width, hop, window_size)
+    }
+}
+
+extern "C" fn dusk_env(res: *const ResourceAbi, f: f32) -> f32 {
+    unsafe { envelope_at((*res).envelope(), f) }
+}
+
+extern "C" fn dusk_fine(res: *const ResourceAbi, f: f32) -> f32 {
+    unsafe { fine_at((*res).fft(), (*res).envelope(), f) }
+}
+
+extern "C" fn dusk_harm(res: *const ResourceAbi, f: f32, out: *mut Complex32) {
+    unsafe { *out = harm_at((*res).fft(), (*res).harmonic(), f) }
+}
+
+extern "C" fn dusk_perc(res: *const ResourceAbi, f: f32, out: *mut Complex32) {
+    unsafe { *out = perc_at((*res).fft(), (*res).harmonic(), f) }
+}
+
+extern "C" fn dusk_harmonic_of(res: *const ResourceAbi, f: f32) -> f32 {
+    unsafe { harmonic_of((*res).f0, f) }
+}
+
+extern "C" fn dusk_harmonic_dist(res: *const ResourceAbi, f: f32) -> f32 {
+    unsafe { harmonic_dist((*res).f0, f) }
+}
+
+extern "C" fn dusk_noise(res: *const ResourceAbi, f: f32) -> f32 {
+    unsafe { noise_at((*res).frame_seed, (*res).channel as usize, f) }
+}
+
+extern "C" fn dusk_rand_phase(res: *const ResourceAbi, f: f32) -> f32 {
+    unsafe { rand_phase_at((*res).frame_seed, (*res).channel as usize, f) }
+}
+
+extern "C" fn dusk_rand(res: *const ResourceAbi, seed: f32, f: f32) -> f32 {
+    unsafe { rand_at((*res).frame_seed, (*res).channel as usize, seed, f) }
+}
+
+extern "C" fn dusk_lfo(res: *const ResourceAbi, wave: i32, period: f32) -> f32 {
+    unsafe { lfo(Wave::ALL[wave as usize], (*res).beat, period) }
+}
+
+extern "C" fn dusk_step_seq(res: *const ResourceAbi, steps: f32, step_beats: f32) -> f32 {
+    unsafe { step_seq((*res).beat, steps, step_beats) }
+}
+
+extern "C" fn dusk_sin(f: f32) -> f32 {
+    f.sin()
+}
+
+extern "C" fn dusk_cos(f: f32) -> f32 {
+    f.cos()
+}
+
+extern "C" fn dusk_tan(f: f32) -> f32 {
+    tan(f)
+}
+
+extern "C" fn dusk_atan2(im: f32, re: f32) -> f32 {
+    im.atan2(re)
+}
+
+extern "C" fn dusk_mod(x: f32, f: f32) -> f32 {
+    if f == 0.0 { 0.0 } else { x % f }
+}
+
+const HELPERS: &[(&str, *const u8)] = &[
+    ("dusk_fft", dusk_fft as *const u8),
+    ("dusk_param", dusk_param as *const u8),
+    ("dusk_fft_with", dusk_fft_with as *const u8),
+    ("dusk_param_with", dusk_param_with as *const u8),
+    ("dusk_fft_of", dusk_fft_of as *const u8),
+    ("dusk_hist", dusk_hist as *const u8),
+    ("dusk_state", dusk_state as *const u8),
+    ("dusk_mag", dusk_mag as *const u8),
+    ("dusk_inst_freq", dusk_inst_freq as *const u8),
+    ("dusk_synth", dusk_synth as *const u8),
+    ("dusk_env", dusk_env as *const u8),
+    ("dusk_fine", dusk_fine as *const u8),
+    ("dusk_harm", dusk_harm as *const u8),
+    ("dusk_perc", dusk_perc as *const u8),
+    ("dusk_harmonic_of", dusk_harmonic_of as *const u8),
+    ("dusk_harmonic_dist", dusk_harmonic_dist as *const u8),
+    ("dusk_noise", dusk_noise as *const u8),
+    ("dusk_rand_phase", dusk_rand_phase as *const u8),
+    ("dusk_rand", dusk_rand as *const u8),
+    ("dusk_lfo", dusk_lfo as *const u8),
+    ("dusk_step_seq", dusk_step_seq as *const u8),
+    ("dusk_sin", dusk_sin as *const u8),
+    ("dusk_cos", dusk_cos as *const u8),
+    ("dusk_tan", dusk_tan as *const u8),
+    ("dusk_atan2", dusk_atan2 as *const u8),
+    ("dusk_mod", dusk_mod as *const u8),
+];
+
+/// Value during lowering, either SSA values or a partially applied library function
+#[derive(Clone)]
+enum JitValue {
+    Float(IrValue),
+    Bool(IrValue),
+    Tuple(Vec<JitValue>),
+    Lib(Lib, Vec<JitValue>),
+}
+
+struct Helpers {
+    fft: FuncRef,
+    param: FuncRef,
+    fft_with: FuncRef,
+    param_with: FuncRef,
+    fft_of: FuncRef,
+    hist: FuncRef,
+    state: FuncRef,
+    mag: FuncRef,
+    inst_freq: FuncRef,
+    synth: FuncRef,
+    env: FuncRef,
+    fine: FuncRef,
+    harm: FuncRef,
+    perc: FuncRef,
+    harmonic_of: FuncRef,
+    harmonic_dist: FuncRef,
+    noise: FuncRef,
+    rand_phase: FuncRef,
+    rand: FuncRef,
+    lfo: FuncRef,
+    step_seq: FuncRef,
+    sin: FuncRef,
+    cos: FuncRef,
+    tan: FuncRef,
+    atan2: FuncRef,
+    modulo: FuncRef,
+}
+
+/// Lowers a term into the function being built
+struct Lower<'a, 'b> {
+    builder: &'a mut FunctionBuilder<'b>,
+    helpers: Helpers,
+    ptr: Type,
+    res: IrValue,
+}
+
+impl<'a, 'b> Lower<'a, 'b> {
+    fn new(
+        module: &mut JITModule,
+        builder: &'a mut FunctionBuilder<'b>,
+        ptr: Type,
+        res: IrValue,
+    ) -> Result<Self, JitError> {
+        let mut import = |name: &str, params: &[Type], returns: &[Type]| -> Result<FuncRef, JitError> {
+            let mut sig = module.make_signature();
+            sig.params.extend(params.iter().map(|t| AbiParam::new(*t)));
+            sig.returns.extend(returns.iter().map(|t| AbiParam::new(*t)));
+            let id = module
+                .declare_function(name, Linkage::Import, &sig)
+                .map_err(|e| e.to_string())?;
+            Ok(module.declare_func_in_func(id, builder.func))
+        };
+        let helpers = Helpers {
+            fft: import("dusk_fft", &[ptr, types::F32, ptr], &[])?,
+            param: import("dusk_param", &[ptr, types::F32], &[types::F32])?,
+            fft_with: import("dusk_fft_with", &[ptr, types::I32, types::F32, ptr], &[])?,
+            param_with: import("dusk_param_with", &[ptr, types::I32, types::F32], &[types::F32])?,
+            fft_of: import("dusk_fft_of", &[ptr, types::I32, types::F32, ptr], &[])?,
+            hist: import("dusk_hist", &[ptr, types::F32, types::F32, ptr], &[])?,
+            state: import(
+                "dusk_state",
+                &[ptr, types::I32, types::I32, types::F32, types::F32, types::F32],
+                &[types::F32],
+            )?,
+            mag: import("dusk_mag", &[ptr, types::F32], &[types::F32])?,
+            inst_freq: import("dusk_inst_freq", &[ptr, types::F32], &[types::F32])?,
+            synth: import("dusk_synth", &[ptr, types::F32, types::F32, ptr], &[])?,
+            env: import("dusk_env", &[ptr, types::F32], &[types::F32])?,
+            fine: import("dusk_fine", &[ptr, types::F32], &[types::F32])?,
+            harm: import("dusk_harm", &[ptr, types::F32, ptr], &[])?,
+            perc: import("dusk_perc", &[ptr, types::F32, ptr], &[])?,
+            harmonic_of: import("dusk_harmonic_of", &[ptr, types::F32], &[types::F32])?,
+            harmonic_dist: import("dusk_harmonic_dist", &[ptr, types::F32], &[types::F32])?,
+            noise: import("dusk_noise", &[ptr, types::F32], &[types::F32])?,
+            rand_phase: import("dusk_rand_phase", &[ptr, types::F32], &[types::F32])?,
+            rand: import("dusk_rand", &[ptr, types::F32, types::F32], &[types::F32])?,
+            lfo: import("dusk_lfo", &[ptr, types::I32, types::F32], &[types::F32])?,
+            step_seq: import("dusk_step_seq", &[ptr, types::F32, types::F32], &[types::F32])?,
+            sin: import("dusk_sin", &[types::F32], &[types::F32])?,
+            cos: import("dusk_cos", &[types::F32], &[types::F32])?,
+            tan: import("dusk_tan", &[types::F32], &[types::F32])?,
+            atan2: import("dusk_atan2", &[types::F32, types::F32], &[types::F32])?,
+            modulo: import("dusk_mod", &[types::F32, types::F32], &[types::F32])?,
+        };
+        Ok(Self { builder, helpers, ptr, res })
+    }
+
+    fn term(&mut self, term: &Term, env: &mut Vec<JitValue>) -> Result<JitValue, JitError> {
+        match term {
+            Term::Float(x) => Ok(JitValue::Float(self.builder.ins().f32const(*x))),
+            Term::Bool(x) => Ok(JitValue::Bool(self.builder.ins().iconst(types::I8, *x as i64))),
+            Term::Var(v) => env
+                .len()
+                .checked_sub(*v as usize + 1)
+                .map(|i| env[i].clone())
+                .ok_or_else(|| format!("Unbound variable: {}", v)),
+            Term::Lib(lib) => self.lib(lib.clone(), Vec::new()),
+            Term::Tuple(terms) => {
+                let mut values = Vec::new();
+                for term in terms {
+                    values.push(self.term(term, env)?);
+                }
+                Ok(JitValue::Tuple(values))
+            }
+            Term::Apply(func, arg) => {
+                let func = self.term(func, env)?;
+                let arg = self.term(arg, env)?;
+                match func {
+                    JitValue::Lib(lib, mut args) => {
+                        args.push(arg);
+                        self.lib(lib, args)
+                    }
+                    _ => Err(format!("Unsupported application: {}", term.pretty_term())),
+                }
+            }
+            Term::Func(_, _, _) => Err(format!("Unsupported function: {}", term.pretty_term())),
+            Term::Let(_, _, body, next) => {
+                let value = self.term(body, env)?;
+                env.push(value);
+                let result = self.term(next, env);
+                env.pop();
+                result
+            }
+            Term::Alt(cond, then, else_) => {
+                let JitValue::Bool(cond) = self.term(cond, env)? else {
+                    return Err(format!("Not a boolean: {}", cond.pretty_term()));
+                };
+                let then_block = self.builder.create_block();
+                let else_block = self.builder.create_block();
+                let merge_block = self.builder.create_block();
+                self.builder.ins().brif(cond, then_block, &[], else_block, &[]);
+
+                self.builder.switch_to_block(then_block);
+                let then = self.term(then, env)?;
+                let mut then_leaves = Vec::new();
+                flatten(&then, &mut then_leaves)?;
+                for leaf in &then_leaves {
+                    let ty = self.builder.func.dfg.value_type(*leaf);
+                    self.builder.append_block_param(merge_block, ty);
+                }
+                self.builder.ins().jump(merge_block, &then_leaves);
+
+                self.builder.switch_to_block(else_block);
+                let else_ = self.term(else_, env)?;
+                let mut else_leaves = Vec::new();
+                flatten(&else_, &mut else_leaves)?;
+                if else_leaves.len() != then_leaves.len() {
+                    return Err("Branches have different shapes".into());
+                }
+                self.builder.ins().jump(merge_block, &else_leaves);
+
+                self.builder.switch_to_block(merge_block);
+                let mut params = self.builder.block_params(merge_block).to_vec().into_iter();
+                Ok(rebuild(&then, &mut params))
+            }
+        }
+    }
+
+    /// Apply a library function once enough arguments are collected
+    fn lib(&mut self, lib: Lib, mut args: Vec<JitValue>) -> Result<JitValue, JitError> {
+        if args.len() < arity(&lib)? {
+            return Ok(JitValue::Lib(lib, args));
+        }
+        let value = match lib {
+            Lib::Beat => self.load_f64(mem::offset_of!(ResourceAbi, beat)),
+            Lib::Sec => self.load_f64(mem::offset_of!(ResourceAbi, second)),
+            Lib::Srate => self.load_f32(mem::offset_of!(ResourceAbi, sample_rate)),
+            Lib::Wsize => self.load_f32(mem::offset_of!(ResourceAbi, window_size)),
+            Lib::Hop => self.load_f32(mem::offset_of!(ResourceAbi, hop)),
+            Lib::Nyquist => {
+                let sample_rate = self.load_f32(mem::offset_of!(ResourceAbi, sample_rate));
+                let half = self.builder.ins().f32const(0.5);
+                self.builder.ins().fmul(sample_rate, half)
+            }
+            Lib::Channel => self.load_f32(mem::offset_of!(ResourceAbi, channel)),
+            Lib::Feature(feature) => {
+                let index = feature as usize * mem::size_of::<f32>();
+                self.load_f32(mem::offset_of!(ResourceAbi, features) + index)
+            }
+            Lib::Timing(timing) => {
+                let index = timing as usize * mem::size_of::<f32>();
+                self.load_f32(mem::offset_of!(ResourceAbi, timing) + index)
+            }
+            Lib::IsMid | Lib::IsSide | Lib::Onset | Lib::Playing | Lib::Loop => {
+                let offset = match lib {
+                    Lib::IsMid => mem::offset_of!(ResourceAbi, is_mid),
+                    Lib::IsSide => mem::offset_of!(ResourceAbi, is_side),
+                    Lib::Onset => mem::offset_of!(ResourceAbi, onset),
+                    Lib::Playing => mem::offset_of!(ResourceAbi, playing),
+                    _ => mem::offset_of!(ResourceAbi, looping),
+                };
+                let res = self.res;
+                let flag = self.builder.ins().load(types::I8, MemFlags::trusted(), res, offset as i32);
+                return Ok(JitValue::Bool(flag));
+            }
+            Lib::Hz | Lib::Bin => {
+                let f = self.float(args.pop())?;
+                let sample_rate = self.load_f32(mem::offset_of!(ResourceAbi, sample_rate));
+                let window_size = self.load_f32(mem::offset_of!(ResourceAbi, window_size));
+                let (num, den) = match lib {
+                    Lib::Hz => (sample_rate, window_size),
+                    _ => (window_size, sample_rate),
+                };
+                let scaled = self.builder.ins().fmul(f, num);
+                self.builder.ins().fdiv(scaled, den)
+            }
+            Lib::Fft => {
+                let f = self.float(args.pop())?;
+                let res = self.res;
+                return Ok(self.call_complex(self.helpers.fft, &[res, f]));
+            }
+            Lib::Param => {
+                let f = self.float(args.pop())?;
+                let res = self.res;
+                self.call(self.helpers.param, &[res, f])
+            }
+            Lib::FftWith(mode) => {
+                let f = self.float(args.pop())?;
+                let mode = self.builder.ins().iconst(types::I32, mode as i64);
+                let res = self.res;
+                return Ok(self.call_complex(self.helpers.fft_with, &[res, mode, f]));
+            }
+            Lib::ParamWith(mode) => {
+                let f = self.float(args.pop())?;
+                let mode = self.builder.ins().iconst(types::I32, mode as i64);
+                let res = self.res;
+                self.call(self.helpers.param_with, &[res, mode, f])
+            }
+            Lib::FftOf(source) => {
+                let f = self.float(args.pop())?;
+                let source = self.builder.ins().iconst(types::I32, source as i64);
+                let res = self.res;
+                return Ok(self.call_complex(self.helpers.fft_of, &[res, source, f]));
+            }
+            Lib::Hist | Lib::Hist1(_) | Lib::FftPrev => {
+                let [k, f] = match lib {
+                    Lib::Hist => {
+                        let f = self.float(args.pop())?;
+                        [self.float(args.pop())?, f]
+                    }
+                    Lib::Hist1(k) => {
+                        let f = self.float(args.pop())?;
+                        [self.builder.ins().f32const(k), f]
+                    }
+                    _ => self.complex(args.pop().unwrap_or(JitValue::Tuple(vec![])))?,
+                };
+                let res = self.res;
+                return Ok(self.call_complex(self.helpers.hist, &[res, k, f]));
+            }
+            Lib::State(op, slot) => {
+                let JitValue::Tuple(xs) = args.pop().unwrap_or(JitValue::Tuple(vec![])) else {
+                    return Err("Expected a tuple".into());
+                };
+                if xs.len() != op.params() + 1 {
+                    return Err(format!("Expected {} arguments to {}", op.params() + 1, op.name()));
+                }
+                let mut values = Vec::new();
+                for x in xs {
+                    values.push(self.float(Some(x))?);
+                }
+                let x = values.pop().unwrap();
+                let zero = self.builder.ins().f32const(0.0);
+                values.resize(2, zero);
+                let op = self.builder.ins().iconst(types::I32, op as i64);
+                let slot = self.builder.ins().iconst(types::I32, slot as i64);
+                let res = self.res;
+                self.call(self.helpers.state, &[res, op, slot, values[0], values[1], x])
+            }
+            Lib::Mag
+            | Lib::InstFreq
+            | Lib::Env
+            | Lib::Fine
+            | Lib::HarmonicOf
+            | Lib::HarmonicDist
+            | Lib::Noise
+            | Lib::RandPhase => {
+                let f = self.float(args.pop())?;
+                let func = match lib {
+                    Lib::Mag => self.helpers.mag,
+                    Lib::InstFreq => self.helpers.inst_freq,
+                    Lib::Env => self.helpers.env,
+                    Lib::Fine => self.helpers.fine,
+                    Lib::HarmonicOf => self.helpers.harmonic_of,
+                    Lib::HarmonicDist => self.helpers.harmonic_dist,
+                    Lib::Noise => self.helpers.noise,
+                    _ => self.helpers.rand_phase,
+                };
+                let res = self.res;
+                self.call(func, &[res, f])
+            }
+            Lib::Rand => {
+                let [seed, f] = self.complex(args.pop().unwrap_or(JitValue::Tuple(vec![])))?;
+                let res = self.res;
+                self.call(self.helpers.rand, &[res, seed, f])
+            }
+            Lib::Lfo(wave) => {
+                let period = self.float(args.pop())?;
+                let wave = self.builder.ins().iconst(types::I32, wave as i64);
+                let res = self.res;
+                self.call(self.helpers.lfo, &[res, wave, period])
+            }
+            Lib::StepSeq => {
+                let [steps, step_beats] =
+                    self.complex(args.pop().unwrap_or(JitValue::Tuple(vec![])))?;
+                let res = self.res;
+                self.call(self.helpers.step_seq, &[res, steps, step_beats])
+            }
+            Lib::Harm | Lib::Perc => {
+                let f = self.float(args.pop())?;
+                let func = match lib {
+                    Lib::Harm => self.helpers.harm,
+                    _ => self.helpers.perc,
+                };
+                let res = self.res;
+                return Ok(self.call_complex(func, &[res, f]));
+            }
+            Lib::Synth => {
+                let [mag, freq] = self.complex(args.pop().unwrap_or(JitValue::Tuple(vec![])))?;
+                let res = self.res;
+                return Ok(self.call_complex(self.helpers.synth, &[res, mag, freq]));
+            }
+            Lib::Nth(k, _) => {
+                return match args.pop() {
+                    Some(JitValue::Tuple(xs)) if k < xs.len() => Ok(xs[k].clone()),
+                    _ => Err("Expected a tuple".into()),
+                };
+            }
+            Lib::Sin => {
+                let f = self.float(args.pop())?;
+                self.call(self.helpers.sin, &[f])
+            }
+            Lib::Cos => {
+                let f = self.float(args.pop())?;
+                self.call(self.helpers.cos, &[f])
+            }
+            Lib::Tan => {
+                let f = self.float(args.pop())?;
+                self.call(self.helpers.tan, &[f])
+            }
+            Lib::Re | Lib::Im | Lib::Norm | Lib::Angle | Lib::Polar => {
+                let [x, y] = self.complex(args.pop().unwrap_or(JitValue::Tuple(vec![])))?;
+                match lib {
+                    Lib::Re => x,
+                    Lib::Im => y,
+                    Lib::Norm => {
+                        let xx = self.builder.ins().fmul(x, x);
+                        let yy = self.builder.ins().fmul(y, y);
+                        let sum = self.builder.ins().fadd(xx, yy);
+                        self.builder.ins().sqrt(sum)
+                    }
+                    Lib::Angle => self.call(self.helpers.atan2, &[y, x]),
+                    _ => {
+                        let cos = self.call(self.helpers.cos, &[y]);
+                        let sin = self.call(self.helpers.sin, &[y]);
+                        let re = self.builder.ins().fmul(x, cos);
+                        let im = self.builder.ins().fmul(x, sin);
+                        return Ok(JitValue::Tuple(vec![JitValue::Float(re), JitValue::Float(im)]));
+                    }
+                }
+            }
+            Lib::Add | Lib::Sub | Lib::Mul | Lib::Div | Lib::Mod | Lib::Lt | Lib::Le | Lib::Gt | Lib::Ge => {
+                let y = self.float(args.pop())?;
+                let x = self.float(args.pop())?;
+                return self.binary(&lib, x, y);
+            }
+            Lib::Add1(x) | Lib::Sub1(x) | Lib::Mul1(x) | Lib::Div1(x) | Lib::Mod1(x)
+            | Lib::Lt1(x) | Lib::Le1(x) | Lib::Gt1(x) | Lib::Ge1(x) => {
+                let y = self.float(args.pop())?;
+                let x = self.builder.ins().f32const(x);
+                return self.binary(&lib, x, y);
+            }
+            Lib::AddI(x) | Lib::SubI(x) | Lib::MulI(x) | Lib::DivI(x) | Lib::ModI(x)
+            | Lib::LtI(x) | Lib::LeI(x) | Lib::GtI(x) | Lib::GeI(x) => {
+                let y = self.float(args.pop())?;
+                let x = self.builder.ins().f32const(x as f32);
+                return self.binary(&lib, x, y);
+            }
+        };
+        Ok(JitValue::Float(value))
+    }
+
+    /// Binary arithmetic and comparison, `x` being the first argument
+    fn binary(&mut self, lib: &Lib, x: IrValue, y: IrValue) -> Result<JitValue, JitError> {
+        let value = match lib {
+            Lib::Add | Lib::Add1(_) | Lib::AddI(_) => self.builder.ins().fadd(x, y),
+            Lib::Sub | Lib::Sub1(_) | Lib::SubI(_) => self.builder.ins().fsub(x, y),
+            Lib::Mul | Lib::Mul1(_) | Lib::MulI(_) => self.builder.ins().fmul(x, y),
+            Lib::Div | Lib::Div1(_) | Lib::DivI(_) => {
+                // Division by zero results in zero
+                let zero = self.builder.ins().f32const(0.0);
+                let is_zero = self.builder.ins().fcmp(FloatCC::Equal, y, zero);
+                let quotient = self.builder.ins().fdiv(x, y);
+                self.builder.ins().select(is_zero, zero, quotient)
+            }
+            Lib::Mod | Lib::Mod1(_) | Lib::ModI(_) => self.call(self.helpers.modulo, &[x, y]),
+            _ => {
+                let cond = match lib {
+                    Lib::Lt | Lib::Lt1(_) | Lib::LtI(_) => FloatCC::LessThan,
+                    Lib::Le | Lib::Le1(_) | Lib::LeI(_) => FloatCC::LessThanOrEqual,
+                    Lib::Gt | Lib::Gt1(_) | Lib::GtI(_) => FloatCC::GreaterThan,
+                    _ => FloatCC::GreaterThanOrEqual,
+                };
+                return Ok(JitValue::Bool(self.builder.ins().fcmp(cond, x, y)));
+            }
+        };
+        Ok(JitValue::Float(value))
+    }
+
+    fn call(&mut self, func: FuncRef, args: &[IrValue]) -> IrValue {
+        let inst = self.builder.ins().call(func, args);
+        self.builder.inst_results(inst)[0]
+    }
+
+    /// Call a helper that writes a complex number through its last argument
+    fn call_complex(&mut self, func: FuncRef, args: &[IrValue]) -> JitValue {
+        let slot = self.builder.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            8,
+            2,
+        ));
+        let out = self.builder.ins().stack_addr(self.ptr, slot, 0);
+        let args: Vec<IrValue> = args.iter().copied().chain([out]).collect();
+        self.builder.ins().call(func, &args);
+        let re = self.builder.ins().stack_load(types::F32, slot, 0);
+        let im = self.builder.ins().stack_load(types::F32, slot, 4);
+        JitValue::Tuple(vec![JitValue::Float(re), JitValue::Float(im)])
+    }
+
+    fn load_f32(&mut self, offset: usize) -> IrValue {
+        let res = self.res;
+        self.builder.ins().load(types::F32, MemFlags::trusted(), res, offset as i32)
+    }
+
+    fn load_f64(&mut self, offset: usize) -> IrValue {
+        let res = self.res;
+        let value = self.builder.ins().load(types::F64, MemFlags::trusted(), res, offset as i32);
+        self.builder.ins().fdemote(types::F32, value)
+    }
+
+    /// Store booleans as 0 or 1, so that every prologue value takes a float
+    fn store_leaf(&mut self, leaf: IrValue) -> IrValue {
+        if self.builder.func.dfg.value_type(leaf) != types::I8 {
+            return leaf;
+        }
+        let one = self.builder.ins().f32const(1.0);
+        let zero = self.builder.ins().f32const(0.0);
+        self.builder.ins().select(leaf, one, zero)
+    }
+
+    /// Load a prologue value of the same shape as `shape`, starting at `offset` in `values`
+    fn load_leaves(&mut self, shape: &JitValue, values: IrValue, offset: &mut i32) -> JitValue {
+        match shape {
+            JitValue::Float(_) | JitValue::Bool(_) => {
+                let flags = MemFlags::trusted();
+                let leaf = self.builder.ins().load(types::F32, flags, values, *offset);
+                *offset += 4;
+                if let JitValue::Float(_) = shape {
+                    return JitValue::Float(leaf);
+                }
+                let zero = self.builder.ins().f32const(0.0);
+                JitValue::Bool(self.builder.ins().fcmp(FloatCC::NotEqual, leaf, zero))
+            }
+            JitValue::Tuple(xs) => JitValue::Tuple(
+                xs.iter()
+                    .map(|x| self.load_leaves(x, values, offset))
+                    .collect(),
+            ),
+            lib @ JitValue::Lib(_, _) => lib.clone(),
+        }
+    }
+
+    fn float(&mut self, value: Option<JitValue>) -> Result<IrValue, JitError> {
+        match value {
+            Some(JitValue::Float(x)) => Ok(x),
+            _ => Err("Expected a float".into()),
+        }
+    }
+
+    fn complex(&mut self, value: JitValue) -> Result<[IrValue; 2], JitError> {
+        match value {
+            JitValue::Tuple(xs) => match xs.as_slice() {
+                [JitValue::Float(re), JitValue::Float(im)] => Ok([*re, *im]),
+                _ => Err("Expected a complex number".into()),
+            },
+            _ => Err("Expected a complex number".into()),
+        }
+    }
+}
+
+/// Number of arguments a library function takes before it computes
+fn arity(lib: &Lib) -> Result<usize, JitError> {
+    match lib {
+        Lib::Beat | Lib::Sec => Ok(0),
+        Lib::Srate | Lib::Wsize | Lib::Hop | Lib::Nyquist => Ok(0),
+        Lib::Hz | Lib::Bin => Ok(1),
+        Lib::Channel | Lib::IsMid | Lib::IsSide | Lib::Onset | Lib::Feature(_) => Ok(0),
+        Lib::Timing(_) | Lib::Playing | Lib::Loop => Ok(0),
+        Lib::FftOf(_) => Ok(1),
+        Lib::Hist => Ok(2),
+        Lib::Hist1(_) | Lib::FftPrev => Ok(1),
+        Lib::State(..) | Lib::Nth(..) => Ok(1),
+        Lib::Mag | Lib::InstFreq | Lib::Synth | Lib::Env | Lib::Fine => Ok(1),
+        Lib::Harm | Lib::Perc | Lib::HarmonicOf | Lib::HarmonicDist => Ok(1),
+        Lib::Noise | Lib::RandPhase | Lib::Rand | Lib::Lfo(_) | Lib::StepSeq => Ok(1),
+        Lib::Fft | Lib::Param | Lib::Sin | Lib::Cos | Lib::Tan => Ok(1),
+        Lib::FftWith(_) | Lib::ParamWith(_) => Ok(1),
+        Lib::Re | Lib::Im | Lib::Norm | Lib::Angle | Lib::Polar => Ok(1),
+        Lib::Add | Lib::Sub | Lib::Mul | Lib::Div | Lib::Mod => Ok(2),
+        Lib::Lt | Lib::Le | Lib::Gt | Lib::Ge => Ok(2),
+        Lib::Add1(_) | Lib::Sub1(_) | Lib::Mul1(_) | Lib::Div1(_) | Lib::Mod1(_) => Ok(1),
+        Lib::Lt1(_) | Lib::Le1(_) | Lib::Gt1(_) | Lib::Ge1(_) => Ok(1),
+        Lib::AddI(_) | Lib::SubI(_) | Lib::MulI(_) | Lib::DivI(_) | Lib::ModI(_) => Ok(1),
+        Lib::LtI(_) | Lib::LeI(_) | Lib::GtI(_) | Lib::GeI(_) => Ok(1),
+    }
+}
+
+/// Collect the SSA values of a first-order value
+fn flatten(value: &JitValue, leaves: &mut Vec<IrValue>) -> Result<(), JitError> {
+    match value {
+        JitValue::Float(x) | JitValue::Bool(x) => leaves.push(*x),
+        JitValue::Tuple(xs) => {
+            for x in xs {
+                flatten(x, leaves)?;
+            }
+        }
+        JitValue::Lib(lib, _) => return Err(format!("Unsupported branch on function: {}", lib)),
+    }
+    Ok(())
+}
+
+/// Rebuild a value of the same shape from merged SSA values
+fn rebuild(shape: &JitValue, leaves: &mut impl Iterator<Item = IrValue>) -> JitValue {
+    match shape {
+        JitValue::Float(_) => JitValue::Float(leaves.next().unwrap()),
+        JitValue::Bool(_) => JitValue::Bool(leaves.next().unwrap()),
+        JitValue::Tuple(xs) => JitValue::Tuple(xs.iter().map(|x| rebuild(x, leaves)).collect()),
+        lib @ JitValue::Lib(_, _) => lib.clone(),
+    }
+}
+
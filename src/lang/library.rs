@@ -1,6 +1,87 @@
 use std::fmt::Display;
+use realfft::num_complex::Complex32;
 use super::*;
 
+/// Look up a spectrum at a fractional band, interpolating with a raised cosine
+pub fn fft_at(fft: &[Complex32], f: f32) -> Complex32 {
+    let floor = f.floor() as usize;
+    let ceil = f.ceil() as usize;
+    if ceil >= fft.len() || floor >= fft.len() {
+        Complex32::default()
+    } else {
+        let lower = fft[floor];
+        let upper = fft[ceil];
+        let fraction = f.fract();
+        let fraction = (1.0 - (fraction * std::f32::consts::PI).cos()) * 0.5;
+        lower + (upper - lower) * fraction
+    }
+}
+
+/// Look up modulation params at a fractional index, interpolating with a raised cosine
+pub fn param_at(modulation: &[f32], f: f32) -> f32 {
+    let floor = f.floor() as usize;
+    let ceil = f.ceil() as usize;
+    if ceil >= modulation.len() || floor >= modulation.len() {
+        0.0
+    } else {
+        let lower = modulation[floor];
+        let upper = modulation[ceil];
+        let fraction = f.fract();
+        let fraction = (1.0 - (fraction * std::f32::consts::PI).cos()) * 0.5;
+        lower + (upper - lower) * fraction
+    }
+}
+
+/// Look up the spectral envelope at a fractional band, interpolating linearly. Bands outside of
+/// it are silent.
+pub fn envelope_at(envelope: &[f32], f: f32) -> f32 {
+    let floor = f.floor() as usize;
+    let ceil = f.ceil() as usize;
+    if f < 0.0 || ceil >= envelope.len() {
+        0.0
+    } else {
+        let lower = envelope[floor];
+        lower + (envelope[ceil] - lower) * f.fract()
+    }
+}
+
+/// Magnitude of a spectrum relative to its envelope at a fractional band, silent where the
+/// envelope is
+pub fn fine_at(fft: &[Complex32], envelope: &[f32], f: f32) -> f32 {
+    let envelope = envelope_at(envelope, f);
+    if envelope > 0.0 {
+        mag_at(fft, f) / envelope
+    } else {
+        0.0
+    }
+}
+
+/// Harmonic part of a spectrum at a fractional band, given the harmonic fraction of each band.
+/// Without a separation everything is harmonic.
+pub fn harm_at(fft: &[Complex32], harmonic: &[f32], f: f32) -> Complex32 {
+    let value = fft_at(fft, f);
+    if harmonic.is_empty() {
+        value
+    } else {
+        value * envelope_at(harmonic, f)
+    }
+}
+
+/// Percussive part of a spectrum at a fractional band, the rest of it after `harm_at`
+pub fn perc_at(fft: &[Complex32], harmonic: &[f32], f: f32) -> Complex32 {
+    fft_at(fft, f) - harm_at(fft, harmonic, f)
+}
+
+/// Tangent that is undefined near the poles
+pub fn tan(f: f32) -> f32 {
+    if (f.abs() - std::f32::consts::FRAC_PI_2).abs() < 0.0001 {
+        // Handle edge case where tangent is undefined
+        f32::NAN
+    } else {
+        f.tan()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Lib {
     Fft,
@@ -42,58 +123,331 @@ pub enum Lib {
     GtI(i32),
     GeI(i32),
     Tan,
+    FftWith(Interp),
+    ParamWith(Interp),
+    Srate,
+    Wsize,
+    Hop,
+    Nyquist,
+    Hz,
+    Bin,
+    Channel,
+    IsMid,
+    IsSide,
+    FftOf(Source),
+    Hist,
+    Hist1(f32),
+    FftPrev,
+    /// A stateful call with its slot, every call site gets its own slot once simplified
+    State(StateOp, usize),
+    /// Component `k` of a tuple with the given component types, from destructuring
+    Nth(usize, Vec<ValueType>),
+    Mag,
+    InstFreq,
+    Synth,
+    Env,
+    Fine,
+    Harm,
+    Perc,
+    Feature(Feature),
+    HarmonicOf,
+    HarmonicDist,
+    Onset,
+    Noise,
+    RandPhase,
+    Rand,
+    Timing(Timing),
+    Playing,
+    Loop,
+    Lfo(Wave),
+    StepSeq,
 }
 
 impl Display for Lib {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Lib::Fft => write!(f, "fft"),
-            Lib::Param => write!(f, "param"),
-            Lib::Beat => write!(f, "beat"),
-            Lib::Sec => write!(f, "sec"),
-            Lib::Add => write!(f, "add"),
-            Lib::Sub => write!(f, "sub"),
-            Lib::Mul => write!(f, "mul"),
-            Lib::Div => write!(f, "div"),
-            Lib::Mod => write!(f, "mod"),
-            Lib::Sin => write!(f, "sin"),
-            Lib::Cos => write!(f, "cos"),
-            Lib::Re => write!(f, "re"),
-            Lib::Im => write!(f, "im"),
-            Lib::Norm => write!(f, "norm"),
-            Lib::Angle => write!(f, "angle"),
-            Lib::Polar => write!(f, "polar"),
-            Lib::Lt => write!(f, "lt"),
-            Lib::Le => write!(f, "le"),
-            Lib::Gt => write!(f, "gt"),
-            Lib::Ge => write!(f, "ge"),
-            Lib::Add1(x) => write!(f, "add({:.3})", x),
-            Lib::Sub1(x) => write!(f, "sub({:.3})", x),
-            Lib::Mul1(x) => write!(f, "mul({:.3})", x),
-            Lib::Div1(x) => write!(f, "div({:.3})", x),
-            Lib::Mod1(x) => write!(f, "mod({:.3})", x),
-            Lib::Lt1(x) => write!(f, "lt({:.3})", x),
-            Lib::Le1(x) => write!(f, "le({:.3})", x),
-            Lib::Gt1(x) => write!(f, "gt({:.3})", x),
-            Lib::Ge1(x) => write!(f, "ge({:.3})", x),
-            Lib::AddI(x) => write!(f, "add({})", x),
-            Lib::SubI(x) => write!(f, "sub({})", x),
-            Lib::MulI(x) => write!(f, "mul({})", x),
-            Lib::DivI(x) => write!(f, "div({})", x),
-            Lib::ModI(x) => write!(f, "mod({})", x),
-            Lib::LtI(x) => write!(f, "lt({})", x),
-            Lib::LeI(x) => write!(f, "le({})", x),
-            Lib::GtI(x) => write!(f, "gt({})", x),
-            Lib::GeI(x) => write!(f, "ge({})", x),
-            Lib::Tan => write!(f, "tan"),
+            Lib::Add1(x) | Lib::Sub1(x) | Lib::Mul1(x) | Lib::Div1(x) | Lib::Mod1(x)
+            | Lib::Lt1(x) | Lib::Le1(x) | Lib::Gt1(x) | Lib::Ge1(x) | Lib::Hist1(x) => {
+                write!(f, "{}({:.3})", self.name(), x)
+            }
+            Lib::AddI(x) | Lib::SubI(x) | Lib::MulI(x) | Lib::DivI(x) | Lib::ModI(x)
+            | Lib::LtI(x) | Lib::LeI(x) | Lib::GtI(x) | Lib::GeI(x) => {
+                write!(f, "{}({})", self.name(), x)
+            }
+            Lib::Nth(k, _) => write!(f, "{}({})", self.name(), k),
+            _ => write!(f, "{}", self.name()),
         }
     }
 }
 
 impl Lib {
+    /// Name of the library function, without partially applied arguments
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lib::Fft => "fft",
+            Lib::Param => "param",
+            Lib::Beat => "beat",
+            Lib::Sec => "sec",
+            Lib::Add | Lib::Add1(_) | Lib::AddI(_) => "add",
+            Lib::Sub | Lib::Sub1(_) | Lib::SubI(_) => "sub",
+            Lib::Mul | Lib::Mul1(_) | Lib::MulI(_) => "mul",
+            Lib::Div | Lib::Div1(_) | Lib::DivI(_) => "div",
+            Lib::Mod | Lib::Mod1(_) | Lib::ModI(_) => "mod",
+            Lib::Sin => "sin",
+            Lib::Cos => "cos",
+            Lib::Re => "re",
+            Lib::Im => "im",
+            Lib::Norm => "norm",
+            Lib::Angle => "angle",
+            Lib::Polar => "polar",
+            Lib::Lt | Lib::Lt1(_) | Lib::LtI(_) => "lt",
+            Lib::Le | Lib::Le1(_) | Lib::LeI(_) => "le",
+            Lib::Gt | Lib::Gt1(_) | Lib::GtI(_) => "gt",
+            Lib::Ge | Lib::Ge1(_) | Lib::GeI(_) => "ge",
+            Lib::Tan => "tan",
+            Lib::FftWith(mode) => match mode {
+                Interp::Nearest => "fft_nearest",
+                Interp::Linear => "fft_linear",
+                Interp::Cosine => "fft_cosine",
+                Interp::Cubic => "fft_cubic",
+                Interp::Sinc => "fft_sinc",
+            },
+            Lib::Srate => "srate",
+            Lib::Wsize => "wsize",
+            Lib::Hop => "hop",
+            Lib::Nyquist => "nyquist",
+            Lib::Hz => "hz",
+            Lib::Bin => "bin",
+            Lib::Channel => "channel",
+            Lib::IsMid => "is_mid",
+            Lib::IsSide => "is_side",
+            Lib::FftOf(source) => match source {
+                Source::Left => "fft_l",
+                Source::Right => "fft_r",
+                Source::Mid => "fft_mid",
+                Source::Side => "fft_side",
+                Source::Sidechain => "side",
+                Source::Output => "out_prev",
+            },
+            Lib::Hist | Lib::Hist1(_) => "hist",
+            Lib::FftPrev => "fft_prev",
+            Lib::State(op, _) => op.name(),
+            Lib::Nth(_, _) => "nth",
+            Lib::Mag => "mag",
+            Lib::InstFreq => "inst_freq",
+            Lib::Synth => "synth",
+            Lib::Env => "env",
+            Lib::Fine => "fine",
+            Lib::Harm => "harm",
+            Lib::Perc => "perc",
+            Lib::Feature(feature) => feature.name(),
+            Lib::HarmonicOf => "harmonic_of",
+            Lib::HarmonicDist => "harmonic_dist",
+            Lib::Onset => "onset",
+            Lib::Noise => "noise",
+            Lib::RandPhase => "rand_phase",
+            Lib::Rand => "rand",
+            Lib::Timing(timing) => timing.name(),
+            Lib::Playing => "playing",
+            Lib::Loop => "loop",
+            Lib::Lfo(wave) => wave.name(),
+            Lib::StepSeq => "step_seq",
+            Lib::ParamWith(mode) => match mode {
+                Interp::Nearest => "param_nearest",
+                Interp::Linear => "param_linear",
+                Interp::Cosine => "param_cosine",
+                Interp::Cubic => "param_cubic",
+                Interp::Sinc => "param_sinc",
+            },
+        }
+    }
+
+    /// The same lookup with the default interpolation, or the same stateful call in slot 0,
+    /// other functions are unchanged
+    pub fn canonical(&self) -> Lib {
+        match self {
+            Lib::FftWith(_) => Lib::Fft,
+            Lib::ParamWith(_) => Lib::Param,
+            Lib::State(op, _) => Lib::State(*op, 0),
+            lib => lib.clone(),
+        }
+    }
+
     // Check if library function is a symbol
     pub fn is_symbol(&self) -> bool {
-        matches!(self, Lib::Fft | Lib::Param | Lib::Beat | Lib::Sec)
+        matches!(
+            self,
+            Lib::Fft
+                | Lib::Param
+                | Lib::Beat
+                | Lib::Sec
+                | Lib::FftWith(_)
+                | Lib::ParamWith(_)
+                | Lib::Srate
+                | Lib::Wsize
+                | Lib::Hop
+                | Lib::Nyquist
+                | Lib::Hz
+                | Lib::Bin
+                | Lib::Channel
+                | Lib::IsMid
+                | Lib::IsSide
+                | Lib::FftOf(_)
+                | Lib::Hist
+                | Lib::Hist1(_)
+                | Lib::FftPrev
+                | Lib::State(..)
+                | Lib::Mag
+                | Lib::InstFreq
+                | Lib::Synth
+                | Lib::Env
+                | Lib::Fine
+                | Lib::Harm
+                | Lib::Perc
+                | Lib::Feature(_)
+                | Lib::HarmonicOf
+                | Lib::HarmonicDist
+                | Lib::Onset
+                | Lib::Noise
+                | Lib::RandPhase
+                | Lib::Rand
+                | Lib::Timing(_)
+                | Lib::Playing
+                | Lib::Loop
+                | Lib::Lfo(_)
+                | Lib::StepSeq
+        )
+    }
+
+    /// Check if library function depends on the bin being evaluated without taking it as an
+    /// argument. Stateful calls keep a value for each bin and `synth` a phase.
+    pub fn reads_bin(&self) -> bool {
+        match self {
+            Lib::State(..) | Lib::Synth => true,
+            Lib::Fft
+            | Lib::Param
+            | Lib::Beat
+            | Lib::Sec
+            | Lib::Add
+            | Lib::Sub
+            | Lib::Mul
+            | Lib::Div
+            | Lib::Mod
+            | Lib::Sin
+            | Lib::Cos
+            | Lib::Re
+            | Lib::Im
+            | Lib::Norm
+            | Lib::Angle
+            | Lib::Polar
+            | Lib::Lt
+            | Lib::Le
+            | Lib::Gt
+            | Lib::Ge
+            | Lib::Add1(_)
+            | Lib::Sub1(_)
+            | Lib::Mul1(_)
+            | Lib::Div1(_)
+            | Lib::Mod1(_)
+            | Lib::Lt1(_)
+            | Lib::Le1(_)
+            | Lib::Gt1(_)
+            | Lib::Ge1(_)
+            | Lib::AddI(_)
+            | Lib::SubI(_)
+            | Lib::MulI(_)
+            | Lib::DivI(_)
+            | Lib::ModI(_)
+            | Lib::LtI(_)
+            | Lib::LeI(_)
+            | Lib::GtI(_)
+            | Lib::GeI(_)
+            | Lib::Tan
+            | Lib::FftWith(_)
+            | Lib::ParamWith(_)
+            | Lib::Srate
+            | Lib::Wsize
+            | Lib::Hop
+            | Lib::Nyquist
+            | Lib::Hz
+            | Lib::Bin
+            | Lib::Channel
+            | Lib::IsMid
+            | Lib::IsSide
+            | Lib::FftOf(_)
+            | Lib::Hist
+            | Lib::Hist1(_)
+            | Lib::FftPrev
+            | Lib::Nth(..)
+            | Lib::Mag
+            | Lib::InstFreq
+            | Lib::Env
+            | Lib::Fine
+            | Lib::Harm
+            | Lib::Perc
+            | Lib::Feature(_)
+            | Lib::HarmonicOf
+            | Lib::HarmonicDist
+            | Lib::Onset
+            | Lib::Noise
+            | Lib::RandPhase
+            | Lib::Rand
+            | Lib::Timing(_)
+            | Lib::Lfo(_)
+            | Lib::Playing
+            | Lib::Loop
+            | Lib::StepSeq => false,
+        }
+    }
+
+    /// Check if library function gives the same result in every frame, given the same modulation
+    pub fn is_static(&self) -> bool {
+        matches!(
+            self,
+            Lib::Param
+                | Lib::ParamWith(_)
+                | Lib::Srate
+                | Lib::Wsize
+                | Lib::Nyquist
+                | Lib::Hz
+                | Lib::Bin
+                | Lib::Add
+                | Lib::Sub
+                | Lib::Mul
+                | Lib::Div
+                | Lib::Mod
+                | Lib::Sin
+                | Lib::Cos
+                | Lib::Tan
+                | Lib::Re
+                | Lib::Im
+                | Lib::Norm
+                | Lib::Angle
+                | Lib::Polar
+                | Lib::Lt
+                | Lib::Le
+                | Lib::Gt
+                | Lib::Ge
+                | Lib::Add1(_)
+                | Lib::Sub1(_)
+                | Lib::Mul1(_)
+                | Lib::Div1(_)
+                | Lib::Mod1(_)
+                | Lib::Lt1(_)
+                | Lib::Le1(_)
+                | Lib::Gt1(_)
+                | Lib::Ge1(_)
+                | Lib::AddI(_)
+                | Lib::SubI(_)
+                | Lib::MulI(_)
+                | Lib::DivI(_)
+                | Lib::ModI(_)
+                | Lib::LtI(_)
+                | Lib::LeI(_)
+                | Lib::GtI(_)
+                | Lib::GeI(_)
+                | Lib::Nth(..)
+        )
     }
 
     // Reduce to value during evaluation
@@ -101,80 +455,211 @@ impl Lib {
         match self {
             Lib::Beat => Value::Float(res.beat as f32),
             Lib::Sec => Value::Float(res.second as f32),
+            Lib::Srate => Value::Float(res.sample_rate),
+            Lib::Wsize => Value::Float(res.window_size as f32),
+            Lib::Hop => Value::Float(res.hop() as f32),
+            Lib::Nyquist => Value::Float(res.nyquist()),
+            Lib::Channel => Value::Float(res.channel() as f32),
+            Lib::IsMid => Value::Bool(res.is_mid()),
+            Lib::IsSide => Value::Bool(res.is_side()),
+            Lib::Feature(feature) => Value::Float(res.feature(feature)),
+            Lib::Onset => Value::Bool(res.features.onset.detected),
+            Lib::Timing(timing) => Value::Float(res.clock.get(timing, res.beat)),
+            Lib::Playing => Value::Bool(res.clock.playing),
+            Lib::Loop => Value::Bool(res.clock.loop_range.is_some()),
             _ => Value::Lib(self),
         }
     }
 
     // Apply in evaluation
-    pub fn apply(&self, arg: Value, res: &Resource) -> Value {
+    pub fn apply(&self, arg: Value, res: &Resource) -> Result<Value, EvalError> {
         match self {
             Lib::Fft => {
                 match arg {
                     Value::Float(f) => {
-                        let floor = f.floor() as usize;
-                        let ceil = f.ceil() as usize;
-                        if ceil >= res.fft.len() || floor >= res.fft.len() {
-                            Value::Tuple(vec![Value::Float(0.0), Value::Float(0.0)])
-                        } else {
-                            let lower = res.fft[floor];
-                            let upper = res.fft[ceil];
-                            let fraction = f.fract();
-                            let fraction = (1.0 - (fraction * std::f32::consts::PI).cos()) * 0.5;
-                            let interpolated_value = lower + (upper - lower) * fraction;
-                            Value::Tuple(vec![Value::Float(interpolated_value.re), Value::Float(interpolated_value.im)])
-                        }
+                        let value = fft_at(res.fft, f);
+                        Ok(Value::Tuple(vec![Value::Float(value.re), Value::Float(value.im)]))
                     }
                     Value::Int(i) => {
                         let i = i as usize;
                         if i >= res.fft.len() {
-                            Value::Tuple(vec![Value::Float(0.0), Value::Float(0.0)])
+                            Ok(Value::Tuple(vec![Value::Float(0.0), Value::Float(0.0)]))
                         } else {
                             let value = res.fft[i];
-                            Value::Tuple(vec![Value::Float(value.re), Value::Float(value.im)])
+                            Ok(Value::Tuple(vec![Value::Float(value.re), Value::Float(value.im)]))
                         }
                     }
-                    _ => panic!("lib function {} does not accept {}", self, arg)
+                    _ => Err(EvalError::Argument(self.name()))
                 }
             }
             Lib::Param => {
                 match arg {
-                    Value::Float(f) => {
-                        let floor = f.floor() as usize;
-                        let ceil = f.ceil() as usize;
-                        if ceil >= res.modulation.len() || floor >= res.modulation.len() {
-                            Value::Float(0.0)
-                        } else {
-                            let lower = res.modulation[floor];
-                            let upper = res.modulation[ceil];
-                            let fraction = f.fract();
-                            let fraction = (1.0 - (fraction * std::f32::consts::PI).cos()) * 0.5;
-                            let interpolated_value = lower + (upper - lower) * fraction;
-                            Value::Float(interpolated_value)
-                        }
-                    }
+                    Value::Float(f) => Ok(Value::Float(param_at(res.modulation, f))),
                     Value::Int(i) => {
                         let i = i as usize;
                         if i >= res.modulation.len() {
-                            Value::Float(0.0)
+                            Ok(Value::Float(0.0))
                         } else {
                             let value = res.modulation[i];
-                            Value::Float(value)
+                            Ok(Value::Float(value))
                         }
                     }
-                    _ => panic!("lib function {} does not accept {}", self, arg)
+                    _ => Err(EvalError::Argument(self.name()))
                 }
             }
             Lib::Tan => {
                 match arg {
-                    Value::Float(f) => {
-                        if (f.abs() - std::f32::consts::FRAC_PI_2).abs() < 0.0001 {
-                            // Handle edge case where tangent is undefined
-                            Value::Float(f32::NAN)
-                        } else {
-                            Value::Float(f.tan())
-                        }
-                    }
-                    _ => panic!("lib function tan does not accept {}", arg)
+                    Value::Float(f) => Ok(Value::Float(tan(f))),
+                    _ => self.clone().papply(arg)
+                }
+            }
+            Lib::FftWith(mode) => {
+                let f = match arg {
+                    Value::Float(f) => f,
+                    Value::Int(i) => i as f32,
+                    _ => return Err(EvalError::Argument(self.name()))
+                };
+                let value = interpolate(res.fft, f, *mode);
+                Ok(Value::Tuple(vec![Value::Float(value.re), Value::Float(value.im)]))
+            }
+            Lib::Hz | Lib::Bin => {
+                let f = match arg {
+                    Value::Float(f) => f,
+                    Value::Int(i) => i as f32,
+                    _ => return Err(EvalError::Argument(self.name()))
+                };
+                match self {
+                    Lib::Hz => Ok(Value::Float(f * res.bin_width())),
+                    _ => Ok(Value::Float(f / res.bin_width())),
+                }
+            }
+            Lib::FftOf(source) => {
+                let f = match arg {
+                    Value::Float(f) => f,
+                    Value::Int(i) => i as f32,
+                    _ => return Err(EvalError::Argument(self.name()))
+                };
+                let value = fft_at(res.spectrum(*source), f);
+                Ok(Value::Tuple(vec![Value::Float(value.re), Value::Float(value.im)]))
+            }
+            Lib::Hist => {
+                match arg {
+                    Value::Float(k) => Ok(Value::Lib(Lib::Hist1(k))),
+                    Value::Int(k) => Ok(Value::Lib(Lib::Hist1(k as f32))),
+                    _ => Err(EvalError::Argument(self.name()))
+                }
+            }
+            Lib::Hist1(k) => {
+                let f = match arg {
+                    Value::Float(f) => f,
+                    Value::Int(i) => i as f32,
+                    _ => return Err(EvalError::Argument(self.name()))
+                };
+                let value = res.history_at(*k, f);
+                Ok(Value::Tuple(vec![Value::Float(value.re), Value::Float(value.im)]))
+            }
+            Lib::FftPrev => {
+                let Value::Tuple(xs) = &arg else {
+                    return Err(EvalError::Argument(self.name()));
+                };
+                let [k, f] = xs.as_slice() else {
+                    return Err(EvalError::Argument(self.name()));
+                };
+                let value = res.history_at(k.try_into()?, f.try_into()?);
+                Ok(Value::Tuple(vec![Value::Float(value.re), Value::Float(value.im)]))
+            }
+            Lib::State(op, slot) => {
+                let Value::Tuple(xs) = &arg else {
+                    return Err(EvalError::Argument(self.name()));
+                };
+                if xs.len() != op.params() + 1 {
+                    return Err(EvalError::Argument(self.name()));
+                }
+                let mut params = [0.0; 2];
+                for (param, x) in params.iter_mut().zip(xs) {
+                    *param = x.try_into()?;
+                }
+                let x = (&xs[op.params()]).try_into()?;
+                Ok(Value::Float(res.state_step(*op, *slot, params, x)))
+            }
+            Lib::Mag
+            | Lib::InstFreq
+            | Lib::Env
+            | Lib::Fine
+            | Lib::HarmonicOf
+            | Lib::HarmonicDist
+            | Lib::Noise
+            | Lib::RandPhase => {
+                let f = match arg {
+                    Value::Float(f) => f,
+                    Value::Int(i) => i as f32,
+                    _ => return Err(EvalError::Argument(self.name()))
+                };
+                match self {
+                    Lib::Mag => Ok(Value::Float(mag_at(res.fft, f))),
+                    Lib::InstFreq => Ok(Value::Float(res.inst_freq(f))),
+                    Lib::Env => Ok(Value::Float(envelope_at(res.envelope, f))),
+                    Lib::Fine => Ok(Value::Float(fine_at(res.fft, res.envelope, f))),
+                    Lib::HarmonicOf => Ok(Value::Float(harmonic_of(res.features.pitch.f0, f))),
+                    Lib::HarmonicDist => Ok(Value::Float(harmonic_dist(res.features.pitch.f0, f))),
+                    Lib::Noise => Ok(Value::Float(noise_at(res.frame_seed, res.channel(), f))),
+                    _ => Ok(Value::Float(rand_phase_at(res.frame_seed, res.channel(), f))),
+                }
+            }
+            Lib::Rand => {
+                let Value::Tuple(xs) = &arg else {
+                    return Err(EvalError::Argument(self.name()));
+                };
+                let [seed, f] = xs.as_slice() else {
+                    return Err(EvalError::Argument(self.name()));
+                };
+                let value = rand_at(res.frame_seed, res.channel(), seed.try_into()?, f.try_into()?);
+                Ok(Value::Float(value))
+            }
+            Lib::Lfo(wave) => {
+                let period = match arg {
+                    Value::Float(f) => f,
+                    Value::Int(i) => i as f32,
+                    _ => return Err(EvalError::Argument(self.name()))
+                };
+                Ok(Value::Float(lfo(*wave, res.beat, period)))
+            }
+            Lib::StepSeq => {
+                let Value::Tuple(xs) = &arg else {
+                    return Err(EvalError::Argument(self.name()));
+                };
+                let [steps, step_beats] = xs.as_slice() else {
+                    return Err(EvalError::Argument(self.name()));
+                };
+                Ok(Value::Float(step_seq(res.beat, steps.try_into()?, step_beats.try_into()?)))
+            }
+            Lib::Harm | Lib::Perc => {
+                let f = match arg {
+                    Value::Float(f) => f,
+                    Value::Int(i) => i as f32,
+                    _ => return Err(EvalError::Argument(self.name()))
+                };
+                let value = match self {
+                    Lib::Harm => harm_at(res.fft, res.harmonic, f),
+                    _ => perc_at(res.fft, res.harmonic, f),
+                };
+                Ok(Value::Tuple(vec![Value::Float(value.re), Value::Float(value.im)]))
+            }
+            Lib::Synth => {
+                let Value::Tuple(xs) = &arg else {
+                    return Err(EvalError::Argument(self.name()));
+                };
+                let [mag, freq] = xs.as_slice() else {
+                    return Err(EvalError::Argument(self.name()));
+                };
+                let value = res.synth(mag.try_into()?, freq.try_into()?);
+                Ok(Value::Tuple(vec![Value::Float(value.re), Value::Float(value.im)]))
+            }
+            Lib::ParamWith(mode) => {
+                match arg {
+                    Value::Float(f) => Ok(Value::Float(interpolate(res.modulation, f, *mode))),
+                    Value::Int(i) => Ok(Value::Float(interpolate(res.modulation, i as f32, *mode))),
+                    _ => Err(EvalError::Argument(self.name()))
                 }
             }
             _ => self.clone().papply(arg)
@@ -182,14 +667,19 @@ impl Lib {
     }
 
     /// Apply in partial evaluation
-    pub fn papply(self, arg: Value) -> Value {
+    pub fn papply(self, arg: Value) -> Result<Value, EvalError> {
+        // Components of a tuple can be taken even if others are symbols
+        if let (Lib::Nth(k, _), Value::Tuple(xs)) = (&self, &arg) {
+            return xs.get(*k).cloned().ok_or(EvalError::Argument(self.name()));
+        }
+
         // Refuse to apply lib function to symbol (during partial eval stage)
         if arg.is_symbol() || self.is_symbol() {
-            return Value::Apply(Value::Lib(self).into(), vec![arg]);
+            return Ok(Value::Apply(Value::Lib(self).into(), vec![arg]));
         }
 
-        // Otherwise the application is typechecked, thus it should be valid
-        match arg {
+        // Otherwise the application is typechecked, but values can still be malformed
+        let value = match arg {
             Value::Float(f) => {
                 match self {
                     Lib::Add => Value::Lib(Lib::Add1(f)),
@@ -222,7 +712,7 @@ impl Lib {
                     Lib::GtI(x) => Value::Bool(x as f32 > f),
                     Lib::GeI(x) => Value::Bool(x as f32 >= f),
                     Lib::Tan => Value::Float(f.tan()),
-                    _ => panic!("lib function {} does not accept float", self)
+                    _ => return Err(EvalError::Argument(self.name()))
                 }
             }
             Value::Int(i) => {
@@ -247,51 +737,50 @@ impl Lib {
                     Lib::Le1(x) => Value::Bool(x <= i as f32),
                     Lib::Gt1(x) => Value::Bool(x > i as f32),
                     Lib::Ge1(x) => Value::Bool(x >= i as f32),
-                    Lib::AddI(x) => Value::Int(x + i),
-                    Lib::SubI(x) => Value::Int(x - i),
-                    Lib::MulI(x) => Value::Int(x * i),
+                    Lib::AddI(x) => Value::Int(x.checked_add(i).ok_or(EvalError::Overflow)?),
+                    Lib::SubI(x) => Value::Int(x.checked_sub(i).ok_or(EvalError::Overflow)?),
+                    Lib::MulI(x) => Value::Int(x.checked_mul(i).ok_or(EvalError::Overflow)?),
                     Lib::DivI(x) => Value::Float(if i == 0 { 0.0 } else { x as f32 / i as f32 }),
-                    Lib::ModI(x) => Value::Int(if i == 0 { 0 } else { x % i }),
+                    Lib::ModI(x) => Value::Int(if i == 0 { 0 } else { x.checked_rem(i).ok_or(EvalError::Overflow)? }),
                     Lib::LtI(x) => Value::Bool(x < i),
                     Lib::LeI(x) => Value::Bool(x <= i),
                     Lib::GtI(x) => Value::Bool(x > i),
                     Lib::GeI(x) => Value::Bool(x >= i),
-                    _ => panic!("lib function {} does not accept int", self)
+                    Lib::Tan => Value::Float(tan(i as f32)),
+                    _ => return Err(EvalError::Argument(self.name()))
                 }
             }
-            Value::Tuple(xs) => {
+            Value::Tuple(xs) if xs.len() == 2 => {
+                let x: f32 = (&xs[0]).try_into()?;
+                let y: f32 = (&xs[1]).try_into()?;
                 match self {
-                    Lib::Re => Value::Float((&xs[0]).into()),
-                    Lib::Im => Value::Float((&xs[1]).into()),
-                    Lib::Norm => {
-                        let re: f32 = (&xs[0]).into();
-                        let im: f32 = (&xs[1]).into();
-                        Value::Float((re * re + im * im).sqrt())
-                    }
-                    Lib::Angle => {
-                        let re: f32 = (&xs[0]).into();
-                        let im: f32 = (&xs[1]).into();
-                        Value::Float(im.atan2(re))
-                    }
-                    Lib::Polar => {
-                        let norm: f32 = (&xs[0]).into();
-                        let angle: f32 = (&xs[1]).into();
-                        Value::Tuple(vec![Value::Float(norm * angle.cos()), Value::Float(norm * angle.sin())])
-                    }
-                    _ => panic!("lib function {} does not accept tuple", self)
+                    Lib::Re => Value::Float(x),
+                    Lib::Im => Value::Float(y),
+                    Lib::Norm => Value::Float((x * x + y * y).sqrt()),
+                    Lib::Angle => Value::Float(y.atan2(x)),
+                    Lib::Polar => Value::Tuple(vec![Value::Float(x * y.cos()), Value::Float(x * y.sin())]),
+                    _ => return Err(EvalError::Argument(self.name()))
                 }
             }
-            other => panic!("lib function {} does not accept {}", self, other)
-        }
+            _ => return Err(EvalError::Argument(self.name()))
+        };
+        Ok(value)
     }
 }
 
 impl From<Lib> for ValueType {
     fn from(lib: Lib) -> Self {
         match lib {
-            Lib::Fft => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float]))),
-            Lib::Param => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Float)),
+            Lib::Fft | Lib::FftWith(_) | Lib::FftOf(_) | Lib::Harm | Lib::Perc => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float]))),
+            Lib::Param | Lib::ParamWith(_) => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Float)),
             Lib::Beat | Lib::Sec => ValueType::Float,
+            Lib::Srate | Lib::Wsize | Lib::Hop | Lib::Nyquist => ValueType::Float,
+            Lib::Channel | Lib::Feature(_) | Lib::Timing(_) => ValueType::Float,
+            Lib::IsMid | Lib::IsSide | Lib::Onset | Lib::Playing | Lib::Loop => ValueType::Bool,
+            Lib::Hz | Lib::Bin => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Float)),
+            Lib::Hist => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float]))))),
+            Lib::Hist1(_) => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float]))),
+            Lib::FftPrev => ValueType::Func(Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float])), Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float]))),
             Lib::Add | Lib::Sub | Lib::Mul | Lib::Div | Lib::Mod => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Float)))),
             Lib::Lt | Lib::Le | Lib::Gt | Lib::Ge => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Bool)))),
             Lib::Add1(_) | Lib::Sub1(_) | Lib::Mul1(_) | Lib::Div1(_) | Lib::Mod1(_) => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Float)),
@@ -301,6 +790,13 @@ impl From<Lib> for ValueType {
             Lib::Sin | Lib::Cos | Lib::Tan => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Float)),
             Lib::Re | Lib::Im | Lib::Norm | Lib::Angle => ValueType::Func(Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float])), Box::new(ValueType::Float)),
             Lib::Polar => ValueType::Func(Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float])), Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float]))),
+            Lib::State(op, _) => ValueType::Func(Box::new(ValueType::Tuple(vec![ValueType::Float; op.params() + 1])), Box::new(ValueType::Float)),
+            Lib::Mag | Lib::InstFreq | Lib::Env | Lib::Fine | Lib::HarmonicOf | Lib::HarmonicDist => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Float)),
+            Lib::Noise | Lib::RandPhase => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Float)),
+            Lib::Rand | Lib::StepSeq => ValueType::Func(Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float])), Box::new(ValueType::Float)),
+            Lib::Lfo(_) => ValueType::Func(Box::new(ValueType::Float), Box::new(ValueType::Float)),
+            Lib::Synth => ValueType::Func(Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float])), Box::new(ValueType::Tuple(vec![ValueType::Float, ValueType::Float]))),
+            Lib::Nth(k, types) => ValueType::Func(Box::new(ValueType::Tuple(types.clone())), Box::new(types.get(k).cloned().unwrap_or(ValueType::Tuple(vec![])))),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,160 @@
+use super::*;
+
+/// The per-bin state of a program written as `{ init: S, step: (S, Float) -> (S, out) }`. The
+/// state of each bin is kept in the first slots of `State`, one value per float or boolean.
+#[derive(Clone, Debug)]
+pub struct Machine {
+    /// Type of the state of each bin
+    pub state_type: ValueType,
+    /// The state every bin starts from, a closed term
+    pub init: Term,
+}
+
+impl Machine {
+    /// Split a simplified program with state into the machine and its step function
+    pub fn new(term: &Term) -> Option<(Self, Term)> {
+        let (state_type, _) = Machine::of_type(&type_of(term, &mut Vec::new()).ok()?)?;
+        let Term::Tuple(terms) = term else {
+            return None;
+        };
+        let [init, step] = terms.as_slice() else {
+            return None;
+        };
+        Some((Self { state_type, init: init.clone() }, step.clone()))
+    }
+
+    /// The state type and output of a program with state, of type `(S, (S, Float) -> (S, out))`
+    pub fn of_type(value_type: &ValueType) -> Option<(ValueType, Output)> {
+        let ValueType::Tuple(types) = value_type else {
+            return None;
+        };
+        let [state_type, ValueType::Func(param_type, ret_type)] = types.as_slice() else {
+            return None;
+        };
+        width(state_type)?;
+        if **param_type != ValueType::Tuple(vec![state_type.clone(), ValueType::Float]) {
+            return None;
+        }
+        let ValueType::Tuple(ret_types) = &**ret_type else {
+            return None;
+        };
+        let [next_type, bin_type] = ret_types.as_slice() else {
+            return None;
+        };
+        if next_type != state_type {
+            return None;
+        }
+        let output = Output::ALL.into_iter().find(|output| output.bin_type() == *bin_type)?;
+        Some((state_type.clone(), output))
+    }
+
+    /// Type of the argument of the step function
+    pub fn param_type(&self) -> ValueType {
+        ValueType::Tuple(vec![self.state_type.clone(), ValueType::Float])
+    }
+
+    /// Number of slots the state of each bin takes
+    pub fn width(&self) -> usize {
+        width(&self.state_type).unwrap_or(0)
+    }
+
+    /// Read the state of a bin, `None` if it isn't kept
+    pub fn load(&self, state: &State, bin: usize) -> Option<Value> {
+        load(&self.state_type, state, bin, &mut 0)
+    }
+
+    /// Keep the state of a bin, non-finite values are kept as zero
+    pub fn store(&self, state: &State, bin: usize, value: &Value) -> Result<(), EvalError> {
+        store(value, state, bin, &mut 0)
+    }
+}
+
+/// Number of slots a value of a first-order type takes, `None` for functions
+pub fn width(value_type: &ValueType) -> Option<usize> {
+    match value_type {
+        ValueType::Float | ValueType::Bool => Some(1),
+        ValueType::Tuple(types) => types.iter().map(width).sum(),
+        ValueType::Func(_, _) => None,
+    }
+}
+
+fn load(value_type: &ValueType, state: &State, bin: usize, slot: &mut usize) -> Option<Value> {
+    let mut next = || {
+        *slot += 1;
+        state.get(*slot - 1, bin)
+    };
+    match value_type {
+        ValueType::Float => Some(Value::Float(next()?)),
+        ValueType::Bool => Some(Value::Bool(next()? != 0.0)),
+        ValueType::Tuple(types) => Some(Value::Tuple(
+            types
+                .iter()
+                .map(|t| load(t, state, bin, slot))
+                .collect::<Option<_>>()?,
+        )),
+        ValueType::Func(_, _) => None,
+    }
+}
+
+fn store(value: &Value, state: &State, bin: usize, slot: &mut usize) -> Result<(), EvalError> {
+    let x = match value {
+        Value::Float(x) => *x,
+        Value::Int(x) => *x as f32,
+        Value::Bool(x) => *x as i32 as f32,
+        Value::Tuple(xs) => {
+            for x in xs {
+                store(x, state, bin, slot)?;
+            }
+            return Ok(());
+        }
+        _ => return Err(EvalError::NotFloat),
+    };
+    state.set(*slot, bin, if x.is_finite() { x } else { 0.0 });
+    *slot += 1;
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_machine {
+    use super::*;
+
+    #[test]
+    fn test_machine_state() {
+        let program = run("{ init: (1, (true, 2)), step: (s: (Float, (Bool, Float)), i: Float) => (s, fft(i)) }").unwrap();
+        let machine = program.machine.expect("program has state");
+        assert_eq!(machine.width(), 3);
+
+        // Values round trip through the slots, and bins out of range aren't kept
+        let state = State::new(4);
+        let value = Value::Tuple(vec![
+            Value::Float(f32::NAN),
+            Value::Tuple(vec![Value::Bool(true), Value::Int(3)]),
+        ]);
+        machine.store(&state, 2, &value).unwrap();
+        let expected = Value::Tuple(vec![
+            Value::Float(0.0),
+            Value::Tuple(vec![Value::Bool(true), Value::Float(3.0)]),
+        ]);
+        assert!(machine.load(&state, 2) == Some(expected));
+        assert!(machine.load(&state, 4).is_none());
+    }
+
+    #[test]
+    fn test_machine_type() {
+        for code in [
+            "{ init: 0, step: (s: Float, i: Float) => (s, (i, 0)) }",
+            "(0, (s: Float, i: Float) => (s, ((i, 0), (0, i))))",
+        ] {
+            assert!(run(code).unwrap().machine.is_some(), "{}", code);
+        }
+        for code in [
+            "{ init: 0, step: (s: Float, i: Float) => ((s, s), (i, 0)) }",
+            "{ init: 0, step: (s: Bool, i: Float) => (s, (i, 0)) }",
+            "{ init: sin, step: (s: Float -> Float, i: Float) => (s, (i, 0)) }",
+            "{ start: 0, step: (s: Float, i: Float) => (s, (i, 0)) }",
+        ] {
+            assert!(run(code).is_err(), "{}", code);
+        }
+    }
+}
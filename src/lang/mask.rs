@@ -0,0 +1,250 @@
+use realfft::num_complex::Complex32;
+
+use super::*;
+
+/// A program that only multiplies each bin by a gain, which doesn't change between frames
+/// unless modulation params do
+#[derive(Clone, Debug)]
+pub struct Mask {
+    /// The complex gain, as a `Float -> (Float, Float)` program
+    pub gain: Program,
+    /// Modulation params the gain depends on, `None` if it looks them up at variable indices
+    pub params: Option<Vec<usize>>,
+}
+
+impl Mask {
+    /// Detect a simplified `Float -> (Float, Float)` term of the form `fft(i) * gain(i)`
+    pub fn new(term: &Term) -> Option<Self> {
+        let Term::Func(param_type, name, body) = term else {
+            return None;
+        };
+        let gain = Term::Func(param_type.clone(), name.clone(), gain(body)?.into());
+        let mut params = Some(Vec::new());
+        referenced_params(&gain, &mut params);
+        if let Some(params) = &mut params {
+            params.sort();
+            params.dedup();
+        }
+        Some(Self {
+            gain: Program::unmasked(gain),
+            params,
+        })
+    }
+
+    /// Evaluate the gain at every bin into `out`, reusing `env`
+    pub fn compute(
+        &self,
+        env: &mut Env,
+        res: &Resource,
+        out: &mut [Complex32],
+    ) -> Result<(), EvalError> {
+        self.gain.collect_into(env, res, out)
+    }
+
+    /// Check if the gain may differ between two sets of modulation values
+    pub fn is_affected(&self, old: &[f32], new: &[f32]) -> bool {
+        match &self.params {
+            Some(params) => params.iter().any(|&i| old.get(i) != new.get(i)),
+            None => old != new,
+        }
+    }
+}
+
+/// Complex gain of a program body under the bin binder, if it is `fft(i)` times a gain
+fn gain(body: &Term) -> Option<Term> {
+    match body {
+        // Identity
+        Term::Apply(func, arg) if is_fft_bin(func, arg) => {
+            Some(Term::Tuple(vec![Term::Float(1.0), Term::Float(0.0)]))
+        }
+
+        // Silence
+        Term::Tuple(terms) if terms.iter().all(|t| *t == Term::Float(0.0)) => {
+            Some(Term::Tuple(vec![Term::Float(0.0), Term::Float(0.0)]))
+        }
+
+        // Scaled real and imaginary parts, `(fft(i).re * g, fft(i).im * g)`
+        Term::Tuple(terms) if terms.len() == 2 => {
+            let re = factor(&terms[0], Lib::Re)?;
+            let im = factor(&terms[1], Lib::Im)?;
+            (re == im).then(|| Term::Tuple(vec![re, Term::Float(0.0)]))
+        }
+
+        // Scaled and rotated, `(fft(i).norm * g, fft(i).angle + phi).polar`
+        Term::Apply(func, arg) if **func == Term::Lib(Lib::Polar) => match &**arg {
+            Term::Tuple(terms) if terms.len() == 2 => {
+                let norm = factor(&terms[0], Lib::Norm)?;
+                let phase = offset(&terms[1], Lib::Angle)?;
+                Some(Term::Apply(
+                    Term::Lib(Lib::Polar).into(),
+                    Term::Tuple(vec![norm, phase]).into(),
+                ))
+            }
+            _ => None,
+        },
+
+        // Switching between masks
+        Term::Alt(cond, then, else_) if is_static(cond) => Some(Term::Alt(
+            cond.clone(),
+            gain(then)?.into(),
+            gain(else_)?.into(),
+        )),
+
+        _ => None,
+    }
+}
+
+/// Factor `g` out of `proj(fft(i)) * g`, `g * proj(fft(i))` or `proj(fft(i))`
+fn factor(term: &Term, proj: Lib) -> Option<Term> {
+    if is_fft_proj(term, &proj) {
+        return Some(Term::Float(1.0));
+    }
+    let Term::Apply(func, y) = term else {
+        return None;
+    };
+    match &**func {
+        Term::Lib(Lib::Mul1(c)) if is_fft_proj(y, &proj) => Some(Term::Float(*c)),
+        Term::Apply(op, x) if **op == Term::Lib(Lib::Mul) => {
+            if is_fft_proj(x, &proj) && is_static(y) {
+                Some((**y).clone())
+            } else if is_fft_proj(y, &proj) && is_static(x) {
+                Some((**x).clone())
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Take `phi` out of `proj(fft(i)) + phi`, `phi + proj(fft(i))` or `proj(fft(i))`
+fn offset(term: &Term, proj: Lib) -> Option<Term> {
+    if is_fft_proj(term, &proj) {
+        return Some(Term::Float(0.0));
+    }
+    let Term::Apply(func, y) = term else {
+        return None;
+    };
+    match &**func {
+        Term::Lib(Lib::Add1(c)) if is_fft_proj(y, &proj) => Some(Term::Float(*c)),
+        Term::Apply(op, x) if **op == Term::Lib(Lib::Add) => {
+            if is_fft_proj(x, &proj) && is_static(y) {
+                Some((**y).clone())
+            } else if is_fft_proj(y, &proj) && is_static(x) {
+                Some((**x).clone())
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn is_fft_bin(func: &Term, arg: &Term) -> bool {
+    *func == Term::Lib(Lib::Fft) && *arg == Term::Var(0)
+}
+
+fn is_fft_proj(term: &Term, proj: &Lib) -> bool {
+    match term {
+        Term::Apply(func, arg) => {
+            matches!(&**func, Term::Lib(l) if l == proj)
+                && matches!(&**arg, Term::Apply(f, a) if is_fft_bin(f, a))
+        }
+        _ => false,
+    }
+}
+
+/// Check if a term stays the same between frames and channels, given the same modulation and
+/// window size
+fn is_static(term: &Term) -> bool {
+    match term {
+        Term::Lib(lib) => lib.is_static(),
+        Term::Tuple(terms) => terms.iter().all(is_static),
+        Term::Apply(func, arg) => is_static(func) && is_static(arg),
+        Term::Func(_, _, body) => is_static(body),
+        Term::Let(_, _, value, next) => is_static(value) && is_static(next),
+        Term::Alt(cond, then, else_) => is_static(cond) && is_static(then) && is_static(else_),
+        Term::Float(_) | Term::Bool(_) | Term::Var(_) => true,
+    }
+}
+
+/// Collect constant `param` indices, giving up on variable ones
+fn referenced_params(term: &Term, params: &mut Option<Vec<usize>>) {
+    match term {
+        Term::Apply(func, arg) => match (&**func, &**arg) {
+            (Term::Lib(Lib::Param), Term::Float(f)) => {
+                if let Some(params) = params {
+                    params.push(f.floor().max(0.0) as usize);
+                    params.push(f.ceil().max(0.0) as usize);
+                }
+            }
+            (Term::Lib(Lib::Param | Lib::ParamWith(_)), _) => *params = None,
+            (func, arg) => {
+                referenced_params(func, params);
+                referenced_params(arg, params);
+            }
+        },
+        Term::Lib(Lib::Param | Lib::ParamWith(_)) => *params = None,
+        Term::Tuple(terms) => terms.iter().for_each(|t| referenced_params(t, params)),
+        Term::Func(_, _, body) => referenced_params(body, params),
+        Term::Let(_, _, value, next) => {
+            referenced_params(value, params);
+            referenced_params(next, params);
+        }
+        Term::Alt(cond, then, else_) => {
+            referenced_params(cond, params);
+            referenced_params(then, params);
+            referenced_params(else_, params);
+        }
+        _ => (),
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_mask {
+    use super::*;
+
+    #[test]
+    fn test_mask_lp() {
+        let code = "let lp: Float -> Float -> Float = (l: Float) => (i: Float) => if i < l then 1 else 0 in (i: Float) => (fft(i).norm * lp(800)(i), fft(i).angle).polar";
+        let program = run(code).unwrap();
+        let mask = program.mask.expect("low pass is a mask");
+        assert_eq!(mask.params, Some(vec![]));
+    }
+
+    #[test]
+    fn test_mask_params() {
+        let program = run("(i: Float) => (fft(i).re * param(2), fft(i).im * param(2))").unwrap();
+        let mask = program.mask.expect("gain is a mask");
+        assert_eq!(mask.params, Some(vec![2]));
+        assert!(mask.is_affected(&[0.0, 0.0, 0.5], &[0.0, 0.0, 0.6]));
+        assert!(!mask.is_affected(&[0.0, 0.0, 0.5], &[0.3, 0.0, 0.5]));
+    }
+
+    #[test]
+    fn test_mask_alt() {
+        let program = run("(i: Float) => if param(i) > 0 then fft(i) else (0, 0)").unwrap();
+        let mask = program.mask.expect("gate is a mask");
+        assert_eq!(mask.params, None);
+    }
+
+    #[test]
+    fn test_mask_silence() {
+        let program = run("(i: Float) => (0, 0)").unwrap();
+        let mask = program.mask.expect("silence is a mask");
+        assert!(mask.gain.mask.is_none());
+    }
+
+    #[test]
+    fn test_not_mask() {
+        for code in [
+            "(i: Float) => fft(i * 2)",
+            "(i: Float) => (fft(i).norm * sin(beat), fft(i).angle).polar",
+            "(i: Float) => (fft(i).re * 2, fft(i).im * 3)",
+            "(i: Float) => if fft(i).norm > 1 then fft(i) else (0, 0)",
+        ] {
+            assert!(run(code).unwrap().mask.is_none(), "{} is not a mask", code);
+        }
+    }
+}
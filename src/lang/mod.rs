@@ -1,44 +1,90 @@
+pub mod clock;
+pub mod cse;
 pub mod elaborate;
 pub mod eval;
+pub mod features;
+pub mod history;
+pub mod hoist;
+pub mod interp;
 pub mod quote;
 pub mod library;
+pub mod machine;
+pub mod mask;
+pub mod noise;
+pub mod onset;
 pub mod parse;
+pub mod pitch;
+pub mod pragma;
+pub mod program;
+pub mod rewrite;
 pub mod syntax;
 pub mod term;
 pub mod value;
 pub mod value_type;
 pub mod resource;
+pub mod specialise;
+pub mod state;
+pub mod vocoder;
+#[cfg(feature = "jit")]
+pub mod jit;
 
 use std::collections::HashMap;
 
+pub use clock::*;
+use cse::*;
 use elaborate::*;
 use eval::*;
+pub use eval::EvalError;
+pub use features::*;
+pub use history::*;
+use hoist::*;
+pub use interp::*;
 pub use library::*;
+pub use machine::*;
+pub use mask::*;
+pub use noise::*;
+pub use onset::*;
 use parse::*;
+pub use pragma::*;
+pub use pitch::*;
+pub use program::*;
 pub use quote::*;
+pub use rewrite::*;
 pub use syntax::*;
 pub use term::*;
 pub use value::*;
 pub use value_type::*;
 pub use resource::*;
+pub use specialise::*;
+pub use state::*;
+pub use vocoder::*;
 
 pub type RunError = String;
 
-fn target_type() -> ValueType {
-    ValueType::Func(
-        Box::new(ValueType::Float),
-        Box::new(ValueType::Tuple(vec![
-            ValueType::Float,
-            ValueType::Float,
-        ])),
-    )
+/// Parse and elaborate code, then normalise it by evaluation
+pub fn normalise(code: &str) -> Result<Term, RunError> {
+    let ctx = HashMap::new();
+    let (pragmas, code) = pragmas(code).map_err(|e| format!("Pragma error: {}", e))?;
+    let syntax = parse(&code).map_err(|e| format!("Parse error: {}", e))?;
+    let (term, value_type) = infer(syntax, ctx, 0).map_err(|e| format!("Elaborate error: {}", e))?;
+    let machine = Machine::of_type(&value_type);
+    if !Output::ALL.iter().any(|output| output.value_type() == value_type) && machine.is_none() {
+        return Err(format!(
+            "Elaborate error: Type mismatch: {} != {} or {}, or a program with state",
+            value_type,
+            Output::Mono.value_type(),
+            Output::Stereo.value_type(),
+        ));
+    }
+    if machine.is_some() && pragmas.domain != Domain::Bin {
+        return Err("Pragma error: #domain doesn't apply to programs with state".into());
+    }
+    let term = simp(pragmas.domain.wrap(term)).map_err(|e| format!("Evaluation error: {}", e))?;
+    let reserved = machine.map_or(0, |(state_type, _)| width(&state_type).unwrap_or(0));
+    allocate_slots(term, reserved).map_err(|e| format!("State error: {}", e))
 }
 
-pub fn run(code: &str) -> Result<Value, RunError> {
-    let mut env = Vec::new();
-    let ctx = HashMap::new();
-    let syntax = parse(code).map_err(|e| format!("Parse error: {}", e))?;
-    let term = check(syntax, ctx, target_type(), 0).map_err(|e| format!("Elaborate error: {}", e))?;
-    let simp_term = simp(term);
-    Ok(peval(simp_term, &mut env))
+pub fn run(code: &str) -> Result<Program, RunError> {
+    let simp_term = normalise(code)?;
+    Ok(Program::new(rewrite(simp_term)))
 }
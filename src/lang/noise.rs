@@ -0,0 +1,100 @@
+use std::f32::consts::PI;
+
+/// Stream of `noise`, above the bits of any seed given to `rand`
+const NOISE: u64 = 1 << 32;
+
+/// Stream of `rand_phase`
+const PHASE: u64 = 2 << 32;
+
+/// Scramble the bits of a key, with the finaliser of SplitMix64
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Key of the random values of a frame, from the `Seed` param and a count of frames
+pub fn frame_seed(seed: u32, frame: u64) -> u64 {
+    mix(mix(seed as u64) ^ frame)
+}
+
+/// Uniform value from 0 to 1 for argument `f` of a stream, on a channel of a frame. Values are
+/// hashed from their key rather than drawn in turn, so they're the same however many are taken
+/// and in whatever order.
+pub fn random(frame_seed: u64, channel: usize, stream: u64, f: f32) -> f32 {
+    // Adding 0 makes -0 and 0 the same argument
+    let stream = mix(stream ^ ((channel as u64) << 48));
+    let key = mix(frame_seed ^ stream) ^ (f + 0.0).to_bits() as u64;
+    (mix(key) >> 40) as f32 / (1 << 24) as f32
+}
+
+/// Uniform value from -1 to 1
+pub fn noise_at(frame_seed: u64, channel: usize, f: f32) -> f32 {
+    random(frame_seed, channel, NOISE, f) * 2.0 - 1.0
+}
+
+/// Uniform phase from -pi to pi
+pub fn rand_phase_at(frame_seed: u64, channel: usize, f: f32) -> f32 {
+    (random(frame_seed, channel, PHASE, f) * 2.0 - 1.0) * PI
+}
+
+/// Uniform value from 0 to 1, from a stream of its own for every `seed`
+pub fn rand_at(frame_seed: u64, channel: usize, seed: f32, f: f32) -> f32 {
+    random(frame_seed, channel, (seed + 0.0).to_bits() as u64, f)
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_noise {
+    use super::*;
+
+    #[test]
+    fn test_random_reproducible() {
+        let key = frame_seed(7, 100);
+        assert_eq!(key, frame_seed(7, 100));
+        assert_eq!(noise_at(key, 1, 3.5), noise_at(key, 1, 3.5));
+        assert_eq!(rand_at(key, 0, 0.0, 3.0), rand_at(key, 0, -0.0, -0.0 + 3.0));
+
+        // Each part of the key gives another value
+        let value = rand_at(key, 0, 1.0, 3.0);
+        for other in [
+            rand_at(frame_seed(8, 100), 0, 1.0, 3.0),
+            rand_at(frame_seed(7, 101), 0, 1.0, 3.0),
+            rand_at(key, 1, 1.0, 3.0),
+            rand_at(key, 0, 2.0, 3.0),
+            rand_at(key, 0, 1.0, 4.0),
+            noise_at(key, 0, 3.0) * 0.5 + 0.5,
+        ] {
+            assert_ne!(value, other);
+        }
+    }
+
+    #[test]
+    fn test_random_uniform() {
+        // Every tenth of the range gets about a tenth of the values, across bins and across frames
+        let n = 100000;
+        let mut across_bins = [0; 10];
+        let mut across_frames = [0; 10];
+        for k in 0..n {
+            let value = rand_at(frame_seed(0, 5), 0, 0.0, k as f32);
+            assert!((0.0..1.0).contains(&value));
+            across_bins[(value * 10.0) as usize] += 1;
+            let value = rand_at(frame_seed(0, k as u64), 0, 0.0, 5.0);
+            across_frames[(value * 10.0) as usize] += 1;
+        }
+        for count in across_bins.into_iter().chain(across_frames) {
+            assert!((count as f32 - n as f32 / 10.0).abs() < n as f32 / 100.0, "{}", count);
+        }
+
+        // Noise and phases are spread over their whole range
+        let noise: Vec<f32> = (0..n).map(|k| noise_at(1, 0, k as f32)).collect();
+        let mean = noise.iter().sum::<f32>() / n as f32;
+        assert!(mean.abs() < 0.01 && noise.iter().all(|x| (-1.0..1.0).contains(x)));
+        let (low, high) = (0..n)
+            .map(|k| rand_phase_at(1, 0, k as f32))
+            .fold((0.0f32, 0.0f32), |(low, high), x| (low.min(x), high.max(x)));
+        assert!((-PI..-3.1).contains(&low) && (3.1..PI).contains(&high));
+    }
+}
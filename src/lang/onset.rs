@@ -0,0 +1,192 @@
+use realfft::num_complex::Complex32;
+
+/// Number of recent frames the threshold follows the median of
+const MEDIAN_FRAMES: usize = 16;
+
+/// How far the rise of a frame must be above its recent median to be an onset
+const THRESHOLD: f32 = 0.25;
+
+/// Onsets closer than this to the last one are ignored, as they're the same note still entering
+/// the window
+const MIN_GAP: f32 = 0.05;
+
+/// Whether a note starts on a frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Onset {
+    pub detected: bool,
+    /// How far the spectral flux rose above its recent median, as a fraction of the magnitudes of
+    /// the frame, from 0 to 1
+    pub strength: f32,
+    /// Seconds since the last onset, or since the detector was cleared
+    pub since: f32,
+}
+
+/// Detects onsets from the spectral flux of each frame, relative to the magnitudes of the frame
+/// so that it doesn't depend on the level. The threshold adapts to the median of recent frames,
+/// so steady or noisy material doesn't trigger it. Never allocates.
+#[derive(Clone, Debug, Default)]
+pub struct OnsetDetector {
+    /// Rises of recent frames, a ring buffer
+    rises: [f32; MEDIAN_FRAMES],
+    /// Index of the most recent rise
+    head: usize,
+    /// Number of frames detected since the last clear, at most `MEDIAN_FRAMES`
+    filled: usize,
+    /// Seconds since the last onset
+    since: f32,
+    /// Whether there was an onset since the last clear
+    triggered: bool,
+}
+
+impl OnsetDetector {
+    /// Forget all frames, like after the window size changes
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Detect an onset on `spectrum`, which comes `seconds` after a frame with magnitudes
+    /// `previous`. Bins missing from `previous` count as silent. A frame is an onset when its
+    /// rise clears the threshold and is larger than the rise of the frame before it, at least
+    /// `MIN_GAP` after the last onset, so that a note entering the window over several frames
+    /// only triggers once.
+    pub fn detect(&mut self, spectrum: &[Complex32], previous: &[f32], seconds: f32) -> Onset {
+        let (mut flux, mut total) = (0.0, 0.0);
+        for (k, bin) in spectrum.iter().enumerate() {
+            let magnitude = bin.norm();
+            total += magnitude;
+            flux += (magnitude - previous.get(k).copied().unwrap_or(0.0)).max(0.0);
+        }
+        let rise = if total > 0.0 && total.is_finite() { flux / total } else { 0.0 };
+
+        let mut recent = self.rises;
+        let recent = &mut recent[..self.filled];
+        let median = if recent.is_empty() {
+            0.0
+        } else {
+            let middle = recent.len() / 2;
+            *recent.select_nth_unstable_by(middle, f32::total_cmp).1
+        };
+        let last = if self.filled > 0 { self.rises[self.head] } else { 0.0 };
+        let strength = (rise - median).clamp(0.0, 1.0);
+        let settled = !self.triggered || self.since + seconds >= MIN_GAP;
+        let detected = strength > THRESHOLD && rise > last && settled;
+
+        self.head = (self.head + 1) % MEDIAN_FRAMES;
+        self.rises[self.head] = rise;
+        self.filled = (self.filled + 1).min(MEDIAN_FRAMES);
+        self.since = if detected { 0.0 } else { self.since + seconds };
+        self.triggered |= detected;
+        Onset {
+            detected,
+            strength,
+            since: self.since,
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_onset {
+    use std::f32::consts::TAU;
+
+    use realfft::RealFftPlanner;
+
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 44100.0;
+
+    /// Run the detector over Hann-windowed frames of `signal`, returning the sample each onset
+    /// frame ends at
+    fn onsets(signal: &[f32], window_size: usize, hop: usize) -> Vec<usize> {
+        let r2c = RealFftPlanner::<f32>::new().plan_fft_forward(window_size);
+        let mut detector = OnsetDetector::default();
+        let mut previous = vec![0.0; window_size / 2 + 1];
+        let mut spectrum = vec![Complex32::default(); window_size / 2 + 1];
+        let mut found = vec![];
+        for start in (0..signal.len() - window_size).step_by(hop) {
+            let mut frame: Vec<f32> = signal[start..start + window_size]
+                .iter()
+                .enumerate()
+                .map(|(n, x)| x * (0.5 - 0.5 * (TAU * n as f32 / window_size as f32).cos()))
+                .collect();
+            r2c.process(&mut frame, &mut spectrum).unwrap();
+            let onset = detector.detect(&spectrum, &previous, hop as f32 / SAMPLE_RATE);
+            if onset.detected {
+                assert_eq!(onset.since, 0.0);
+                found.push(start + window_size);
+            }
+            for (previous, bin) in previous.iter_mut().zip(&spectrum) {
+                *previous = bin.norm();
+            }
+        }
+        found
+    }
+
+    /// Check that each event is found once, within two hops of entering the window or on the
+    /// first frame
+    fn assert_found(found: &[usize], events: &[usize], window_size: usize, hop: usize) {
+        assert_eq!(found.len(), events.len(), "{:?} for {:?}", found, events);
+        for (found, event) in found.iter().zip(events) {
+            let latest = (event + 2 * hop).max(window_size);
+            assert!(*found > *event && *found <= latest, "{} for {}", found, event);
+        }
+    }
+
+    #[test]
+    fn test_onset_of_clicks() {
+        for (window_size, hop) in [(1024, 256), (2048, 512), (2048, 128)] {
+            let events = [10000, 30000, 50000];
+            let mut signal = vec![0.0; 60000];
+            for (event, gain) in events.iter().zip([1.0, 0.1, 0.5]) {
+                signal[*event] = gain;
+            }
+            assert_found(&onsets(&signal, window_size, hop), &events, window_size, hop);
+        }
+    }
+
+    #[test]
+    fn test_onset_of_tone_bursts() {
+        // Bursts at different pitches over a quieter steady tone, which triggers only when it
+        // starts. Each burst fades out over 20 ms, as a sudden stop is a click of its own.
+        let events = [0, 11025, 22050, 33075];
+        let signal: Vec<f32> = (0..44100)
+            .map(|n| {
+                let t = n as f32 / SAMPLE_RATE;
+                let burst = events
+                    .iter()
+                    .zip([440.0, 660.0, 880.0, 330.0])
+                    .filter(|(event, _)| n >= **event && n < **event + 4410)
+                    .map(|(event, f)| {
+                        let release = ((event + 4410 - n) as f32 / 882.0).min(1.0);
+                        release * (TAU * f * t).sin()
+                    })
+                    .sum::<f32>();
+                burst + 0.2 * (TAU * 220.0 * t).sin()
+            })
+            .collect();
+        for (window_size, hop) in [(1024, 256), (2048, 512)] {
+            assert_found(&onsets(&signal, window_size, hop), &events, window_size, hop);
+        }
+
+        // A steady tone and silence have no onsets after the start
+        let tone: Vec<f32> = (0..44100).map(|n| (TAU * 440.0 * n as f32 / SAMPLE_RATE).sin()).collect();
+        assert_eq!(onsets(&tone, 1024, 256).len(), 1);
+        assert!(onsets(&vec![0.0; 44100], 1024, 256).is_empty());
+    }
+
+    #[test]
+    fn test_since_onset() {
+        let mut detector = OnsetDetector::default();
+        let silence = vec![Complex32::default(); 8];
+        let click = vec![Complex32::new(1.0, 0.0); 8];
+        for _ in 0..3 {
+            assert!(!detector.detect(&silence, &[], 0.5).detected);
+        }
+        let onset = detector.detect(&click, &[], 0.5);
+        assert!(onset.detected && onset.strength == 1.0 && onset.since == 0.0);
+        let onset = detector.detect(&click, &[1.0; 8], 0.5);
+        assert!(!onset.detected && onset.strength == 0.0 && onset.since == 0.5);
+        detector.clear();
+        assert_eq!(detector.detect(&silence, &[], 0.25).since, 0.25);
+    }
+}
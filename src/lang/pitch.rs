@@ -0,0 +1,218 @@
+use realfft::num_complex::Complex32;
+
+use super::*;
+
+/// Number of harmonics multiplied together for each candidate fundamental
+const HARMONICS: usize = 8;
+
+/// How much less each harmonic counts than the one below it, so that a fundamental outweighs
+/// its own octaves below
+const HARMONIC_DECAY: f32 = 0.84;
+
+/// Magnitudes this far below the loudest bin all count as missing harmonics (-60 dB)
+const FLOOR: f32 = 1e-2;
+
+/// Candidate fundamentals are this many per bin
+const STEPS_PER_BIN: usize = 8;
+
+/// Lowest candidate fundamental in bins, harmonics closer than the main lobe of a Hann window
+/// blur together
+const MIN_F0: usize = 2;
+
+/// Bins on each side of a harmonic that count towards it
+const HARMONIC_WIDTH: f32 = 1.0;
+
+/// Fundamental frequency of a frame, found by a weighted harmonic product spectrum
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pitch {
+    /// In bins, 0 without a fundamental
+    pub f0: f32,
+    /// How much of the energy of the frame lies on harmonics of `f0` beyond what noise would
+    /// put there, from 0 to 1
+    pub confidence: f32,
+}
+
+impl Pitch {
+    /// Estimate the fundamental of `spectrum`. The best candidate multiplies the magnitudes of
+    /// its harmonics, each raised to a power decaying with its number, and is then refined by
+    /// the peaks of the harmonics themselves.
+    pub fn estimate(spectrum: &[Complex32]) -> Self {
+        let len = spectrum.len();
+        let peak = spectrum.iter().map(|bin| bin.norm()).fold(0.0, f32::max);
+        if len <= HARMONICS * MIN_F0 || !(peak > 0.0 && peak.is_finite()) {
+            return Self::default();
+        }
+
+        // Logarithms above the floor keep the product from underflowing, and a single missing
+        // harmonic from ruling a candidate out
+        let floor = peak * FLOOR;
+        let mut best = (0.0, 0.0);
+        let candidates = (len - 1) / HARMONICS * STEPS_PER_BIN;
+        for step in MIN_F0 * STEPS_PER_BIN..candidates {
+            let f0 = step as f32 / STEPS_PER_BIN as f32;
+            let (product, _) = (1..=HARMONICS).fold((0.0, 1.0), |(product, weight), r| {
+                let level = (mag_at(spectrum, f0 * r as f32) / floor).max(1.0).ln();
+                (product + level * weight, weight * HARMONIC_DECAY)
+            });
+            if product > best.0 {
+                best = (product, f0);
+            }
+        }
+        let (product, coarse) = best;
+        if product <= 0.0 {
+            return Self::default();
+        }
+
+        // Each harmonic measures the fundamental, the higher ones more finely
+        let (mut sum, mut weights) = (0.0, 0.0);
+        for r in 1..=HARMONICS {
+            if let Some((peak, magnitude)) = peak_near(spectrum, coarse * r as f32) {
+                let weight = magnitude * r as f32;
+                sum += peak / r as f32 * weight;
+                weights += weight;
+            }
+        }
+        let f0 = if weights > 0.0 { sum / weights } else { coarse };
+
+        // Noise puts energy on harmonics in proportion to the bins they cover
+        let (mut harmonic, mut total) = (0.0, 0.0);
+        for (k, bin) in spectrum.iter().enumerate() {
+            let r = (k as f32 / f0).round();
+            let power = bin.norm_sqr();
+            total += power;
+            if r >= 1.0 && (k as f32 - r * f0).abs() <= HARMONIC_WIDTH {
+                harmonic += power;
+            }
+        }
+        let covered = (2.0 * HARMONIC_WIDTH / f0).min(1.0);
+        let confidence = if total > 0.0 && covered < 1.0 {
+            ((harmonic / total - covered) / (1.0 - covered)).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        Self { f0, confidence }
+    }
+}
+
+/// The loudest bin within a bin of `f`, refined by a parabola through the log magnitudes
+/// around it, with its magnitude
+fn peak_near(spectrum: &[Complex32], f: f32) -> Option<(f32, f32)> {
+    let start = (f - HARMONIC_WIDTH).round().max(1.0) as usize;
+    let end = ((f + HARMONIC_WIDTH).round() as usize).min(spectrum.len().checked_sub(2)?);
+    let k = (start..=end).max_by(|a, b| spectrum[*a].norm().total_cmp(&spectrum[*b].norm()))?;
+    let [left, centre, right] = [k - 1, k, k + 1].map(|k| spectrum[k].norm().max(f32::MIN_POSITIVE).ln());
+    let curvature = left - 2.0 * centre + right;
+    let offset = if curvature < 0.0 {
+        (0.5 * (left - right) / curvature).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    Some((k as f32 + offset, spectrum[k].norm()))
+}
+
+/// Number of the harmonic of a fundamental of `f0` bins nearest to band `f`, 0 without a
+/// fundamental
+pub fn harmonic_of(f0: f32, f: f32) -> f32 {
+    if f0 > 0.0 {
+        (f / f0).round()
+    } else {
+        0.0
+    }
+}
+
+/// Distance from band `f` to the nearest harmonic of a fundamental of `f0` bins, in bins,
+/// negative below it
+pub fn harmonic_dist(f0: f32, f: f32) -> f32 {
+    f - harmonic_of(f0, f) * f0
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_pitch {
+    use std::f32::consts::TAU;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use realfft::RealFftPlanner;
+
+    use super::*;
+
+    /// Spectrum of a Hann-windowed frame of harmonics of `f0` Hz, the `r`th with an amplitude of
+    /// `amplitude(r)`, at 44.1 kHz
+    fn harmonics(f0: f32, window_size: usize, amplitude: impl Fn(usize) -> f32) -> Vec<Complex32> {
+        let mut frame: Vec<f32> = (0..window_size)
+            .map(|n| {
+                let window = 0.5 - 0.5 * (TAU * n as f32 / window_size as f32).cos();
+                let t = n as f32 / 44100.0;
+                let x: f32 = (1..)
+                    .take_while(|r| f0 * (*r as f32) < 22050.0)
+                    .map(|r| amplitude(r) * (TAU * f0 * r as f32 * t + r as f32).sin())
+                    .sum();
+                window * x
+            })
+            .collect();
+        let mut spectrum = vec![Complex32::default(); window_size / 2 + 1];
+        let r2c = RealFftPlanner::<f32>::new().plan_fft_forward(window_size);
+        r2c.process(&mut frame, &mut spectrum).unwrap();
+        spectrum
+    }
+
+    #[test]
+    fn test_pitch_of_harmonics() {
+        for window_size in [1024, 2048, 4096] {
+            let bin_width = 44100.0 / window_size as f32;
+            for f0 in [110.0, 220.0, 347.5, 880.0] {
+                // Fundamentals below a few bins can't be told apart from their neighbours
+                if f0 / bin_width < 4.0 {
+                    continue;
+                }
+                for (name, amplitude) in [
+                    ("sawtooth", (|r| 1.0 / r as f32) as fn(usize) -> f32),
+                    ("odd", |r| if r % 2 == 1 { 1.0 / r as f32 } else { 0.0 }),
+                    ("bright", |r| if r <= 8 { 1.0 } else { 0.0 }),
+                ] {
+                    let pitch = Pitch::estimate(&harmonics(f0, window_size, amplitude));
+                    let hz = pitch.f0 * bin_width;
+                    assert!(
+                        (hz - f0).abs() < f0 * 0.01,
+                        "{} at {} Hz with {} bins: {} Hz",
+                        name,
+                        f0,
+                        window_size,
+                        hz
+                    );
+                    assert!(pitch.confidence > 0.75, "{} at {} Hz: {}", name, f0, pitch.confidence);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_pitch_of_noise() {
+        let window_size = 2048;
+        let mut rng = StdRng::seed_from_u64(47);
+        let mut frame: Vec<f32> = (0..window_size)
+            .map(|n| {
+                let window = 0.5 - 0.5 * (TAU * n as f32 / window_size as f32).cos();
+                window * rng.gen_range(-1.0..1.0)
+            })
+            .collect();
+        let mut spectrum = vec![Complex32::default(); window_size / 2 + 1];
+        let r2c = RealFftPlanner::<f32>::new().plan_fft_forward(window_size);
+        r2c.process(&mut frame, &mut spectrum).unwrap();
+        assert!(Pitch::estimate(&spectrum).confidence < 0.2);
+
+        // Silence has no fundamental
+        assert_eq!(Pitch::estimate(&vec![Complex32::default(); 1025]), Pitch::default());
+        assert_eq!(Pitch::estimate(&[]), Pitch::default());
+    }
+
+    #[test]
+    fn test_harmonic_of() {
+        assert_eq!(harmonic_of(10.0, 34.0), 3.0);
+        assert_eq!(harmonic_dist(10.0, 34.0), 4.0);
+        assert_eq!(harmonic_dist(10.0, 36.0), -4.0);
+        assert_eq!(harmonic_of(0.0, 34.0), 0.0);
+        assert_eq!(harmonic_dist(0.0, 34.0), 34.0);
+    }
+}
@@ -0,0 +1,254 @@
+use super::*;
+
+pub type PragmaError = String;
+
+/// Unit of the program argument and of `fft` lookups
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Domain {
+    /// Band index, as given by the FFT
+    Bin,
+    /// Frequency in Hz
+    Hz,
+    /// Frequency relative to Nyquist, from 0 to 1
+    Norm,
+}
+
+/// Settings given by `#` lines in the code
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pragmas {
+    pub domain: Domain,
+}
+
+impl Default for Pragmas {
+    fn default() -> Self {
+        Self {
+            domain: Domain::Bin,
+        }
+    }
+}
+
+/// Read pragma lines and blank them out, so that positions in the remaining code don't change
+pub fn pragmas(code: &str) -> Result<(Pragmas, String), PragmaError> {
+    let mut pragmas = Pragmas::default();
+    let mut rest = String::with_capacity(code.len());
+    for line in code.split_inclusive('\n') {
+        let Some(pragma) = line.trim_start().strip_prefix('#') else {
+            rest.push_str(line);
+            continue;
+        };
+        let mut words = pragma.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("domain"), Some(domain), None) => {
+                pragmas.domain = match domain {
+                    "bin" => Domain::Bin,
+                    "hz" => Domain::Hz,
+                    "norm" => Domain::Norm,
+                    _ => return Err(format!("Unknown domain: {}", domain)),
+                }
+            }
+            _ => return Err(format!("Unknown pragma: {}", line.trim())),
+        }
+        rest.extend(line.chars().map(|c| if c == '\n' { '\n' } else { ' ' }));
+    }
+    Ok((pragmas, rest))
+}
+
+impl Domain {
+    /// Wrap an elaborated `Float -> (Float, Float)` term taking this domain, into one taking bins
+    pub fn wrap(self, term: Term) -> Term {
+        if self == Domain::Bin {
+            return term;
+        }
+        let program = self.lookup_in(term);
+        let arg = self.map_bin(Term::Var(0));
+        Term::Func(
+            ValueType::Float.into(),
+            "i".into(),
+            Term::Apply(program.into(), arg.into()).into(),
+        )
+    }
+
+    /// Convert a bin to this domain
+    fn map_bin(self, bin: Term) -> Term {
+        let hz = Term::Apply(Term::Lib(Lib::Hz).into(), bin.into());
+        match self {
+            Domain::Bin => unreachable!(),
+            Domain::Hz => hz,
+            Domain::Norm => binary(Lib::Div, hz, Term::Lib(Lib::Nyquist)),
+        }
+    }
+
+    /// Convert a value in this domain to a bin
+    fn bin_of(self, value: Term) -> Term {
+        let hz = match self {
+            Domain::Bin => unreachable!(),
+            Domain::Hz => value,
+            Domain::Norm => binary(Lib::Mul, value, Term::Lib(Lib::Nyquist)),
+        };
+        Term::Apply(Term::Lib(Lib::Bin).into(), hz.into())
+    }
+
+    /// Make `fft` lookups take this domain, the replacement is closed so no shifting is needed
+    fn lookup_in(self, term: Term) -> Term {
+        match term {
+            Term::Lib(
+                lib @ (Lib::Fft
+                | Lib::FftWith(_)
+                | Lib::FftOf(_)
+                | Lib::Mag
+                | Lib::InstFreq
+                | Lib::Env
+                | Lib::Fine
+                | Lib::Harm
+                | Lib::Perc
+                | Lib::HarmonicOf
+                | Lib::HarmonicDist),
+            ) => Term::Func(
+                ValueType::Float.into(),
+                "f".into(),
+                Term::Apply(Term::Lib(lib).into(), self.bin_of(Term::Var(0)).into()).into(),
+            ),
+            // `hist(k)(f)`, where only `f` is a frequency
+            Term::Lib(Lib::Hist) => Term::Func(
+                ValueType::Float.into(),
+                "k".into(),
+                Term::Func(
+                    ValueType::Float.into(),
+                    "f".into(),
+                    Term::Apply(
+                        Term::Apply(Term::Lib(Lib::Hist).into(), Term::Var(1).into()).into(),
+                        self.bin_of(Term::Var(0)).into(),
+                    )
+                    .into(),
+                )
+                .into(),
+            ),
+            // `fft_prev(k, f)`, likewise
+            Term::Lib(Lib::FftPrev) => {
+                let pair = ValueType::Tuple(vec![ValueType::Float, ValueType::Float]);
+                let k = Term::Apply(Term::Lib(Lib::Re).into(), Term::Var(0).into());
+                let f = Term::Apply(Term::Lib(Lib::Im).into(), Term::Var(0).into());
+                Term::Func(
+                    pair.into(),
+                    "kf".into(),
+                    Term::Apply(
+                        Term::Lib(Lib::FftPrev).into(),
+                        Term::Tuple(vec![k, self.bin_of(f)]).into(),
+                    )
+                    .into(),
+                )
+            }
+            Term::Tuple(terms) => Term::Tuple(terms.into_iter().map(|t| self.lookup_in(t)).collect()),
+            Term::Apply(func, arg) => {
+                Term::Apply(self.lookup_in(*func).into(), self.lookup_in(*arg).into())
+            }
+            Term::Func(param_type, name, body) => {
+                Term::Func(param_type, name, self.lookup_in(*body).into())
+            }
+            Term::Let(value_type, name, value, next) => Term::Let(
+                value_type,
+                name,
+                self.lookup_in(*value).into(),
+                self.lookup_in(*next).into(),
+            ),
+            Term::Alt(cond, then, else_) => Term::Alt(
+                self.lookup_in(*cond).into(),
+                self.lookup_in(*then).into(),
+                self.lookup_in(*else_).into(),
+            ),
+            other => other,
+        }
+    }
+}
+
+fn binary(lib: Lib, x: Term, y: Term) -> Term {
+    Term::Apply(Term::Apply(Term::Lib(lib).into(), x.into()).into(), y.into())
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_pragma {
+    use super::*;
+
+    #[test]
+    fn test_pragmas() {
+        let (found, rest) = pragmas("#domain hz\n(i: Float) => fft(i)").unwrap();
+        assert_eq!(found.domain, Domain::Hz);
+        assert_eq!(rest, "          \n(i: Float) => fft(i)");
+        assert_eq!(pragmas("(i: Float) => fft(i)").unwrap().0, Pragmas::default());
+        assert!(pragmas("#domain mel\n(i: Float) => fft(i)").is_err());
+        assert!(pragmas("#stereo\n(i: Float) => fft(i)").is_err());
+    }
+
+    #[test]
+    fn test_domain_identity() {
+        for domain in ["hz", "norm"] {
+            let code = format!("#domain {}\n(i: Float) => fft(i)", domain);
+            let program = run(&code).unwrap();
+            assert_eq!(
+                program.term,
+                Term::Func(
+                    ValueType::Float.into(),
+                    "".into(),
+                    Term::Apply(Term::Lib(Lib::Fft).into(), Term::Var(0).into()).into(),
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_domain_sources() {
+        // Every spectrum is read in the domain, only the frequency of past frames is converted
+        let lookup = |lib: Lib| Term::Apply(Term::Lib(lib).into(), Term::Var(0).into());
+        for domain in ["hz", "norm"] {
+            for (code, expected) in [
+                ("fft_l(i)", lookup(Lib::FftOf(Source::Left))),
+                ("fft_side(i)", lookup(Lib::FftOf(Source::Side))),
+                ("side(i)", lookup(Lib::FftOf(Source::Sidechain))),
+                ("out_prev(i)", lookup(Lib::FftOf(Source::Output))),
+                (
+                    "(mag(i), inst_freq(i))",
+                    Term::Tuple(vec![lookup(Lib::Mag), lookup(Lib::InstFreq)]),
+                ),
+                (
+                    "(env(i), fine(i))",
+                    Term::Tuple(vec![lookup(Lib::Env), lookup(Lib::Fine)]),
+                ),
+                (
+                    "(harm(i).re, perc(i).im)",
+                    Term::Tuple(vec![
+                        Term::Apply(Term::Lib(Lib::Re).into(), lookup(Lib::Harm).into()),
+                        Term::Apply(Term::Lib(Lib::Im).into(), lookup(Lib::Perc).into()),
+                    ]),
+                ),
+                (
+                    "(harmonic_of(i), harmonic_dist(i))",
+                    Term::Tuple(vec![lookup(Lib::HarmonicOf), lookup(Lib::HarmonicDist)]),
+                ),
+                (
+                    "hist(2)(i)",
+                    Term::Apply(
+                        Term::Apply(Term::Lib(Lib::Hist).into(), Term::Float(2.0).into()).into(),
+                        Term::Var(0).into(),
+                    ),
+                ),
+                (
+                    "fft_prev(3, i)",
+                    Term::Apply(
+                        Term::Lib(Lib::FftPrev).into(),
+                        Term::Tuple(vec![Term::Float(3.0), Term::Var(0)]).into(),
+                    ),
+                ),
+            ] {
+                let code = format!("#domain {}\n(i: Float) => {}", domain, code);
+                let program = run(&code).unwrap();
+                assert_eq!(
+                    program.term,
+                    Term::Func(ValueType::Float.into(), "".into(), expected.into()),
+                    "{}",
+                    code
+                );
+            }
+        }
+    }
+}
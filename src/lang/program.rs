@@ -0,0 +1,280 @@
+use realfft::num_complex::Complex32;
+
+use super::*;
+
+/// What a program gives for each bin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    /// One bin, `Float -> (Float, Float)`
+    Mono,
+    /// Left and right bins, `Float -> ((Float, Float), (Float, Float))`
+    Stereo,
+}
+
+impl Output {
+    pub const ALL: [Output; 2] = [Output::Mono, Output::Stereo];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Output::Mono => "mono",
+            Output::Stereo => "stereo",
+        }
+    }
+
+    /// Type of the value given for each bin
+    pub fn bin_type(&self) -> ValueType {
+        let complex = || ValueType::Tuple(vec![ValueType::Float, ValueType::Float]);
+        match self {
+            Output::Mono => complex(),
+            Output::Stereo => ValueType::Tuple(vec![complex(), complex()]),
+        }
+    }
+
+    /// Type of a program with this output
+    pub fn value_type(&self) -> ValueType {
+        ValueType::Func(Box::new(ValueType::Float), Box::new(self.bin_type()))
+    }
+
+    /// Output of a closed term, terms of other types are treated as mono
+    pub fn of(term: &Term) -> Self {
+        match type_of(term, &mut Vec::new()) {
+            Ok(value_type) if value_type == Output::Stereo.value_type() => Output::Stereo,
+            Ok(value_type) => Machine::of_type(&value_type).map_or(Output::Mono, |(_, output)| output),
+            Err(_) => Output::Mono,
+        }
+    }
+}
+
+/// A compiled program, split into a per-frame prologue and a per-bin body
+#[derive(Clone, Debug)]
+pub struct Program {
+    /// The simplified program
+    pub term: Term,
+    /// Terms that don't depend on the bin, evaluated once per frame
+    pub prologue: Vec<Term>,
+    /// Evaluated once per bin, with the prologue values and then the bin in scope
+    pub body: Term,
+    /// The gain the program applies, if it is only a static filter
+    pub mask: Option<Box<Mask>>,
+    /// Whether the program gives one bin or a pair of left and right bins
+    pub output: Output,
+    /// The state of each bin, for programs written as `{ init: ..., step: ... }`, whose body is
+    /// then the step function
+    pub machine: Option<Machine>,
+    /// Whether the output depends on past frames, through `hist` or `fft_prev`
+    pub uses_history: bool,
+    /// Whether the output is fed back through `out_prev`
+    pub uses_feedback: bool,
+    /// Whether the program reads the spectral envelope, through `env` or `fine`
+    pub uses_envelope: bool,
+    /// Whether the program reads the harmonic part of the input, through `harm` or `perc`
+    pub uses_hpss: bool,
+    /// Whether the program reads descriptors of the frame, or measures bands against its pitch
+    pub uses_features: bool,
+    /// Whether the program reads onsets, through `onset`, `onset_strength` or `since_onset`
+    pub uses_onset: bool,
+}
+
+/// What reads the onset detector, which keeps track of earlier frames on its own
+const ONSET: [Lib; 3] = [
+    Lib::Onset,
+    Lib::Feature(Feature::OnsetStrength),
+    Lib::Feature(Feature::SinceOnset),
+];
+
+impl Program {
+    /// Split a simplified program term, sharing repeated terms in the body
+    pub fn new(term: Term) -> Self {
+        let program = Self::unmasked(term);
+        let mask = match program.output {
+            Output::Mono => Mask::new(&program.term).map(Box::new),
+            Output::Stereo => None,
+        };
+        Self { mask, ..program }
+    }
+
+    /// Split a term without looking for a mask, as the gain of a mask may itself look like one
+    pub(crate) fn unmasked(term: Term) -> Self {
+        let (machine, func) = match Machine::new(&term) {
+            Some((machine, step)) => (Some(machine), step),
+            None => (None, term.clone()),
+        };
+        let param_type = machine.as_ref().map_or(ValueType::Float, Machine::param_type);
+        let body = match func {
+            Term::Func(_, _, body) => *body,
+            other => Term::Apply(other.into(), Term::Var(0).into()),
+        };
+        let (prologue, body) = hoist(body, param_type.clone());
+        let ctx = prologue
+            .iter()
+            .map(|t| type_of(t, &mut Vec::new()))
+            .collect::<Result<Vec<_>, _>>();
+        let body = match ctx {
+            Ok(mut ctx) => {
+                ctx.push(param_type);
+                cse(body, &mut ctx)
+            }
+            Err(_) => body,
+        };
+        Self {
+            output: Output::of(&term),
+            uses_history: mentions(&term, &Lib::Hist) || mentions(&term, &Lib::FftPrev),
+            uses_feedback: mentions(&term, &Lib::FftOf(Source::Output)),
+            uses_envelope: mentions(&term, &Lib::Env) || mentions(&term, &Lib::Fine),
+            uses_hpss: mentions(&term, &Lib::Harm) || mentions(&term, &Lib::Perc),
+            uses_features: Feature::ALL
+                .map(Lib::Feature)
+                .into_iter()
+                .chain([Lib::HarmonicOf, Lib::HarmonicDist])
+                .filter(|lib| !ONSET.contains(lib))
+                .any(|lib| mentions(&term, &lib)),
+            uses_onset: ONSET.iter().any(|lib| mentions(&term, lib)),
+            term,
+            prologue,
+            body,
+            mask: None,
+            machine,
+        }
+    }
+
+    /// Evaluate the prologue with the resource of the current frame
+    pub fn frame(&self, env: &mut Env, res: &Resource) -> Result<(), EvalError> {
+        env.clear();
+        for term in &self.prologue {
+            let value = eval(term, env, res)?;
+            env.push(value);
+        }
+        Ok(())
+    }
+
+    /// Evaluate a bin, `env` should be prepared with `frame`
+    pub fn apply(&self, arg: Value, env: &mut Env, res: &Resource) -> Result<Value, EvalError> {
+        env.push(arg);
+        let result = eval(&self.body, env, res);
+        env.pop();
+        result
+    }
+
+    /// Evaluate the bins of a frame in order, giving the index and value of each to `bin`
+    fn each_bin(
+        &self,
+        env: &mut Env,
+        res: &Resource,
+        range: impl Iterator<Item = usize>,
+        mut bin: impl FnMut(usize, Value) -> Result<(), EvalError>,
+    ) -> Result<(), EvalError> {
+        self.frame(env, res)?;
+        let Some(machine) = &self.machine else {
+            for i in range {
+                if let Some(state) = res.state {
+                    state.set_bin(i);
+                }
+                bin(i, self.apply(Value::Int(i as i32), env, res)?)?;
+            }
+            return Ok(());
+        };
+
+        // Bins start from the initial state after a reset, and on every frame without state
+        let init = eval(&machine.init, &mut Env::new(), res)?;
+        let fresh = res.state.is_none_or(State::take_fresh);
+        for i in range {
+            let last = match res.state {
+                Some(state) if !fresh => machine.load(state, i),
+                _ => None,
+            };
+            if let Some(state) = res.state {
+                state.set_bin(i);
+            }
+            let arg = Value::Tuple(vec![last.unwrap_or_else(|| init.clone()), Value::Int(i as i32)]);
+            let Value::Tuple(result) = self.apply(arg, env, res)? else {
+                return Err(EvalError::NotStep);
+            };
+            let [next, output]: [Value; 2] = result.try_into().map_err(|_| EvalError::NotStep)?;
+            if let Some(state) = res.state {
+                machine.store(state, i, &next)?;
+            }
+            bin(i, output)?;
+        }
+        Ok(())
+    }
+
+    /// Treat the program as an array, collect its values at all indicies.
+    pub fn collect(
+        &self,
+        range: impl Iterator<Item = usize>,
+        res: &Resource,
+    ) -> Result<Vec<Value>, EvalError> {
+        let mut values = Vec::new();
+        self.each_bin(&mut Env::new(), res, range, |_, value| {
+            values.push(value);
+            Ok(())
+        })?;
+        Ok(values)
+    }
+
+    /// Evaluate all bins of a frame into `out`, reusing `env` so this doesn't allocate
+    pub fn collect_into(
+        &self,
+        env: &mut Env,
+        res: &Resource,
+        out: &mut [Complex32],
+    ) -> Result<(), EvalError> {
+        self.each_bin(env, res, 0..out.len(), |i, value| {
+            out[i] = value.try_into()?;
+            Ok(())
+        })
+    }
+
+    /// Evaluate all bins of a frame of a stereo-output program into `left` and `right`
+    pub fn collect_stereo_into(
+        &self,
+        env: &mut Env,
+        res: &Resource,
+        left: &mut [Complex32],
+        right: &mut [Complex32],
+    ) -> Result<(), EvalError> {
+        self.each_bin(env, res, 0..left.len().min(right.len()), |i, value| {
+            (left[i], right[i]) = value.try_into()?;
+            Ok(())
+        })
+    }
+
+    pub fn pretty_term(&self) -> String {
+        self.term.pretty_term()
+    }
+
+    pub fn pretty_prologue(&self) -> String {
+        self.prologue
+            .iter()
+            .map(|t| t.pretty_term())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_program {
+    use super::*;
+
+    #[test]
+    fn test_program_analyses() {
+        let program = run("(i: Float) => (hist(1)(i).re * env(i), perc(i).im)").unwrap();
+        assert!(program.uses_history && !program.uses_feedback);
+        assert!(program.uses_envelope && program.uses_hpss);
+        assert!(!program.uses_features);
+        let program = run("(i: Float) => (fft(i).re + out_prev(i).re, centroid)").unwrap();
+        assert!(!program.uses_history && program.uses_feedback);
+        assert!(!program.uses_envelope && !program.uses_hpss);
+        assert!(program.uses_features);
+        let program = run("(i: Float) => (fft(i).re * harmonic_dist(i), 0)").unwrap();
+        assert!(program.uses_features);
+        assert!(!program.uses_onset);
+        let program = run("(i: Float) => (fft(i).re, since_onset)").unwrap();
+        assert!(!program.uses_features && program.uses_onset);
+        let program = run("(i: Float) => fft(i)").unwrap();
+        assert!(!program.uses_history && !program.uses_feedback);
+        assert!(!program.uses_envelope && !program.uses_hpss);
+        assert!(!program.uses_features && !program.uses_onset);
+    }
+}
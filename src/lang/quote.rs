@@ -1,43 +1,48 @@
 use super::*;
 
-pub fn quote(env_len: usize, val: Value) -> Term {
-    match val {
+pub fn quote(env_len: usize, val: Value) -> Result<Term, EvalError> {
+    let term = match val {
         Value::Float(i) => Term::Float(i),
         Value::Int(i) => Term::Float(i as f32),
         Value::Bool(i) => Term::Bool(i),
         Value::Lib(i) => Term::Lib(i),
         Value::Tuple(xs) => {
-            let xs = xs.into_iter().map(|x| quote(env_len, x)).collect();
+            let xs = xs
+                .into_iter()
+                .map(|x| quote(env_len, x))
+                .collect::<Result<_, _>>()?;
             Term::Tuple(xs)
         }
         Value::Func(return_type, closure) => {
-            let temp_val = closure.papply(Value::Var(env_len as i32));
-            Term::Func(return_type, "".into(), quote(env_len + 1, temp_val).into())
+            let temp_val = closure.papply(Value::Var(env_len as i32))?;
+            Term::Func(return_type, "".into(), quote(env_len + 1, temp_val)?.into())
         }
         Value::Apply(func, args) => {
-            let func = quote(env_len, *func);
-            let args = args.into_iter().map(|a| quote(env_len, a));
-            let mut result = func;
+            let mut result = quote(env_len, *func)?;
             for arg in args {
-                result = Term::Apply(result.into(), arg.into());
+                result = Term::Apply(result.into(), quote(env_len, arg)?.into());
             }
             result
         }
         Value::Var(i) => {
-            let var_index = env_len - i as usize - 1;
-            Term::Var(var_index as i32)
+            let var_index = env_len as i32 - i - 1;
+            if var_index < 0 {
+                return Err(EvalError::Unbound(i));
+            }
+            Term::Var(var_index)
         }
         Value::Alt(cond, then, else_) => {
-            let cond = quote(env_len, *cond);
-            let then = quote(env_len, *then);
-            let else_ = quote(env_len, *else_);
+            let cond = quote(env_len, *cond)?;
+            let then = quote(env_len, *then)?;
+            let else_ = quote(env_len, *else_)?;
             Term::Alt(cond.into(), then.into(), else_.into())
         }
-    }
+    };
+    Ok(term)
 }
 
-pub fn simp(term: Term) -> Term {
-    let val = peval(term, &mut Vec::new());
+pub fn simp(term: Term) -> Result<Term, EvalError> {
+    let val = peval(term, &mut Vec::new())?;
     quote(0, val)
 }
 
@@ -49,7 +54,7 @@ pub mod tests_eval {
     #[test]
     fn test_quote() {
         let code = Term::Float(80.0);
-        let result = quote(0, Value::Float(80.0));
+        let result = quote(0, Value::Float(80.0)).unwrap();
         assert_eq!(code, result);
     }
 
@@ -68,7 +73,8 @@ pub mod tests_eval {
                 Box::new(Term::Float(800.0)),
             )
             .into(),
-        ));
+        ))
+        .unwrap();
         assert_eq!(code, result);
     }
 }
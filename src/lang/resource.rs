@@ -1,8 +1,174 @@
 use realfft::num_complex::Complex32;
 
+use super::*;
+
 pub struct Resource<'a> {
     pub fft: &'a Vec<Complex32>,
     pub modulation: &'a Vec<f32>,
     pub beat: f64,
     pub second: f64,
-}
\ No newline at end of file
+    /// Transport of the block, or the internal clock if the host has none
+    pub clock: Clock,
+    /// Sample rate of the host, in Hz
+    pub sample_rate: f32,
+    /// Size of the STFT window, in samples
+    pub window_size: usize,
+    /// Number of windows overlapping each sample
+    pub overlap: usize,
+    /// Spectra of both channels, `None` for mono input
+    pub stereo: Option<Stereo<'a>>,
+    /// Spectrum of the sidechain input summed to mono, silent when it isn't connected
+    pub sidechain: &'a [Complex32],
+    /// Spectra of the frames before this one, `None` if they aren't recorded
+    pub history: Option<&'a History>,
+    /// Output of the program on the previous frame for the channel being evaluated, silent
+    /// before the first frame
+    pub feedback: &'a [Complex32],
+    /// Values of stateful calls kept between frames, `None` if every call is stateless
+    pub state: Option<&'a State>,
+    /// Smoothed magnitudes of `fft`, empty if they aren't estimated
+    pub envelope: &'a [f32],
+    /// Fraction of each bin of `fft` that is harmonic, empty if it isn't separated
+    pub harmonic: &'a [f32],
+    /// Key of the random values of this frame, see `frame_seed`
+    pub frame_seed: u64,
+    /// Descriptors of the whole input frame
+    pub features: Features,
+}
+
+/// A spectrum that programs can read besides `fft`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Left,
+    Right,
+    Mid,
+    Side,
+    Sidechain,
+    /// The previous output, like `Resource::feedback`
+    Output,
+}
+
+impl Source {
+    /// Every source, indexed by `Source as usize`
+    pub const ALL: [Source; 6] = [
+        Source::Left,
+        Source::Right,
+        Source::Mid,
+        Source::Side,
+        Source::Sidechain,
+        Source::Output,
+    ];
+}
+
+/// Spectra of a stereo frame, shared by the evaluation of each channel
+pub struct Stereo<'a> {
+    pub left: &'a [Complex32],
+    pub right: &'a [Complex32],
+    /// Half the sum of both channels
+    pub mid: &'a [Complex32],
+    /// Half the difference of both channels
+    pub side: &'a [Complex32],
+    /// Index of the channel being evaluated, 0 for left or mid and 1 for right or side
+    pub channel: usize,
+    /// Whether the channels being evaluated are mid and side instead of left and right
+    pub mid_side: bool,
+}
+
+impl<'a> Resource<'a> {
+    /// A frame of `fft` at the start of the transport, with a 2048 sample window overlapped 16
+    /// times at 44.1 kHz. Other fields are set with `..`.
+    pub fn new(fft: &'a Vec<Complex32>, modulation: &'a Vec<f32>) -> Self {
+        Self {
+            fft,
+            modulation,
+            beat: 0.0,
+            second: 0.0,
+            clock: Default::default(),
+            sample_rate: 44100.0,
+            window_size: 2048,
+            overlap: 16,
+            stereo: None,
+            sidechain: &[],
+            history: None,
+            feedback: &[],
+            state: None,
+            envelope: &[],
+            harmonic: &[],
+            frame_seed: 0,
+            features: Default::default(),
+        }
+    }
+}
+
+impl Resource<'_> {
+    /// Number of samples between the start of two windows
+    pub fn hop(&self) -> usize {
+        self.window_size / self.overlap.max(1)
+    }
+
+    /// Highest representable frequency, in Hz
+    pub fn nyquist(&self) -> f32 {
+        self.sample_rate / 2.0
+    }
+
+    /// Width of a bin, in Hz
+    pub fn bin_width(&self) -> f32 {
+        self.sample_rate / self.window_size.max(1) as f32
+    }
+
+    /// Spectrum of a source, mono input is its own left, right and mid with a silent side
+    pub fn spectrum(&self, source: Source) -> &[Complex32] {
+        match (&self.stereo, source) {
+            (_, Source::Sidechain) => self.sidechain,
+            (_, Source::Output) => self.feedback,
+            (Some(stereo), Source::Left) => stereo.left,
+            (Some(stereo), Source::Right) => stereo.right,
+            (Some(stereo), Source::Mid) => stereo.mid,
+            (Some(stereo), Source::Side) => stereo.side,
+            (None, Source::Side) => &[],
+            (None, _) => self.fft,
+        }
+    }
+
+    /// Index of the channel being evaluated
+    pub fn channel(&self) -> usize {
+        self.stereo.as_ref().map_or(0, |stereo| stereo.channel)
+    }
+
+    /// Whether the mid channel is being evaluated
+    pub fn is_mid(&self) -> bool {
+        self.stereo.as_ref().is_some_and(|stereo| stereo.mid_side && stereo.channel == 0)
+    }
+
+    /// Whether the side channel is being evaluated
+    pub fn is_side(&self) -> bool {
+        self.stereo.as_ref().is_some_and(|stereo| stereo.mid_side && stereo.channel == 1)
+    }
+
+    /// Spectrum `k` frames before the current one at a fractional band
+    pub fn history_at(&self, k: f32, f: f32) -> Complex32 {
+        history_at(self.fft, self.history, k, f)
+    }
+
+    /// Advance a stateful call at the bin being evaluated by one hop
+    pub fn state_step(&self, op: StateOp, slot: usize, params: [f32; 2], x: f32) -> f32 {
+        let dt = self.hop() as f32 / self.sample_rate;
+        state_step(self.state, op, slot, params, x, dt)
+    }
+
+    /// Value of a descriptor of the whole frame
+    pub fn feature(&self, feature: Feature) -> f32 {
+        self.features.get(feature, self.bin_width())
+    }
+
+    /// Frequency of `fft` at a fractional band in Hz, measured against the last frame
+    pub fn inst_freq(&self, f: f32) -> f32 {
+        let phases = self.state.map(State::phases);
+        inst_freq_at(self.fft, phases, f, self.hop(), self.window_size) * self.bin_width()
+    }
+
+    /// The bin being evaluated of a sinusoid at `freq` Hz, turning on from the last frame
+    pub fn synth(&self, mag: f32, freq: f32) -> Complex32 {
+        synth(self.state, mag, freq / self.bin_width(), self.hop(), self.window_size)
+    }
+}
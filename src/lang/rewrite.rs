@@ -0,0 +1,239 @@
+use super::*;
+
+/// Simplify a term bottom-up with algebraic identities that normalisation by evaluation misses,
+/// since it can't look into symbolic arguments.
+/// Identities that only hold for finite values, like `0 * x = 0`, are only applied when the other
+/// operand is known to be finite, as `tan` and overflows can give infinities and NaN.
+pub fn rewrite(term: Term) -> Term {
+    match term {
+        Term::Tuple(terms) => Term::Tuple(terms.into_iter().map(rewrite).collect()),
+        Term::Apply(func, arg) => rewrite_apply(rewrite(*func), rewrite(*arg)),
+        Term::Func(param_type, name, body) => Term::Func(param_type, name, rewrite(*body).into()),
+        Term::Let(value_type, name, value, next) => Term::Let(
+            value_type,
+            name,
+            rewrite(*value).into(),
+            rewrite(*next).into(),
+        ),
+        Term::Alt(cond, then, else_) => rewrite_alt(rewrite(*cond), rewrite(*then), rewrite(*else_)),
+        other => other,
+    }
+}
+
+/// Rewrite an application whose function and argument are already rewritten
+fn rewrite_apply(func: Term, arg: Term) -> Term {
+    match (func, arg) {
+        // Binary operators with a symbolic left operand, `x op y`
+        (Term::Apply(op, x), y) if matches!(*op, Term::Lib(_)) => {
+            let Term::Lib(lib) = *op else { unreachable!() };
+            match (&lib, *x, y) {
+                (Lib::Add, x, Term::Float(0.0)) => x,
+                (Lib::Add, Term::Float(0.0), y) => y,
+                (Lib::Sub, x, Term::Float(0.0)) => x,
+                (Lib::Sub, x, y) if x == y && is_finite(&x) => Term::Float(0.0),
+                (Lib::Mul, x, Term::Float(1.0)) => x,
+                (Lib::Mul, Term::Float(1.0), y) => y,
+                (Lib::Mul, Term::Float(0.0), y) if is_finite(&y) => Term::Float(0.0),
+                (Lib::Mul, x, Term::Float(0.0)) if is_finite(&x) => Term::Float(0.0),
+                (Lib::Div, x, Term::Float(1.0)) => x,
+                (Lib::Div, Term::Float(0.0), y) if is_finite(&y) => Term::Float(0.0),
+                (Lib::Div, _, Term::Float(0.0)) => Term::Float(0.0),
+                (Lib::Mod, Term::Float(0.0), y) if is_finite(&y) => Term::Float(0.0),
+                (Lib::Mod, _, Term::Float(0.0)) => Term::Float(0.0),
+                // Undo the division by a setting, which is never zero, as domain conversion does
+                (Lib::Mul, x, d) if is_setting(&d) && divisor(&x) == Some(&d) => {
+                    let Term::Apply(div, _) = x else { unreachable!() };
+                    let Term::Apply(_, n) = *div else { unreachable!() };
+                    *n
+                }
+                (Lib::Add, x, Term::Float(y)) if split(&x, Lib::Add).is_some() => offset(x, y),
+                (Lib::Mul, x, Term::Float(y)) if split(&x, Lib::Mul).is_some() => scale(x, y),
+                (_, x, y) => Term::Apply(
+                    Term::Apply(Term::Lib(lib).into(), x.into()).into(),
+                    y.into(),
+                ),
+            }
+        }
+
+        // Partially applied operators with a constant left operand, `c op x`
+        (Term::Lib(lib), x) => match lib {
+            Lib::Add1(0.0) => x,
+            Lib::AddI(0) => x,
+            Lib::Mul1(1.0) => x,
+            Lib::MulI(1) => x,
+            Lib::Mul1(0.0) | Lib::Div1(0.0) | Lib::Mod1(0.0) if is_finite(&x) => Term::Float(0.0),
+            Lib::MulI(0) | Lib::DivI(0) | Lib::ModI(0) if is_finite(&x) => Term::Float(0.0),
+            Lib::Hz | Lib::Bin => match x {
+                Term::Apply(inner, y) if is_inverse(&lib, &inner) => *y,
+                x => Term::Apply(Term::Lib(lib).into(), x.into()),
+            },
+            Lib::Add1(c) => offset(x, c),
+            Lib::Mul1(c) => scale(x, c),
+            Lib::Re | Lib::Im => match x {
+                Term::Tuple(mut terms) if terms.len() == 2 => {
+                    let im = terms.pop().unwrap();
+                    let re = terms.pop().unwrap();
+                    if lib == Lib::Re {
+                        re
+                    } else {
+                        im
+                    }
+                }
+                x => Term::Apply(Term::Lib(lib).into(), x.into()),
+            },
+            lib => Term::Apply(Term::Lib(lib).into(), x.into()),
+        },
+
+        (func, arg) => Term::Apply(func.into(), arg.into()),
+    }
+}
+
+/// Add a constant to `x`, merging with a constant offset already in `x`
+fn offset(x: Term, c: f32) -> Term {
+    match split(&x, Lib::Add) {
+        Some((d, y)) => rewrite_apply(Term::Lib(Lib::Add1(c + d)), y.clone()),
+        None => Term::Apply(Term::Lib(Lib::Add1(c)).into(), x.into()),
+    }
+}
+
+/// Multiply `x` by a constant, merging with a constant factor already in `x`
+fn scale(x: Term, c: f32) -> Term {
+    match split(&x, Lib::Mul) {
+        Some((d, y)) => rewrite_apply(Term::Lib(Lib::Mul1(c * d)), y.clone()),
+        None => Term::Apply(Term::Lib(Lib::Mul1(c)).into(), x.into()),
+    }
+}
+
+/// Split `c + y` or `y + c` (and likewise for `*`) into the constant and the rest
+fn split(term: &Term, op: Lib) -> Option<(f32, &Term)> {
+    match term {
+        Term::Apply(func, y) => match (&**func, &op) {
+            (Term::Lib(Lib::Add1(c)), Lib::Add) | (Term::Lib(Lib::Mul1(c)), Lib::Mul) => {
+                Some((*c, y))
+            }
+            (Term::Apply(lib, x), _) if matches!(&**lib, Term::Lib(l) if *l == op) => match &**y {
+                Term::Float(c) => Some((*c, x)),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Check if a term is known to be finite without evaluating it
+fn is_finite(term: &Term) -> bool {
+    match term {
+        Term::Float(x) => x.is_finite(),
+        term => is_setting(term),
+    }
+}
+
+/// Check if a term is an STFT setting, which is always positive
+fn is_setting(term: &Term) -> bool {
+    matches!(term, Term::Lib(Lib::Srate | Lib::Wsize | Lib::Hop | Lib::Nyquist))
+}
+
+/// The divisor `d` of `n / d`
+fn divisor(term: &Term) -> Option<&Term> {
+    match term {
+        Term::Apply(func, d) => match &**func {
+            Term::Apply(lib, _) if **lib == Term::Lib(Lib::Div) => Some(d),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Check if `inner` converts back what `lib` converts, as `hz` and `bin` do
+fn is_inverse(lib: &Lib, inner: &Term) -> bool {
+    matches!(
+        (lib, inner),
+        (Lib::Hz, Term::Lib(Lib::Bin)) | (Lib::Bin, Term::Lib(Lib::Hz))
+    )
+}
+
+/// Rewrite a branch whose parts are already rewritten
+fn rewrite_alt(cond: Term, then: Term, else_: Term) -> Term {
+    match (cond, then, else_) {
+        (Term::Bool(true), then, _) => then,
+        (Term::Bool(false), _, else_) => else_,
+        (_, then, else_) if then == else_ => then,
+        (cond, Term::Bool(true), Term::Bool(false)) => cond,
+        (cond, then, else_) => Term::Alt(cond.into(), then.into(), else_.into()),
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_rewrite {
+    use super::*;
+
+    fn fft_norm() -> Term {
+        Term::Apply(
+            Term::Lib(Lib::Norm).into(),
+            Term::Apply(Term::Lib(Lib::Fft).into(), Term::Var(0).into()).into(),
+        )
+    }
+
+    fn body(code: &str) -> Term {
+        match rewrite(normalise(code).unwrap()) {
+            Term::Func(_, _, body) => *body,
+            other => panic!("not a function: {}", other),
+        }
+    }
+
+    #[test]
+    fn test_rewrite_identity() {
+        assert_eq!(
+            body("(i: Float) => (fft(i).norm * 1 + 0, 0 * srate)"),
+            Term::Tuple(vec![fft_norm(), Term::Float(0.0)])
+        );
+    }
+
+    #[test]
+    fn test_rewrite_non_finite() {
+        // `tan` gives NaN at its poles, which zero doesn't cancel
+        let tan = Term::Apply(Term::Lib(Lib::Tan).into(), fft_norm().into());
+        let times_zero = Term::Apply(
+            Term::Apply(Term::Lib(Lib::Mul).into(), tan.clone().into()).into(),
+            Term::Float(0.0).into(),
+        );
+        assert_eq!(
+            body("(i: Float) => (tan(fft(i).norm) * 0, 0 * wsize)"),
+            Term::Tuple(vec![times_zero, Term::Float(0.0)])
+        );
+        assert_eq!(
+            body("(i: Float) => (tan(fft(i).norm) - tan(fft(i).norm), tan(fft(i).norm) / 0)"),
+            Term::Tuple(vec![
+                Term::Apply(
+                    Term::Apply(Term::Lib(Lib::Sub).into(), tan.clone().into()).into(),
+                    tan.into(),
+                ),
+                Term::Float(0.0),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_rewrite_alt() {
+        assert_eq!(
+            body("(i: Float) => if i < 10 then (fft(i).norm, 0) else (fft(i).norm, 0)"),
+            Term::Tuple(vec![fft_norm(), Term::Float(0.0)])
+        );
+    }
+
+    #[test]
+    fn test_rewrite_constants() {
+        assert_eq!(
+            body("(i: Float) => (2 * (fft(i).norm * 3) + 1 + 2, (fft(i).norm, i).re)"),
+            Term::Tuple(vec![
+                Term::Apply(
+                    Term::Lib(Lib::Add1(3.0)).into(),
+                    Term::Apply(Term::Lib(Lib::Mul1(6.0)).into(), fft_norm().into()).into(),
+                ),
+                fft_norm(),
+            ])
+        );
+    }
+}
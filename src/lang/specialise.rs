@@ -0,0 +1,156 @@
+use super::*;
+
+/// Maximum number of substitution rounds, each one can expose constant `param` indices
+const MAX_ROUNDS: usize = 8;
+
+/// Partially evaluate a simplified term again with `param`, `beat`, `sec` and the STFT settings
+/// known, so that branches on them collapse and constants fold through, then rewrite it again
+pub fn specialise(term: &Term, res: &Resource) -> Result<Term, EvalError> {
+    let mut term = term.clone();
+    for _ in 0..MAX_ROUNDS {
+        let next = simp(subst(term.clone(), res))?;
+        if next == term {
+            break;
+        }
+        term = next;
+    }
+    Ok(rewrite(term))
+}
+
+/// Replace known symbols with their values
+fn subst(term: Term, res: &Resource) -> Term {
+    match term {
+        Term::Lib(Lib::Beat) => Term::Float(res.beat as f32),
+        Term::Lib(Lib::Sec) => Term::Float(res.second as f32),
+        Term::Lib(Lib::Srate) => Term::Float(res.sample_rate),
+        Term::Lib(Lib::Wsize) => Term::Float(res.window_size as f32),
+        Term::Lib(Lib::Hop) => Term::Float(res.hop() as f32),
+        Term::Lib(Lib::Nyquist) => Term::Float(res.nyquist()),
+        Term::Lib(Lib::Hz) => Term::Lib(Lib::Mul1(res.bin_width())),
+        Term::Lib(Lib::Bin) => Term::Lib(Lib::Mul1(res.bin_width().recip())),
+        Term::Tuple(terms) => Term::Tuple(terms.into_iter().map(|t| subst(t, res)).collect()),
+        Term::Apply(func, arg) => match (subst(*func, res), subst(*arg, res)) {
+            (Term::Lib(Lib::Param), Term::Float(f)) => Term::Float(param_at(res.modulation, f)),
+            (Term::Lib(Lib::ParamWith(mode)), Term::Float(f)) => {
+                Term::Float(interpolate(res.modulation, f, mode))
+            }
+            (func, arg) => Term::Apply(func.into(), arg.into()),
+        },
+        Term::Func(param_type, name, body) => Term::Func(param_type, name, subst(*body, res).into()),
+        Term::Let(value_type, name, value, next) => Term::Let(
+            value_type,
+            name,
+            subst(*value, res).into(),
+            subst(*next, res).into(),
+        ),
+        Term::Alt(cond, then, else_) => Term::Alt(
+            subst(*cond, res).into(),
+            subst(*then, res).into(),
+            subst(*else_, res).into(),
+        ),
+        other => other,
+    }
+}
+
+/// Check if a term refers to a library function, in any interpolation mode
+pub fn mentions(term: &Term, lib: &Lib) -> bool {
+    match term {
+        Term::Lib(l) => l.canonical() == *lib,
+        Term::Tuple(terms) => terms.iter().any(|t| mentions(t, lib)),
+        Term::Apply(func, arg) => mentions(func, lib) || mentions(arg, lib),
+        Term::Func(_, _, body) => mentions(body, lib),
+        Term::Let(_, _, value, next) => mentions(value, lib) || mentions(next, lib),
+        Term::Alt(cond, then, else_) => {
+            mentions(cond, lib) || mentions(then, lib) || mentions(else_, lib)
+        }
+        _ => false,
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_specialise {
+    use super::*;
+    use realfft::num_complex::Complex32;
+
+    fn resource<'a>(fft: &'a Vec<Complex32>, modulation: &'a Vec<f32>) -> Resource<'a> {
+        Resource {
+            beat: 2.0,
+            second: 1.0,
+            clock: Default::default(),
+            sample_rate: 48000.0,
+            ..Resource::new(fft, modulation)
+        }
+    }
+
+    #[test]
+    fn test_specialise_branch() {
+        let program = run("(i: Float) => if param(0) > 0.5 then fft(i) else (0, 0)").unwrap();
+        let (fft, modulation) = (vec![], vec![0.8]);
+        let term = specialise(&program.term, &resource(&fft, &modulation)).unwrap();
+        assert_eq!(
+            term,
+            Term::Func(
+                ValueType::Float.into(),
+                "".into(),
+                Term::Apply(Term::Lib(Lib::Fft).into(), Term::Var(0).into()).into(),
+            )
+        );
+    }
+
+    #[test]
+    fn test_specialise_fold() {
+        let program = run("(i: Float) => (param(param(1) * 2) + beat * sec, 0)").unwrap();
+        let (fft, modulation) = (vec![], vec![0.0, 1.5, 0.0, 4.0]);
+        let term = specialise(&program.term, &resource(&fft, &modulation)).unwrap();
+        assert!(!mentions(&term, &Lib::Param));
+        assert_eq!(
+            term,
+            Term::Func(
+                ValueType::Float.into(),
+                "".into(),
+                Term::Tuple(vec![Term::Float(6.0), Term::Float(0.0)]).into(),
+            )
+        );
+    }
+
+    #[test]
+    fn test_specialise_symbolic_index() {
+        let program = run("(i: Float) => (param(i), 0)").unwrap();
+        let (fft, modulation) = (vec![], vec![0.3]);
+        let term = specialise(&program.term, &resource(&fft, &modulation)).unwrap();
+        assert_eq!(term, program.term);
+    }
+
+    #[test]
+    fn test_specialise_stft() {
+        let program = run("(i: Float) => (if hz(i) < nyquist / 2 then fft(i).norm else 0, 0)").unwrap();
+        let (fft, modulation) = (vec![], vec![]);
+        let term = specialise(&program.term, &resource(&fft, &modulation)).unwrap();
+        assert!(!mentions(&term, &Lib::Hz) && !mentions(&term, &Lib::Nyquist));
+    }
+
+    #[test]
+    fn test_specialise_random() {
+        // Random values change every frame even with constant arguments
+        let program = run("(i: Float) => (noise(3) + rand(param(0), 2), rand_phase(sec))").unwrap();
+        let (fft, modulation) = (vec![], vec![0.5]);
+        let term = specialise(&program.term, &resource(&fft, &modulation)).unwrap();
+        for lib in [Lib::Noise, Lib::Rand, Lib::RandPhase] {
+            assert!(mentions(&term, &lib));
+        }
+        assert!(!mentions(&term, &Lib::Param) && !mentions(&term, &Lib::Sec));
+    }
+
+    #[test]
+    fn test_specialise_transport() {
+        // The transport is read as it plays, even where `beat` is specialised on
+        let program = run("(i: Float) => (lfo_sin(4) * beat + step_seq(4, 1), tempo)").unwrap();
+        let (fft, modulation) = (vec![], vec![]);
+        let term = specialise(&program.term, &resource(&fft, &modulation)).unwrap();
+        for lib in [Lib::Lfo(Wave::Sin), Lib::StepSeq, Lib::Timing(Timing::Tempo)] {
+            assert!(mentions(&term, &lib));
+        }
+        assert!(!mentions(&term, &Lib::Beat));
+    }
+}
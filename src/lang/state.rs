@@ -0,0 +1,285 @@
+use std::cell::Cell;
+
+use realfft::num_complex::Complex32;
+
+use super::*;
+
+pub type StateError = String;
+
+/// Most stateful calls a program can make, each one keeps a value per bin
+pub const MAX_SLOTS: usize = 16;
+
+/// A library function keeping a value per bin between frames
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateOp {
+    /// `follow(attack, release, x)`, moves towards `x` with an attack or release time in seconds
+    Follow,
+    /// `slew(rate, x)`, moves towards `x` by at most `rate` per second
+    Slew,
+    /// `accum(decay, x)`, adds `x` to the sum so far, which fades by 60 dB every `decay` seconds
+    Accum,
+}
+
+impl StateOp {
+    /// Every operation, indexed by `StateOp as usize`
+    pub const ALL: [StateOp; 3] = [StateOp::Follow, StateOp::Slew, StateOp::Accum];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StateOp::Follow => "follow",
+            StateOp::Slew => "slew",
+            StateOp::Accum => "accum",
+        }
+    }
+
+    /// Number of parameters before `x`
+    pub fn params(&self) -> usize {
+        match self {
+            StateOp::Follow => 2,
+            StateOp::Slew | StateOp::Accum => 1,
+        }
+    }
+
+    /// The next value from the last value `y`, over a frame of `dt` seconds
+    pub fn step(&self, y: f32, params: [f32; 2], x: f32, dt: f32) -> f32 {
+        // Time constants of zero or less are instant
+        let fade = |seconds: f32, ratio: f32| {
+            if seconds > 0.0 {
+                ratio.powf(dt / seconds)
+            } else {
+                0.0
+            }
+        };
+        match self {
+            StateOp::Follow => {
+                let seconds = if x > y { params[0] } else { params[1] };
+                x + (y - x) * fade(seconds, std::f32::consts::E.recip())
+            }
+            StateOp::Slew => {
+                let max = params[0].abs() * dt;
+                y + (x - y).max(-max).min(max)
+            }
+            StateOp::Accum => y * fade(params[0], 0.001) + x,
+        }
+    }
+}
+
+/// Values of every stateful call at every bin, kept between frames. Values are cells so that
+/// programs can update them while only borrowing the resource.
+#[derive(Default)]
+pub struct State {
+    /// `MAX_SLOTS` rows of `max_bins` values
+    values: Vec<Cell<f32>>,
+    max_bins: usize,
+    /// The bin being evaluated
+    bin: Cell<usize>,
+    /// Whether the values were reset since the last `take_fresh`
+    fresh: Cell<bool>,
+    /// Phases of the input and of resynthesised bins, read by `inst_freq` and `synth`
+    phases: Phases,
+}
+
+impl State {
+    pub fn new(max_bins: usize) -> Self {
+        Self {
+            values: vec![Cell::new(0.0); MAX_SLOTS * max_bins],
+            max_bins,
+            bin: Cell::new(0),
+            fresh: Cell::new(true),
+            phases: Phases::new(max_bins),
+        }
+    }
+
+    pub fn max_bins(&self) -> usize {
+        self.max_bins
+    }
+
+    /// Start every call over from silence
+    pub fn reset(&self) {
+        for value in &self.values {
+            value.set(0.0);
+        }
+        self.fresh.set(true);
+        self.phases.reset();
+    }
+
+    /// Record the input of a frame that has just been processed
+    pub fn push(&self, spectrum: &[Complex32]) {
+        self.phases.push(spectrum);
+    }
+
+    pub fn phases(&self) -> &Phases {
+        &self.phases
+    }
+
+    /// Check if the values were reset since this was last called
+    pub fn take_fresh(&self) -> bool {
+        self.fresh.replace(false)
+    }
+
+    /// Value of a slot at a bin, `None` if either is out of range
+    pub fn get(&self, slot: usize, bin: usize) -> Option<f32> {
+        (slot < MAX_SLOTS && bin < self.max_bins).then(|| self.values[slot * self.max_bins + bin].get())
+    }
+
+    /// Set a slot at a bin, out of range slots and bins are ignored
+    pub fn set(&self, slot: usize, bin: usize, value: f32) {
+        if slot < MAX_SLOTS && bin < self.max_bins {
+            self.values[slot * self.max_bins + bin].set(value);
+        }
+    }
+
+    /// Set the bin that the following calls update
+    pub fn set_bin(&self, bin: usize) {
+        self.bin.set(bin);
+    }
+
+    pub fn bin(&self) -> usize {
+        self.bin.get()
+    }
+
+    /// Advance a call at the current bin, calls without a value are stateless and give `x`
+    pub fn step(&self, op: StateOp, slot: usize, params: [f32; 2], x: f32, dt: f32) -> f32 {
+        let bin = self.bin.get();
+        if slot >= MAX_SLOTS || bin >= self.max_bins {
+            return x;
+        }
+        let value = &self.values[slot * self.max_bins + bin];
+        let next = op.step(value.get(), params, x, dt);
+
+        // Don't let a non-finite value stick around
+        value.set(if next.is_finite() { next } else { 0.0 });
+        next
+    }
+}
+
+/// Advance a stateful call, without state it gives `x` as is
+pub fn state_step(state: Option<&State>, op: StateOp, slot: usize, params: [f32; 2], x: f32, dt: f32) -> f32 {
+    match state {
+        Some(state) => state.step(op, slot, params, x, dt),
+        None => x,
+    }
+}
+
+/// Give every stateful call of a simplified term its own slot, in order of appearance, after the
+/// slots `reserved` for the state of the program itself
+pub fn allocate_slots(term: Term, reserved: usize) -> Result<Term, StateError> {
+    let mut next = reserved;
+    let term = allocate(term, &mut next);
+    if next > MAX_SLOTS {
+        return Err(format!(
+            "{} stateful calls and {} values of program state, at most {} values are supported",
+            next - reserved,
+            reserved,
+            MAX_SLOTS,
+        ));
+    }
+    Ok(term)
+}
+
+fn allocate(term: Term, next: &mut usize) -> Term {
+    match term {
+        Term::Lib(Lib::State(op, _)) => {
+            *next += 1;
+            Term::Lib(Lib::State(op, *next - 1))
+        }
+        Term::Tuple(terms) => Term::Tuple(terms.into_iter().map(|t| allocate(t, next)).collect()),
+        Term::Apply(func, arg) => {
+            let func = allocate(*func, next);
+            Term::Apply(func.into(), allocate(*arg, next).into())
+        }
+        Term::Func(param_type, name, body) => Term::Func(param_type, name, allocate(*body, next).into()),
+        Term::Let(value_type, name, value, next_term) => {
+            let value = allocate(*value, next);
+            Term::Let(value_type, name, value.into(), allocate(*next_term, next).into())
+        }
+        Term::Alt(cond, then, else_) => {
+            let cond = allocate(*cond, next);
+            let then = allocate(*then, next);
+            Term::Alt(cond.into(), then.into(), allocate(*else_, next).into())
+        }
+        other => other,
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_state {
+    use super::*;
+
+    #[test]
+    fn test_state_ops() {
+        let state = State::new(4);
+        let step = |op, params, x| state.step(op, 0, params, x, 0.5);
+
+        // Follow rises by 1 - 1/e per attack time, and falls instantly without release time
+        let y = step(StateOp::Follow, [0.5, 0.0], 1.0);
+        assert!((y - (1.0 - std::f32::consts::E.recip())).abs() < 1e-6);
+        assert_eq!(step(StateOp::Follow, [0.5, 0.0], 0.25), 0.25);
+
+        // Slew moves by at most half the rate each half second
+        state.reset();
+        assert_eq!(step(StateOp::Slew, [1.0, 0.0], 2.0), 0.5);
+        assert_eq!(step(StateOp::Slew, [1.0, 0.0], 2.0), 1.0);
+        assert_eq!(step(StateOp::Slew, [1.0, 0.0], 0.75), 0.75);
+
+        // Accum fades by 60 dB over the decay time
+        state.reset();
+        assert_eq!(step(StateOp::Accum, [1.0, 0.0], 1.0), 1.0);
+        let y = step(StateOp::Accum, [1.0, 0.0], 0.0);
+        assert!((y - 0.001f32.sqrt()).abs() < 1e-6);
+        assert!(step(StateOp::Accum, [1.0, 0.0], f32::NAN).is_nan());
+        assert_eq!(step(StateOp::Accum, [1.0, 0.0], 0.0), 0.0);
+
+        // Every bin and slot is separate, and those out of range are stateless
+        state.set_bin(3);
+        assert_eq!(state.step(StateOp::Accum, 1, [1.0, 0.0], 2.0, 0.5), 2.0);
+        assert_eq!(state.step(StateOp::Accum, 2, [1.0, 0.0], 2.0, 0.5), 2.0);
+        assert!(state.step(StateOp::Accum, 1, [1.0, 0.0], 0.0, 0.5) > 0.0);
+        state.set_bin(4);
+        assert_eq!(state.step(StateOp::Accum, 1, [1.0, 0.0], 2.0, 0.5), 2.0);
+        assert_eq!(state.step(StateOp::Accum, 1, [1.0, 0.0], 2.0, 0.5), 2.0);
+        assert_eq!(state.step(StateOp::Accum, MAX_SLOTS, [1.0, 0.0], 2.0, 0.5), 2.0);
+    }
+
+    #[test]
+    fn test_allocate_slots() {
+        let code = "let f: Float -> Float = (x: Float) => follow(0.1, 0.2, x) in (i: Float) => (f(fft(i).norm), f(fft(i * 2).norm))";
+        let term = normalise(code).unwrap();
+        let mut slots = Vec::new();
+        collect_slots(&term, &mut slots);
+        assert_eq!(slots, vec![0, 1]);
+
+        // Program state comes first
+        let code = "{ init: (0, 0), step: (s: (Float, Float), i: Float) => (s, (accum(1, i), 0)) }";
+        let mut slots = Vec::new();
+        collect_slots(&normalise(code).unwrap(), &mut slots);
+        assert_eq!(slots, vec![2]);
+
+        let code = format!("(i: Float) => ({}0, 0)", "accum(1, i) + ".repeat(MAX_SLOTS + 1));
+        let err = normalise(&code).unwrap_err();
+        assert!(err.contains("stateful calls"), "{}", err);
+    }
+
+    fn collect_slots(term: &Term, slots: &mut Vec<usize>) {
+        match term {
+            Term::Lib(Lib::State(_, slot)) => slots.push(*slot),
+            Term::Tuple(terms) => terms.iter().for_each(|t| collect_slots(t, slots)),
+            Term::Apply(func, arg) => {
+                collect_slots(func, slots);
+                collect_slots(arg, slots);
+            }
+            Term::Func(_, _, body) => collect_slots(body, slots),
+            Term::Let(_, _, value, next) => {
+                collect_slots(value, slots);
+                collect_slots(next, slots);
+            }
+            Term::Alt(cond, then, else_) => {
+                collect_slots(cond, slots);
+                collect_slots(then, slots);
+                collect_slots(else_, slots);
+            }
+            _ => (),
+        }
+    }
+}
@@ -29,4 +29,34 @@ impl fmt::Display for Syntax {
             Syntax::Alt(cond, then, else_) => write!(f, "Syntax::Alt({}.into(), {}.into(), {}.into())", cond, then, else_),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Syntax {
+    /// Bind each component of `tuple` to a name around `body`, like `let (a, b): (A, B) = ...`
+    pub fn destructure(params: Vec<(String, ValueType)>, tuple: Syntax, body: Syntax) -> Syntax {
+        let (name, tuple_type, body) = bind_components(params, body);
+        Syntax::Let(tuple_type.into(), name, tuple.into(), body.into())
+    }
+
+    /// A function taking a tuple with each component bound to a name, like `(a: A, b: B) => ...`
+    pub fn func(params: Vec<(String, ValueType)>, body: Syntax) -> Syntax {
+        let (name, tuple_type, body) = bind_components(params, body);
+        Syntax::Func(tuple_type.into(), name, body.into())
+    }
+}
+
+/// Bind components of a tuple to names around `body`, giving the name the tuple must be bound to,
+/// which can't be written in code, and its type
+fn bind_components(params: Vec<(String, ValueType)>, body: Syntax) -> (String, ValueType, Syntax) {
+    let names: Vec<&str> = params.iter().map(|(name, _)| name.as_str()).collect();
+    let name = format!("({})", names.join(", "));
+    let types: Vec<ValueType> = params.iter().map(|(_, t)| t.clone()).collect();
+    let body = params.into_iter().enumerate().rev().fold(body, |body, (k, (n, t))| {
+        let nth = Syntax::Apply(
+            Syntax::Lib(Lib::Nth(k, types.clone())).into(),
+            Syntax::Var(name.clone()).into(),
+        );
+        Syntax::Let(t.into(), n, nth.into(), body.into())
+    });
+    (name, ValueType::Tuple(types), body)
+}
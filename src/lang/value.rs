@@ -8,15 +8,15 @@ pub struct Closure(pub Box<Term>, pub Env, pub String);
 
 impl Closure {
     /// Apply argument in evaluation.
-    pub fn apply(&mut self, arg: Value, res: &Resource) -> Value {
+    pub fn apply(&mut self, arg: Value, res: &Resource) -> Result<Value, EvalError> {
         self.1.push(arg);
-        let result = eval(&mut self.0, &mut self.1, res);
+        let result = eval(&self.0, &mut self.1, res);
         self.1.pop();
         result
     }
 
     /// Apply argument in partial evaluation.
-    pub fn papply(self, arg: Value) -> Value {
+    pub fn papply(self, arg: Value) -> Result<Value, EvalError> {
         let mut env = self.1;
         env.push(arg);
         peval_closure(*self.0, env)
@@ -62,6 +62,7 @@ impl Value {
         match self {
             Value::Var(_) => true,
             Value::Apply(_, _) => true,
+            Value::Alt(_, _, _) => true,
             Value::Tuple(xs) => xs.iter().any(|x| x.is_symbol()),
             Value::Lib(l) => l.is_symbol(),
             _ => false,
@@ -69,85 +70,96 @@ impl Value {
     }
 
     /// Apply an argument in evaluation.
-    pub fn apply(&mut self, arg: Value, res: &Resource) -> Value {
+    pub fn apply(&mut self, arg: Value, res: &Resource) -> Result<Value, EvalError> {
         match self {
             Value::Func(_, closure) => closure.apply(arg, res),
             Value::Lib(l) => l.apply(arg, res),
             Value::Apply(func, args) => {
                 let mut args = args.clone();
                 args.push(arg);
-                Value::Apply(func.clone(), args)
+                Ok(Value::Apply(func.clone(), args))
             }
-            other => Value::Apply((*other).clone().into(), vec![arg]),
+            other => Ok(Value::Apply((*other).clone().into(), vec![arg])),
         }
     }
 
     /// Apply an argument to a value in partial evaluation.
-    pub fn papply(self, arg: Value) -> Value {
+    pub fn papply(self, arg: Value) -> Result<Value, EvalError> {
         match self {
             Value::Func(_, closure) => closure.papply(arg),
             Value::Lib(l) => l.papply(arg),
             Value::Apply(func, mut args) => {
                 args.push(arg);
-                Value::Apply(func, args)
+                Ok(Value::Apply(func, args))
             }
-            other => Value::Apply(other.into(), vec![arg]),
+            other => Ok(Value::Apply(other.into(), vec![arg])),
         }
     }
 
     /// Treat a value as an array, collect its values at all indicies.
-    pub fn collect(mut self, range: impl Iterator<Item = usize>, res: &Resource) -> Vec<Value>
+    pub fn collect(mut self, range: impl Iterator<Item = usize>, res: &Resource) -> Result<Vec<Value>, EvalError>
     {
         let mut values = Vec::new();
         for i in range {
-            values.push(self.apply(Value::Int(i as i32), res));
+            if let Some(state) = res.state {
+                state.set_bin(i);
+            }
+            values.push(self.apply(Value::Int(i as i32), res)?);
         }
-        values
+        Ok(values)
     }
 }
 
-impl From<&Value> for f32 {
-    fn from(val: &Value) -> Self {
+impl TryFrom<&Value> for f32 {
+    type Error = EvalError;
+
+    fn try_from(val: &Value) -> Result<Self, EvalError> {
         match val {
-            Value::Float(x) => *x,
-            Value::Int(x) => *x as f32,
-            _ => panic!("{} is not a float", val),
+            Value::Float(x) => Ok(*x),
+            Value::Int(x) => Ok(*x as f32),
+            _ => Err(EvalError::NotFloat),
         }
     }
 }
 
-impl From<Value> for f32 {
-    fn from(val: Value) -> Self {
-        match val {
-            Value::Float(x) => x,
-            Value::Int(x) => x as f32,
-            _ => panic!("{} is not a float", val),
-        }
+impl TryFrom<Value> for f32 {
+    type Error = EvalError;
+
+    fn try_from(val: Value) -> Result<Self, EvalError> {
+        (&val).try_into()
     }
 }
 
-impl From<&Value> for Complex32 {
-    fn from(val: &Value) -> Self {
+impl TryFrom<&Value> for Complex32 {
+    type Error = EvalError;
+
+    fn try_from(val: &Value) -> Result<Self, EvalError> {
         match val {
-            Value::Tuple(xs) => {
-                let re: f32 = (&xs[0]).into();
-                let im: f32 = (&xs[1]).into();
-                Complex32::new(re, im)
+            Value::Tuple(xs) if xs.len() == 2 => {
+                let re: f32 = (&xs[0]).try_into().map_err(|_| EvalError::NotComplex)?;
+                let im: f32 = (&xs[1]).try_into().map_err(|_| EvalError::NotComplex)?;
+                Ok(Complex32::new(re, im))
             }
-            _ => panic!("{} is not a complex number", val),
+            _ => Err(EvalError::NotComplex),
         }
     }
 }
 
-impl From<Value> for Complex32 {
-    fn from(val: Value) -> Self {
-        match val {
-            Value::Tuple(mut xs) => {
-                let im: f32 = xs.pop().unwrap().into();
-                let re: f32 = xs.pop().unwrap().into();
-                Complex32::new(re, im)
-            }
-            _ => panic!("{} is not a complex number", val),
+impl TryFrom<Value> for Complex32 {
+    type Error = EvalError;
+
+    fn try_from(val: Value) -> Result<Self, EvalError> {
+        (&val).try_into()
+    }
+}
+
+impl TryFrom<Value> for (Complex32, Complex32) {
+    type Error = EvalError;
+
+    fn try_from(val: Value) -> Result<Self, EvalError> {
+        match &val {
+            Value::Tuple(xs) if xs.len() == 2 => Ok(((&xs[0]).try_into()?, (&xs[1]).try_into()?)),
+            _ => Err(EvalError::NotComplex),
         }
     }
 }
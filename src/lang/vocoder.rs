@@ -0,0 +1,215 @@
+use std::cell::Cell;
+use std::f32::consts::{PI, TAU};
+
+use realfft::num_complex::Complex32;
+
+use super::*;
+
+/// Phases kept for every bin between frames. The input phases measure how fast each bin turns,
+/// and the output phases keep resynthesised bins turning smoothly from frame to frame.
+#[derive(Default)]
+pub struct Phases {
+    /// Phase of the input on the last frame
+    input: Vec<Cell<f32>>,
+    /// Phase of the sinusoid each bin was synthesised as on the last frame, at the start of the
+    /// window
+    output: Vec<Cell<f32>>,
+    /// Phases given by `synth` on the current frame, which become `output` once it is pushed
+    next: Vec<Cell<f32>>,
+    /// Whether `input` holds a frame since the last reset
+    primed: Cell<bool>,
+}
+
+impl Phases {
+    pub fn new(max_bins: usize) -> Self {
+        Self {
+            input: vec![Cell::new(0.0); max_bins],
+            output: vec![Cell::new(0.0); max_bins],
+            next: vec![Cell::new(0.0); max_bins],
+            primed: Cell::new(false),
+        }
+    }
+
+    /// Forget every phase, the next frame has nothing to be measured against
+    pub fn reset(&self) {
+        for phase in self.input.iter().chain(&self.output).chain(&self.next) {
+            phase.set(0.0);
+        }
+        self.primed.set(false);
+    }
+
+    /// Record the input of a frame that has just been processed, which must not be longer than
+    /// the spectra the phases were allocated for
+    pub fn push(&self, spectrum: &[Complex32]) {
+        for ((input, value), (output, next)) in self
+            .input
+            .iter()
+            .zip(spectrum)
+            .zip(self.output.iter().zip(&self.next))
+        {
+            input.set(value.arg());
+            output.set(next.get());
+        }
+        self.primed.set(true);
+    }
+
+    /// Frequency of the input at bin `k`, in bins, from how far its phase turned since the last
+    /// frame. Only deviations of up to `window_size / hop / 2` bins from the centre can be told
+    /// apart, and every bin is at its centre before the first frame.
+    pub fn frequency(&self, fft: &[Complex32], k: usize, hop: usize, window_size: usize) -> f32 {
+        let (Some(value), Some(last)) = (fft.get(k), self.input.get(k)) else {
+            return k as f32;
+        };
+        if !self.primed.get() || hop == 0 || window_size == 0 {
+            return k as f32;
+        }
+
+        // The turn of the centre is taken modulo the window first to keep its precision
+        let expected = TAU * ((k * hop) % window_size) as f32 / window_size as f32;
+        let deviation = wrap(value.arg() - last.get() - expected);
+        k as f32 + deviation * window_size as f32 / (TAU * hop as f32)
+    }
+
+    /// Bin `k` of a sinusoid at `frequency` bins, turned on from where it was on the last frame.
+    /// Every bin locks to the peak its frequency points at, keeping the phase relation a Hann
+    /// window gives the bins around the peak of a sinusoid.
+    pub fn synth(
+        &self,
+        k: usize,
+        mag: f32,
+        frequency: f32,
+        hop: usize,
+        window_size: usize,
+    ) -> Complex32 {
+        let peak = frequency.round();
+        let last = match (peak >= 0.0).then(|| self.output.get(peak as usize)).flatten() {
+            Some(last) => last,
+            None => match self.output.get(k) {
+                Some(last) => last,
+                None => return Complex32::from_polar(mag, 0.0),
+            },
+        };
+        let turns = frequency * hop as f32 / window_size.max(1) as f32;
+        let phase = wrap(last.get() + TAU * turns.fract());
+        if !phase.is_finite() {
+            return Complex32::default();
+        }
+        if let Some(next) = self.next.get(k) {
+            next.set(phase);
+        }
+        Complex32::from_polar(mag, phase + PI * (frequency - k as f32))
+    }
+}
+
+/// Wrap a phase into `[-PI, PI)`
+pub fn wrap(phase: f32) -> f32 {
+    phase - TAU * ((phase + PI) / TAU).floor()
+}
+
+/// Magnitude of a spectrum at a fractional band, interpolating linearly between bands so that
+/// opposite phases of neighbouring bands don't cancel
+pub fn mag_at(fft: &[Complex32], f: f32) -> f32 {
+    let floor = f.floor() as usize;
+    let ceil = f.ceil() as usize;
+    if f < 0.0 || ceil >= fft.len() {
+        return 0.0;
+    }
+    let lower = fft[floor].norm();
+    lower + (fft[ceil].norm() - lower) * f.fract()
+}
+
+/// Frequency of a spectrum at a fractional band in bins, interpolating linearly between bands.
+/// Without phases, or outside of the spectrum, it is the band itself.
+pub fn inst_freq_at(
+    fft: &[Complex32],
+    phases: Option<&Phases>,
+    f: f32,
+    hop: usize,
+    window_size: usize,
+) -> f32 {
+    let ceil = f.ceil() as usize;
+    let Some(phases) = phases.filter(|_| f >= 0.0 && ceil < fft.len()) else {
+        return f;
+    };
+    let lower = phases.frequency(fft, f.floor() as usize, hop, window_size);
+    let upper = phases.frequency(fft, ceil, hop, window_size);
+    lower + (upper - lower) * f.fract()
+}
+
+/// Synthesise the bin being evaluated, without state every bin has a zero phase
+pub fn synth(state: Option<&State>, mag: f32, frequency: f32, hop: usize, window_size: usize) -> Complex32 {
+    match state {
+        Some(state) => state.phases().synth(state.bin(), mag, frequency, hop, window_size),
+        None => Complex32::from_polar(mag, 0.0),
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_vocoder {
+    use super::*;
+
+    /// Spectrum of a frame of a Hann-windowed sinusoid at `frequency` bins, starting at `start`
+    fn frame(frequency: f32, start: usize, window_size: usize) -> Vec<Complex32> {
+        (0..=window_size / 2)
+            .map(|k| {
+                (0..window_size)
+                    .map(|n| {
+                        let window = 0.5 - 0.5 * (TAU * n as f32 / window_size as f32).cos();
+                        let x = (TAU * frequency * (start + n) as f32 / window_size as f32).cos();
+                        let turn = -TAU * (k * n % window_size) as f32 / window_size as f32;
+                        Complex32::from_polar(window * x, turn)
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap(0.5), 0.5);
+        assert!((wrap(TAU + 0.5) - 0.5).abs() < 1e-5);
+        assert!((wrap(-PI - 0.5) - (PI - 0.5)).abs() < 1e-5);
+        assert_eq!(wrap(-PI), -PI);
+    }
+
+    #[test]
+    fn test_frequency() {
+        let (window_size, hop) = (64, 16);
+        let phases = Phases::new(window_size / 2 + 1);
+        let first = frame(10.3, 0, window_size);
+        assert_eq!(phases.frequency(&first, 10, hop, window_size), 10.0);
+        phases.push(&first);
+        let second = frame(10.3, hop, window_size);
+        for k in 9..=11 {
+            let frequency = phases.frequency(&second, k, hop, window_size);
+            assert!((frequency - 10.3).abs() < 1e-3, "bin {} at {}", k, frequency);
+        }
+        assert!((mag_at(&second, 10.5) - (second[10].norm() + second[11].norm()) * 0.5).abs() < 1e-3);
+        assert_eq!(inst_freq_at(&second, None, 10.5, hop, window_size), 10.5);
+        assert_eq!(inst_freq_at(&second, Some(&phases), 40.0, hop, window_size), 40.0);
+
+        // Forgetting the last frame goes back to the centres
+        phases.reset();
+        assert_eq!(phases.frequency(&second, 10, hop, window_size), 10.0);
+    }
+
+    #[test]
+    fn test_synth_locks_to_peak() {
+        let (window_size, hop) = (64, 16);
+        let phases = Phases::new(window_size / 2 + 1);
+        for start in [0, hop, hop * 2] {
+            let input = frame(10.3, start, window_size);
+            let output: Vec<Complex32> = (0..input.len())
+                .map(|k| phases.synth(k, input[k].norm(), 10.3, hop, window_size))
+                .collect();
+            phases.push(&input);
+
+            // Bins in the main lobe have the phases of an analysed sinusoid, up to a constant
+            let offset = output[10].arg() - input[10].arg();
+            for k in 9..=12 {
+                assert!(wrap(output[k].arg() - input[k].arg() - offset).abs() < 1e-2, "bin {}", k);
+            }
+        }
+    }
+}
@@ -1,14 +1,28 @@
 use constant::*;
+#[cfg(feature = "jit")]
+use lang::jit::{JitProgram, ResourceAbi};
+use lang::eval::Env;
 use lang::*;
 use nih_plug::prelude::*;
 use nih_plug_vizia::ViziaState;
+use envelope::Envelope;
+use hpss::Hpss;
 use realfft::{num_complex::Complex32, ComplexToReal, RealFftPlanner, RealToComplex};
+use safety::{decay_floor, sanitise, Limiter};
+use slot::Slot;
+use stft::Stft;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
 mod constant;
 mod editor;
+mod envelope;
+mod hpss;
 pub mod lang;
+mod safety;
+mod stft;
+mod slot;
 
 /// This is mostly identical to the gain example, minus some fluff, and with a GUI.
 pub struct DuskPhantom {
@@ -22,15 +36,181 @@ struct LocalState {
     /// between them without replanning or allocations. Initialized during `initialize()`.
     plan_for_order: Option<[Plan; MAX_WINDOW_ORDER - MIN_WINDOW_ORDER + 1]>,
 
-    /// The output of our real->complex FFT.
-    complex_fft_buffer: Vec<Complex32>,
+    /// The output of our real->complex FFT, for each channel.
+    spectra: [Vec<Complex32>; 2],
 
-    /// An adapter that performs most of the overlap-add algorithm for us.
-    stft: util::StftHelper,
+    /// The mid and side spectra of the current frame, only computed for stereo input.
+    mid_side: [Vec<Complex32>; 2],
+
+    /// The spectrum of the sidechain input summed to mono, silent when it isn't connected.
+    sidechain: Vec<Complex32>,
+
+    /// The output of the program for each evaluated channel, which can't be written to `spectra`
+    /// while the program is still reading from it.
+    outputs: [Vec<Complex32>; 2],
+
+    /// The environment the interpreter evaluates the program in, reused between frames.
+    env: Env,
+
+    /// Performs the overlap-add algorithm, giving us the windows of all channels at once.
+    stft: Stft,
 
     /// Contains a Hann window function of the current window length, passed to the overlap-add
     /// helper. Allocated with a `MAX_WINDOW_SIZE` initial capacity.
     window_function: Vec<f32>,
+
+    /// Modulation values of the current frame. Allocated with a `NUM_MODULATION` capacity.
+    modulation: Vec<f32>,
+
+    /// The gain of the current program if it only filters the spectrum.
+    mask: MaskCache,
+
+    /// What is kept between frames for each evaluated channel.
+    channels: [ChannelState; 2],
+
+    /// Estimates the envelope of each channel, with buffers allocated for `MAX_WINDOW_SIZE`.
+    envelope: Envelope,
+
+    /// The code generation the stateful calls of each channel were last used with, so that new
+    /// code starts from silence.
+    state_generation: u64,
+
+    /// Where the transport should be at the start of the next block while it plays, so that jumps
+    /// reset the stateful calls.
+    transport_position: Option<i64>,
+
+    /// Samples processed since the plugin was created, the position of the internal clock when
+    /// the host has no transport.
+    clock_samples: u64,
+
+    /// Number of frames processed, counted in hops from the transport position when playback
+    /// starts or jumps. Keys the random values of each frame.
+    frame: u64,
+
+    /// Keeps the output within the limiter ceiling after overlap-add. Recreated during
+    /// `initialize()` with the sample rate.
+    limiter: Limiter,
+
+    /// The sample rate of the host, set during `initialize()`.
+    sample_rate: f32,
+
+    /// The number of main input channels, which can be fewer than the output channels. Set during
+    /// `initialize()`.
+    num_inputs: usize,
+}
+
+/// The gain of a mask program, computed once and applied to every channel and frame.
+struct MaskCache {
+    /// Allocated with a `MAX_WINDOW_SIZE / 2 + 1` initial capacity.
+    gain: Vec<Complex32>,
+
+    /// The gain term and modulation values `gain` was computed with, it is recomputed only when
+    /// these or the window size change.
+    term: Option<Term>,
+    modulation: [f32; NUM_MODULATION],
+}
+
+/// The state of a channel the program is evaluated on, kept between frames.
+#[derive(Default)]
+struct ChannelState {
+    /// The input spectra of past frames, read by `hist`. Allocated for `MAX_HISTORY_FRAMES` frames
+    /// of `MAX_WINDOW_SIZE` during `initialize()`.
+    history: History,
+
+    /// The output of the previous frame, read by `out_prev`. Kept under the decay floor so that
+    /// feedback can't run away.
+    feedback: Vec<Complex32>,
+
+    /// The values of stateful calls, read by `follow`, `slew` and `accum`, the state of each bin
+    /// for programs with state, and the phases read by `inst_freq` and `synth`. Allocated for
+    /// `MAX_WINDOW_SIZE / 2 + 1` bins during `initialize()`.
+    state: State,
+
+    /// The spectral envelope of the current frame, read by `env` and `fine`. Only estimated for
+    /// programs reading it. Allocated with a `MAX_WINDOW_SIZE / 2 + 1` capacity.
+    envelope: Vec<f32>,
+
+    /// Magnitudes of recent frames, only kept for programs reading `harm` or `perc`. Allocated for
+    /// `MAX_HPSS_FRAMES` frames of `MAX_WINDOW_SIZE` during `initialize()`.
+    hpss: Hpss,
+
+    /// The fraction of each bin of the current frame that is harmonic, separated from `hpss`.
+    /// Allocated with a `MAX_WINDOW_SIZE / 2 + 1` capacity.
+    harmonic: Vec<f32>,
+
+    /// The magnitudes of the previous input, which `flux` is measured against. Allocated with a
+    /// `MAX_WINDOW_SIZE / 2 + 1` capacity.
+    magnitudes: Vec<f32>,
+
+    /// Detects onsets from how `magnitudes` rise, only kept for programs reading them.
+    onsets: OnsetDetector,
+}
+
+/// How the frames of every channel are analysed for the current program.
+struct Analysis<'a> {
+    program: &'a Program,
+    plan: &'a Plan,
+    envelope: &'a mut Envelope,
+    envelope_order: usize,
+    hpss_frames: usize,
+    hpss_bands: usize,
+    hop_seconds: f32,
+}
+
+impl ChannelState {
+    /// Add what the program can read about this channel to `frame`, the resource of its input,
+    /// estimating only what the program reads.
+    fn analyse<'a>(&'a mut self, frame: Resource<'a>, analysis: &mut Analysis) -> Resource<'a> {
+        let program = analysis.program;
+        if program.uses_envelope {
+            analysis.envelope.estimate(
+                frame.fft,
+                analysis.envelope_order,
+                &*analysis.plan.r2c_plan,
+                &*analysis.plan.c2r_plan,
+                &mut self.envelope,
+            );
+        }
+        if program.uses_hpss {
+            self.hpss.push(frame.fft);
+            self.hpss.separate(analysis.hpss_frames, analysis.hpss_bands, &mut self.harmonic);
+        }
+        let mut features = if program.uses_features {
+            Features::analyse(frame.fft, &self.magnitudes)
+        } else {
+            Features::default()
+        };
+        if program.uses_onset {
+            features.onset = self.onsets.detect(frame.fft, &self.magnitudes, analysis.hop_seconds);
+        }
+        Resource {
+            history: Some(&self.history),
+            feedback: &self.feedback,
+            state: Some(&self.state),
+            envelope: if program.uses_envelope { &self.envelope } else { &[] },
+            harmonic: if program.uses_hpss { &self.harmonic } else { &[] },
+            features,
+            ..frame
+        }
+    }
+
+    /// Remember the input and output of a frame once it has been evaluated, fading the feedback
+    /// by `decay`. Stateful calls have already stepped during evaluation.
+    fn record(
+        &mut self,
+        input: &[Complex32],
+        output: impl Iterator<Item = Complex32>,
+        decay: f32,
+    ) {
+        self.history.push(input);
+        self.state.push(input);
+        for (magnitude, input) in self.magnitudes.iter_mut().zip(input) {
+            *magnitude = input.norm();
+        }
+        for ((old, new), input) in self.feedback.iter_mut().zip(output).zip(input) {
+            *old = decay_floor(*old, new, *input, decay);
+        }
+    }
 }
 
 /// An FFT plan for a specific window size, all of which will be precomputed during initilaization.
@@ -45,7 +225,165 @@ struct PluginState {
     debug: Mutex<String>,
     profiler: Mutex<String>,
     message: Mutex<String>,
-    code_value: Mutex<Option<Value>>,
+    /// The current code, read by the audio thread without locking.
+    code_value: Slot<Program>,
+    /// Held off the audio thread while replacing `code_value` or `specialised`, so that a program
+    /// specialised on old code is never stored after new code.
+    code_lock: Mutex<()>,
+    /// Incremented whenever `code_value` changes, while holding `code_lock`.
+    code_generation: AtomicU64,
+    /// The current code specialised on recent parameter values, used in staged mode.
+    specialised: Slot<Specialised>,
+    /// Set while a specialisation task is queued, so that at most one is in flight.
+    specialising: AtomicBool,
+    /// Set when the current code fails at runtime, the plugin passes audio through until the code
+    /// is compiled again.
+    bypassed: AtomicBool,
+    /// Set when the safety stage changed the output, until the code is compiled again.
+    sanitised: AtomicBool,
+    #[cfg(feature = "jit")]
+    jit: Arc<JitSlot>,
+}
+
+/// Values that a program is specialised on, captured on the audio thread.
+#[derive(Clone, Copy, Debug)]
+pub struct Snapshot {
+    modulation: [f32; NUM_MODULATION],
+    beat: f64,
+    second: f64,
+    /// The rest of the transport, which programs read as it plays rather than being specialised
+    /// on it
+    clock: Clock,
+    sample_rate: f32,
+    window_size: usize,
+    overlap: usize,
+}
+
+impl Snapshot {
+    fn new(
+        modulation: &ModParams,
+        transport: &Transport,
+        clock_samples: u64,
+        sample_rate: f32,
+        window_size: usize,
+        overlap: usize,
+    ) -> Self {
+        // Hosts without a position get the internal clock, at their tempo if they have one
+        let tempo = transport.tempo.unwrap_or(DEFAULT_TEMPO);
+        let second = transport
+            .pos_seconds()
+            .unwrap_or(clock_samples as f64 / sample_rate as f64);
+        let beat = transport.pos_beats().unwrap_or(second * tempo / 60.0);
+        let mut clock = Clock::new(
+            tempo,
+            transport.playing,
+            transport.time_sig_numerator.unwrap_or(4),
+            transport.time_sig_denominator.unwrap_or(4),
+            beat,
+        );
+        if let (Some(bar), Some(bar_start)) =
+            (transport.bar_number(), transport.bar_start_pos_beats())
+        {
+            (clock.bar, clock.bar_start) = (bar, bar_start);
+        }
+        clock.loop_range = transport.loop_range_beats();
+        Self {
+            modulation: modulation.to_array(),
+            beat,
+            second,
+            clock,
+            sample_rate,
+            window_size,
+            overlap,
+        }
+    }
+}
+
+/// A program with `param`, `beat` and `sec` replaced by the values in `snapshot`.
+struct Specialised {
+    snapshot: Snapshot,
+    program: Program,
+    /// Whether the generic program depends on each kind of value, others can drift freely.
+    uses_param: bool,
+    uses_beat: bool,
+    uses_second: bool,
+}
+
+impl Specialised {
+    /// Check if the program is still accurate for the values in `snapshot`.
+    fn is_fresh(&self, snapshot: &Snapshot) -> bool {
+        let params = !self.uses_param
+            || self
+                .snapshot
+                .modulation
+                .iter()
+                .zip(snapshot.modulation.iter())
+                .all(|(a, b)| (a - b).abs() <= SPECIALISE_PARAM_TOLERANCE);
+        let beat =
+            !self.uses_beat || (self.snapshot.beat - snapshot.beat).abs() <= SPECIALISE_BEAT_TOLERANCE;
+        let second = !self.uses_second
+            || (self.snapshot.second - snapshot.second).abs() <= SPECIALISE_SECOND_TOLERANCE;
+        let stft = self.snapshot.sample_rate == snapshot.sample_rate
+            && self.snapshot.window_size == snapshot.window_size
+            && self.snapshot.overlap == snapshot.overlap;
+        params && beat && second && stft
+    }
+}
+
+/// Work done off the audio thread.
+pub enum Task {
+    /// Specialise the current code on the values of the snapshot.
+    Specialise(Snapshot),
+    /// Post a runtime error of the code in a profile.
+    Report { profile: i32, error: EvalError },
+}
+
+/// Natively compiled version of the current code, swapped in once a background compilation
+/// finishes. The interpreter is used until then, or if the code can't be compiled.
+#[cfg(feature = "jit")]
+#[derive(Default)]
+struct JitSlot {
+    program: Slot<JitProgram>,
+    /// Incremented on every compilation so that stale results are discarded.
+    generation: AtomicU64,
+    status: Mutex<String>,
+}
+
+#[cfg(feature = "jit")]
+impl JitSlot {
+    /// Compile `program` on a background thread, falling back to the interpreter meanwhile.
+    pub fn compile(self: &Arc<Self>, program: Option<Program>) {
+        let generation = {
+            let mut status = self.status.lock().unwrap();
+            *status = match &program {
+                Some(program) if program.mask.is_some() => "JIT: not needed for a mask".into(),
+                Some(_) => "JIT: compiling".into(),
+                None => "".into(),
+            };
+            self.program.store(None);
+            self.generation.fetch_add(1, Ordering::SeqCst) + 1
+        };
+
+        // Masks are only evaluated when their gain changes, which the interpreter does well enough
+        let Some(program) = program.filter(|program| program.mask.is_none()) else {
+            return;
+        };
+        let slot = self.clone();
+        std::thread::spawn(move || {
+            let result = JitProgram::compile(&program);
+            let mut status = slot.status.lock().unwrap();
+            if slot.generation.load(Ordering::SeqCst) != generation {
+                return;
+            }
+            *status = match result {
+                Ok(program) => {
+                    slot.program.store(Some(program));
+                    "JIT: running native code".into()
+                }
+                Err(err) => format!("JIT: using interpreter, {}", err),
+            };
+        });
+    }
 }
 
 impl PluginState {
@@ -83,16 +421,119 @@ impl PluginState {
     pub fn compile_code(&self, code_str: String) {
         // Evaluate and simplify code as a function
         let (msg, code) = match run(&code_str) {
-            Ok(val) => (
-                format!("Compilation success: {}", val.pretty_term()),
-                Some(val),
+            Ok(program) if program.prologue.is_empty() => (
+                format!(
+                    "Compilation success ({} output): {}",
+                    program.output.name(),
+                    program.pretty_term(),
+                ),
+                Some(program),
+            ),
+            Ok(program) => (
+                format!(
+                    "Compilation success ({} output): {}, hoisted: {}",
+                    program.output.name(),
+                    program.pretty_term(),
+                    program.pretty_prologue(),
+                ),
+                Some(program),
             ),
             Err(err) => (err, None),
         };
 
-        // Put message and code in memory
+        // Compile code natively in the background
+        #[cfg(feature = "jit")]
+        self.jit.compile(code.clone());
+
+        // Put message and code in memory, dropping the program specialised on the old code
         *self.message.lock().unwrap() = msg;
-        *self.code_value.lock().unwrap() = code;
+        let _code_lock = self.code_lock.lock().unwrap();
+        self.code_value.store(code);
+        self.code_generation.fetch_add(1, Ordering::SeqCst);
+        self.specialised.store(None);
+        self.bypassed.store(false, Ordering::SeqCst);
+        self.sanitised.store(false, Ordering::SeqCst);
+    }
+
+    /// Tell the user why the code in `profile` is bypassed.
+    pub fn report(&self, profile: i32, error: EvalError) {
+        *self.message.lock().unwrap() = format!(
+            "Runtime error in profile {}: {}, bypassing until the code is updated",
+            profile, error
+        );
+    }
+
+    /// Check if the specialised program can still be used for the values in `snapshot`.
+    fn is_specialised_on(&self, snapshot: &Snapshot) -> bool {
+        match &*self.specialised.load() {
+            Some(specialised) => specialised.is_fresh(snapshot),
+            None => false,
+        }
+    }
+
+    /// Partially evaluate the current code again with the values in `snapshot`.
+    pub fn specialise(&self, snapshot: Snapshot) {
+        let (generation, code) = {
+            let _code_lock = self.code_lock.lock().unwrap();
+            (
+                self.code_generation.load(Ordering::SeqCst),
+                self.code_value.load_full(),
+            )
+        };
+        if let Some(code) = code {
+            let fft = Vec::new();
+            let modulation = snapshot.modulation.to_vec();
+            let res = Resource {
+                beat: snapshot.beat,
+                second: snapshot.second,
+                clock: snapshot.clock,
+                sample_rate: snapshot.sample_rate,
+                window_size: snapshot.window_size,
+                overlap: snapshot.overlap,
+                ..Resource::new(&fft, &modulation)
+            };
+            let term = match specialise(&code.term, &res) {
+                Ok(term) => term,
+                Err(err) => {
+                    *self.message.lock().unwrap() = format!("Specialisation error: {}", err);
+                    self.specialising.store(false, Ordering::SeqCst);
+                    return;
+                }
+            };
+            let specialised = Specialised {
+                snapshot,
+                program: Program::new(term),
+                uses_param: mentions(&code.term, &Lib::Param),
+                uses_beat: mentions(&code.term, &Lib::Beat),
+                uses_second: mentions(&code.term, &Lib::Sec),
+            };
+
+            // Discard the result if the code changed in the meantime
+            let _code_lock = self.code_lock.lock().unwrap();
+            if self.code_generation.load(Ordering::SeqCst) == generation {
+                self.specialised.store(Some(specialised));
+            }
+        }
+        self.specialising.store(false, Ordering::SeqCst);
+    }
+
+    /// Evaluate the natively compiled code into `out` if there is any. Unlike the interpreter
+    /// this never reports an error, so code that overflows at runtime is only bypassed when it
+    /// isn't compiled.
+    #[cfg(feature = "jit")]
+    fn collect_native(&self, res: &Resource, out: &mut [Complex32]) -> bool {
+        match &*self.jit.program.load() {
+            Some(program) => {
+                program.collect_into(&ResourceAbi::new(res), out);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn collect_native(&self, _res: &Resource, _out: &mut [Complex32]) -> bool {
+        false
     }
 }
 
@@ -117,6 +558,10 @@ struct PluginParams {
     /// Modulation parameters.
     #[nested(group = "modulation")]
     pub modulation: Arc<ModParams>,
+
+    /// Output safety parameters.
+    #[nested(group = "safety")]
+    pub safety: Arc<SafetyParams>,
 }
 
 #[derive(Params)]
@@ -133,6 +578,66 @@ pub struct GlobalParams {
     /// The profile to use.
     #[id = "profile"]
     pub profile: IntParam,
+
+    /// Whether to run the code specialised on the current modulation and transport values.
+    #[id = "staged"]
+    pub staged: BoolParam,
+
+    /// How the channels of stereo input are given to the code.
+    #[id = "stereo_mode"]
+    pub stereo_mode: EnumParam<StereoMode>,
+
+    /// How many past frames the code can read with `hist`.
+    #[id = "history"]
+    pub history_frames: IntParam,
+
+    /// How many cepstral coefficients the envelope read by `env` and `fine` keeps, fewer give a
+    /// smoother envelope.
+    #[id = "envelope_order"]
+    pub envelope_order: IntParam,
+
+    /// How many frames the median finding harmonic content in `harm` runs over.
+    #[id = "hpss_frames"]
+    pub hpss_frames: IntParam,
+
+    /// How many bands the median finding percussive content in `perc` runs over.
+    #[id = "hpss_bands"]
+    pub hpss_bands: IntParam,
+
+    /// Keys the values of `noise`, `rand_phase` and `rand`, the same seed gives the same values
+    /// from the same transport position.
+    #[id = "seed"]
+    pub seed: IntParam,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq)]
+pub enum StereoMode {
+    /// Run the code on the left and right channels separately.
+    #[name = "Independent L/R"]
+    Independent,
+
+    /// Run the code on the mid channel, and apply its change to both channels.
+    #[name = "Linked"]
+    Linked,
+
+    /// Run the code on the mid and side channels separately.
+    #[name = "Mid/Side"]
+    MidSide,
+}
+
+#[derive(Params)]
+pub struct SafetyParams {
+    /// Whether to sanitise bins and limit the output.
+    #[id = "safety"]
+    pub enabled: BoolParam,
+
+    /// The largest magnitude of a bin, in decibels relative to a full scale sine wave.
+    #[id = "bin_ceiling"]
+    pub bin_ceiling: FloatParam,
+
+    /// The largest sample the limiter lets through, in decibels.
+    #[id = "limiter_ceiling"]
+    pub limiter_ceiling: FloatParam,
 }
 
 #[derive(Params)]
@@ -176,16 +681,49 @@ impl Default for DuskPhantom {
         Self {
             params: PluginParams::default().into(),
             local_state: LocalState {
-                stft: util::StftHelper::new(2, MAX_WINDOW_SIZE, 0),
+                stft: Stft::new(2, 2, MAX_WINDOW_SIZE),
                 plan_for_order: None,
                 window_function: Vec::with_capacity(MAX_WINDOW_SIZE),
-                complex_fft_buffer: Vec::with_capacity(MAX_WINDOW_SIZE / 2 + 1),
+                modulation: Vec::with_capacity(NUM_MODULATION),
+                mask: MaskCache {
+                    gain: Vec::with_capacity(MAX_WINDOW_SIZE / 2 + 1),
+                    term: None,
+                    modulation: [0.0; NUM_MODULATION],
+                },
+                channels: std::array::from_fn(|_| ChannelState {
+                    feedback: Vec::with_capacity(MAX_WINDOW_SIZE / 2 + 1),
+                    envelope: Vec::with_capacity(MAX_WINDOW_SIZE / 2 + 1),
+                    harmonic: Vec::with_capacity(MAX_WINDOW_SIZE / 2 + 1),
+                    magnitudes: Vec::with_capacity(MAX_WINDOW_SIZE / 2 + 1),
+                    ..Default::default()
+                }),
+                envelope: Envelope::new(MAX_WINDOW_SIZE),
+                state_generation: 0,
+                transport_position: None,
+                clock_samples: 0,
+                frame: 0,
+                limiter: Limiter::new(LIMITER_RELEASE_SECONDS, 44100.0),
+                sample_rate: 44100.0,
+                num_inputs: 2,
+                spectra: std::array::from_fn(|_| Vec::with_capacity(MAX_WINDOW_SIZE / 2 + 1)),
+                mid_side: std::array::from_fn(|_| Vec::with_capacity(MAX_WINDOW_SIZE / 2 + 1)),
+                sidechain: Vec::with_capacity(MAX_WINDOW_SIZE / 2 + 1),
+                outputs: std::array::from_fn(|_| Vec::with_capacity(MAX_WINDOW_SIZE / 2 + 1)),
+                env: Env::new(),
             },
             plugin_state: PluginState {
                 debug: Mutex::new("".into()),
                 profiler: Mutex::new("".into()),
                 message: Mutex::new("".into()),
-                code_value: Mutex::new(None),
+                code_value: Slot::default(),
+                code_lock: Mutex::new(()),
+                code_generation: AtomicU64::new(0),
+                specialised: Slot::default(),
+                specialising: AtomicBool::new(false),
+                bypassed: AtomicBool::new(false),
+                sanitised: AtomicBool::new(false),
+                #[cfg(feature = "jit")]
+                jit: Arc::new(JitSlot::default()),
             }
             .into(),
         }
@@ -199,6 +737,7 @@ impl Default for PluginParams {
             code: Arc::new(Mutex::new(DEFAULT_CODE.into())),
             global: Arc::new(GlobalParams::default()),
             modulation: Arc::new(ModParams::default()),
+            safety: Arc::new(SafetyParams::default()),
         }
     }
 }
@@ -227,6 +766,74 @@ impl Default for GlobalParams {
             .with_value_to_string(formatters::v2s_i32_power_of_two())
             .with_string_to_value(formatters::s2v_i32_power_of_two()),
             profile: IntParam::new("Profile", 1, IntRange::Linear { min: 1, max: 16 }),
+            staged: BoolParam::new("Staged", false),
+            stereo_mode: EnumParam::new("Stereo Mode", StereoMode::Independent),
+            history_frames: IntParam::new(
+                "History",
+                DEFAULT_HISTORY_FRAMES as i32,
+                IntRange::Linear {
+                    min: 0,
+                    max: MAX_HISTORY_FRAMES as i32,
+                },
+            ),
+            envelope_order: IntParam::new(
+                "Envelope Order",
+                DEFAULT_ENVELOPE_ORDER as i32,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_ENVELOPE_ORDER as i32,
+                },
+            ),
+            hpss_frames: IntParam::new(
+                "HPSS Frames",
+                DEFAULT_HPSS_FRAMES as i32,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_HPSS_FRAMES as i32,
+                },
+            ),
+            hpss_bands: IntParam::new(
+                "HPSS Bands",
+                DEFAULT_HPSS_BANDS as i32,
+                IntRange::Linear {
+                    min: 1,
+                    max: MAX_HPSS_BANDS as i32,
+                },
+            ),
+            seed: IntParam::new(
+                "Seed",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: MAX_SEED as i32,
+                },
+            ),
+        }
+    }
+}
+
+impl Default for SafetyParams {
+    fn default() -> Self {
+        SafetyParams {
+            enabled: BoolParam::new("Safety", true),
+            bin_ceiling: FloatParam::new(
+                "Bin Ceiling",
+                12.0,
+                FloatRange::Linear {
+                    min: -24.0,
+                    max: 48.0,
+                },
+            )
+            .with_unit(" dB"),
+            limiter_ceiling: FloatParam::new(
+                "Limiter Ceiling",
+                -1.0,
+                FloatRange::Linear {
+                    min: -24.0,
+                    max: 0.0,
+                },
+            )
+            .with_unit(" dB"),
         }
     }
 }
@@ -367,8 +974,8 @@ impl Default for ModParams {
 }
 
 impl ModParams {
-    fn to_vec(&self) -> Vec<f32> {
-        vec![
+    fn to_array(&self) -> [f32; NUM_MODULATION] {
+        [
             self.mod1.value(),
             self.mod2.value(),
             self.mod3.value(),
@@ -406,10 +1013,81 @@ impl DuskPhantom {
         self.local_state.stft.set_block_size(window_size);
         self.local_state.window_function.resize(window_size, 0.0);
         util::window::hann_in_place(&mut self.local_state.window_function);
+        for buffer in self
+            .local_state
+            .spectra
+            .iter_mut()
+            .chain(&mut self.local_state.mid_side)
+            .chain(&mut self.local_state.outputs)
+            .chain([&mut self.local_state.sidechain])
+        {
+            buffer.resize(window_size / 2 + 1, Complex32::default());
+        }
         self.local_state
-            .complex_fft_buffer
+            .mask
+            .gain
             .resize(window_size / 2 + 1, Complex32::default());
+        self.local_state.mask.term = None;
+
+        // Past frames of another window size can't be read anymore
+        for channel in &mut self.local_state.channels {
+            channel.history.clear();
+            channel.feedback.clear();
+            channel.feedback.resize(window_size / 2 + 1, Complex32::default());
+            channel.state.reset();
+            channel.envelope.resize(window_size / 2 + 1, 0.0);
+            channel.harmonic.resize(window_size / 2 + 1, 1.0);
+            channel.hpss.clear();
+            channel.magnitudes.clear();
+            channel.magnitudes.resize(window_size / 2 + 1, 0.0);
+            channel.onsets.clear();
+        }
+    }
+
+    /// How long the output of `program` can keep going once the input stops, in samples.
+    fn tail_samples(&self, program: &Program, window_size: usize, overlap_times: usize) -> u32 {
+        // Each frame comes out one window late and spreads over another window
+        let mut tail = self.local_state.stft.latency_samples() as usize + window_size;
+        if program.uses_history {
+            let hop = window_size / overlap_times;
+            tail += self.params.global.history_frames.value() as usize * hop;
+        }
+        if program.uses_feedback {
+            tail += (FEEDBACK_DECAY_SECONDS * self.local_state.sample_rate) as usize;
+        }
+        tail as u32
+    }
+}
+
+/// Evaluate a program on the spectrum of `res` into `out`, using the cached gain for masks and
+/// native code when `native` allows it and the program has been compiled.
+fn evaluate(
+    program: &Program,
+    res: &Resource,
+    native: bool,
+    cache: &mut MaskCache,
+    env: &mut Env,
+    plugin_state: &PluginState,
+    out: &mut [Complex32],
+) -> Result<(), EvalError> {
+    if let Some(mask) = &program.mask {
+        let stale = cache.term.as_ref() != Some(&mask.gain.term)
+            || mask.is_affected(&cache.modulation, res.modulation);
+        if stale {
+            cache.term = None;
+            mask.compute(env, res, &mut cache.gain)?;
+            cache.term = Some(mask.gain.term.clone());
+            cache.modulation.copy_from_slice(res.modulation);
+        }
+        for ((complex, input), gain) in out.iter_mut().zip(res.fft).zip(&cache.gain) {
+            *complex = input * gain;
+        }
+        return Ok(());
+    }
+    if native && plugin_state.collect_native(res, out) {
+        return Ok(());
     }
+    program.collect_into(env, res, out)
 }
 
 impl Plugin for DuskPhantom {
@@ -424,11 +1102,31 @@ impl Plugin for DuskPhantom {
         AudioIOLayout {
             main_input_channels: NonZeroU32::new(2),
             main_output_channels: NonZeroU32::new(2),
+            aux_input_ports: &[new_nonzero_u32(2)],
+            names: PortNames {
+                aux_inputs: &["Sidechain"],
+                ..PortNames::const_default()
+            },
             ..AudioIOLayout::const_default()
         },
         AudioIOLayout {
             main_input_channels: NonZeroU32::new(1),
             main_output_channels: NonZeroU32::new(1),
+            aux_input_ports: &[new_nonzero_u32(2)],
+            names: PortNames {
+                aux_inputs: &["Sidechain"],
+                ..PortNames::const_default()
+            },
+            ..AudioIOLayout::const_default()
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(1),
+            main_output_channels: NonZeroU32::new(2),
+            aux_input_ports: &[new_nonzero_u32(2)],
+            names: PortNames {
+                aux_inputs: &["Sidechain"],
+                ..PortNames::const_default()
+            },
             ..AudioIOLayout::const_default()
         },
     ];
@@ -436,7 +1134,7 @@ impl Plugin for DuskPhantom {
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     type SysExMessage = ();
-    type BackgroundTask = ();
+    type BackgroundTask = Task;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
@@ -450,10 +1148,18 @@ impl Plugin for DuskPhantom {
         )
     }
 
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let plugin_state = self.plugin_state.clone();
+        Box::new(move |task| match task {
+            Task::Specialise(snapshot) => plugin_state.specialise(snapshot),
+            Task::Report { profile, error } => plugin_state.report(profile, error),
+        })
+    }
+
     fn initialize(
         &mut self,
         audio_io_layout: &AudioIOLayout,
-        _buffer_config: &BufferConfig,
+        buffer_config: &BufferConfig,
         context: &mut impl InitContext<Self>,
     ) -> bool {
         // Initialize code
@@ -466,9 +1172,11 @@ impl Plugin for DuskPhantom {
             .expect("Plugin does not have a main output")
             .get() as usize;
         if self.local_state.stft.num_channels() != num_output_channels {
-            self.local_state.stft =
-                util::StftHelper::new(self.local_state.stft.num_channels(), MAX_WINDOW_SIZE, 0);
+            self.local_state.stft = Stft::new(num_output_channels, 2, MAX_WINDOW_SIZE);
         }
+        self.local_state.num_inputs = audio_io_layout
+            .main_input_channels
+            .map_or(0, |channels| channels.get() as usize);
 
         // Planning with RustFFT is very fast, but it will still allocate we we'll plan all of the
         // FFTs we might need in advance
@@ -487,10 +1195,31 @@ impl Plugin for DuskPhantom {
             );
         }
 
+        // The history is large, so it is only allocated once the plugin is actually used
+        if self.local_state.channels[0].history.max_frames() == 0 {
+            for channel in &mut self.local_state.channels {
+                channel.history = History::new(MAX_HISTORY_FRAMES, MAX_WINDOW_SIZE / 2 + 1);
+            }
+        }
+        if self.local_state.channels[0].hpss.max_frames() == 0 {
+            for channel in &mut self.local_state.channels {
+                channel.hpss = Hpss::new(MAX_HPSS_FRAMES, MAX_HPSS_BANDS, MAX_WINDOW_SIZE / 2 + 1);
+            }
+        }
+        if self.local_state.channels[0].state.max_bins() == 0 {
+            for channel in &mut self.local_state.channels {
+                channel.state = State::new(MAX_WINDOW_SIZE / 2 + 1);
+            }
+        }
+
         // Resize the window function and the FFT buffer to the new window size
         let window_size = self.window_size();
         self.resize_for_window(window_size);
 
+        // Programs can read the sample rate, and the limiter releases over a fixed time
+        self.local_state.sample_rate = buffer_config.sample_rate;
+        self.local_state.limiter = Limiter::new(LIMITER_RELEASE_SECONDS, buffer_config.sample_rate);
+
         // Set the latency to the STFT latency
         context.set_latency_samples(self.local_state.stft.latency_samples());
 
@@ -501,11 +1230,13 @@ impl Plugin for DuskPhantom {
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
+        aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        // Bypass if there is no code
-        if self.plugin_state.code_value.lock().unwrap().is_none() {
+        // Bypass if there is no code, or the code failed at runtime
+        if self.plugin_state.code_value.load().is_none()
+            || self.plugin_state.bypassed.load(Ordering::SeqCst)
+        {
             return ProcessStatus::Normal;
         };
 
@@ -518,6 +1249,34 @@ impl Plugin for DuskPhantom {
             context.set_latency_samples(self.local_state.stft.latency_samples());
         }
 
+        // Changing the number of past frames forgets them, but never allocates
+        let history_frames = self.params.global.history_frames.value() as usize;
+        for channel in &mut self.local_state.channels {
+            if channel.history.depth() != history_frames {
+                channel.history.set_depth(history_frames);
+            }
+        }
+
+        // Stateful calls and the frames harmonic content and onsets are found in start over with
+        // new code, and when playback starts or jumps
+        let generation = self.plugin_state.code_generation.load(Ordering::SeqCst);
+        let transport = context.transport();
+        let position = transport.pos_samples().filter(|_| transport.playing);
+        let jumped = position.is_some() && position != self.local_state.transport_position;
+        self.local_state.transport_position =
+            position.map(|position| position + buffer.samples() as i64);
+        if let Some(position) = position.filter(|_| jumped) {
+            self.local_state.frame = position.max(0) as u64 / (window_size / overlap_times) as u64;
+        }
+        if jumped || generation != self.local_state.state_generation {
+            self.local_state.state_generation = generation;
+            for channel in &mut self.local_state.channels {
+                channel.state.reset();
+                channel.hpss.clear();
+                channel.onsets.clear();
+            }
+        }
+
         // These plans have already been made during initialization we can switch between versions
         // without reallocating
         let fft_plan = &mut self.local_state.plan_for_order.as_mut().unwrap()
@@ -534,95 +1293,303 @@ impl Plugin for DuskPhantom {
         let input_gain = gain_compensation.sqrt();
         let output_gain = gain_compensation.sqrt();
 
+        // A full scale sine wave peaks at half the window sum after the windowed forward FFT, which
+        // is where the bin ceiling is measured from
+        let safety = self.params.safety.enabled.value();
+        let bin_ceiling = util::db_to_gain(self.params.safety.bin_ceiling.value())
+            * window_size as f32
+            * 0.25
+            * input_gain;
+        let limiter_ceiling = util::db_to_gain(self.params.safety.limiter_ceiling.value());
+
+        let envelope_order = self.params.global.envelope_order.value() as usize;
+        let hpss_frames = self.params.global.hpss_frames.value() as usize;
+        let hpss_bands = self.params.global.hpss_bands.value() as usize;
+        let seed = self.params.global.seed.value() as u32;
+
+        // Feedback fades by at least 60 dB over the decay time, frame by frame
+        let hop = window_size / overlap_times;
+        let decay = util::db_to_gain(
+            -60.0 * hop as f32 / (FEEDBACK_DECAY_SECONDS * self.local_state.sample_rate),
+        );
+        let hop_seconds = hop as f32 / self.local_state.sample_rate;
+
+        // In staged mode, ask for the code to be specialised again once the values it depends on
+        // have drifted. The last specialised program keeps running until the new one arrives.
+        let staged = self.params.global.staged.value();
+        let snapshot = Snapshot::new(
+            &self.params.modulation,
+            context.transport(),
+            self.local_state.clock_samples,
+            self.local_state.sample_rate,
+            window_size,
+            overlap_times,
+        );
+        self.local_state.clock_samples += buffer.samples() as u64;
+        if staged
+            && !self.plugin_state.is_specialised_on(&snapshot)
+            && !self.plugin_state.specialising.swap(true, Ordering::SeqCst)
+        {
+            context.execute_background(Task::Specialise(snapshot));
+        }
+        self.local_state.modulation.clear();
+        self.local_state
+            .modulation
+            .extend_from_slice(&snapshot.modulation);
+
+        // Mono input has nothing to link or split
+        let num_inputs = self.local_state.num_inputs;
+        let stereo_mode = match num_inputs {
+            2 => self.params.global.stereo_mode.value(),
+            _ => StereoMode::Independent,
+        };
+
+        // The first runtime error of this block, frames after it are left unprocessed
+        let mut error = None;
+        let sidechain = aux
+            .inputs
+            .first()
+            .map_or(&[][..], |input| input.as_slice_immutable());
         self.local_state.stft.process_overlap_add(
-            buffer,
+            buffer.as_slice(),
+            sidechain,
             overlap_times,
-            |_channel_idx, real_fft_buffer| {
-                // Get the code value again in case it changed during the last process call
+            |windows, sidechain_windows| {
+                // Get the code value again in case it changed during the last process call,
+                // preferring the specialised program which can be read without locking
                 let profile_0 = std::time::Instant::now();
-                let Some(code_value) = self.plugin_state.code_value.lock().unwrap().clone() else {
-                    return;
+                let specialised = self.plugin_state.specialised.load();
+                let generic = self.plugin_state.code_value.load();
+                let code_value = match (&*specialised, &*generic) {
+                    (Some(specialised), _) if staged => &specialised.program,
+                    (_, Some(generic)) => generic,
+                    (_, None) => return,
                 };
 
-                // We'll window the input with a Hann function to avoid spectral leakage. The input gain
-                // here also contains a compensation factor for the forward FFT to make the compressor
-                // thresholds make more sense.
-                for (sample, window_sample) in real_fft_buffer
+                // We'll window the input with a Hann function to avoid spectral leakage. The input
+                // gain here also contains a compensation factor for the forward FFT to make the
+                // compressor thresholds make more sense. Every channel is transformed before any
+                // is evaluated, so that programs can read the other channel.
+                let profile_1 = std::time::Instant::now();
+                for (window, spectrum) in windows
                     .iter_mut()
-                    .zip(self.local_state.window_function.iter())
+                    .zip(&mut self.local_state.spectra)
+                    .take(num_inputs)
                 {
-                    *sample *= window_sample * input_gain;
+                    for (sample, window_sample) in
+                        window.iter_mut().zip(self.local_state.window_function.iter())
+                    {
+                        *sample *= window_sample * input_gain;
+                    }
+                    fft_plan
+                        .r2c_plan
+                        .process_with_scratch(window, spectrum, &mut [])
+                        .unwrap();
                 }
 
-                // Forward FFT, `real_fft_buffer` already is already padded with zeroes, and the
-                // padding from the last iteration will have already been added back to the start of
-                // the buffer
-                let profile_1 = std::time::Instant::now();
-                fft_plan
-                    .r2c_plan
-                    .process_with_scratch(
-                        real_fft_buffer,
-                        &mut self.local_state.complex_fft_buffer,
-                        &mut [],
-                    )
-                    .unwrap();
-
-                // Collect result as array
+                // The sidechain is summed to mono before the transform
+                if let [first, second] = sidechain_windows {
+                    for ((sample, other), window_sample) in first
+                        .iter_mut()
+                        .zip(second.iter())
+                        .zip(self.local_state.window_function.iter())
+                    {
+                        *sample = (*sample + other) * 0.5 * window_sample * input_gain;
+                    }
+                    fft_plan
+                        .r2c_plan
+                        .process_with_scratch(first, &mut self.local_state.sidechain, &mut [])
+                        .unwrap();
+                }
+
+                // Mid and side spectra, read by stereo-aware programs
+                let stereo = num_inputs == 2;
+                if stereo {
+                    let [left, right] = &self.local_state.spectra;
+                    let [mid, side] = &mut self.local_state.mid_side;
+                    for (((l, r), m), s) in left.iter().zip(right).zip(mid).zip(side) {
+                        *m = (l + r) * 0.5;
+                        *s = (l - r) * 0.5;
+                    }
+                }
+
+                // Evaluate each channel the program runs on into its output buffer
                 let profile_4 = std::time::Instant::now();
-                let len = self.local_state.complex_fft_buffer.len();
-                let res = Resource {
-                    fft: &self.local_state.complex_fft_buffer,
-                    modulation: &self.params.modulation.to_vec(),
-                    beat: context.transport().pos_beats().unwrap_or(0.0),
-                    second: context.transport().pos_seconds().unwrap_or(0.0),
+                let len = self.local_state.spectra[0].len();
+                let frame_seed = frame_seed(seed, self.local_state.frame);
+                self.local_state.frame += 1;
+                let [left, right] = &self.local_state.spectra;
+                let [mid, side] = &self.local_state.mid_side;
+                let resource = |fft, channel, mid_side| Resource {
+                    fft,
+                    modulation: &self.local_state.modulation,
+                    beat: snapshot.beat,
+                    second: snapshot.second,
+                    clock: snapshot.clock,
+                    sample_rate: snapshot.sample_rate,
+                    window_size,
+                    overlap: overlap_times,
+                    stereo: stereo.then_some(Stereo {
+                        left,
+                        right,
+                        mid,
+                        side,
+                        channel,
+                        mid_side,
+                    }),
+                    sidechain: &self.local_state.sidechain,
+                    history: None,
+                    feedback: &[],
+                    state: None,
+                    envelope: &[],
+                    harmonic: &[],
+                    frame_seed,
+                    features: Default::default(),
+                };
+                let mut analysis = Analysis {
+                    program: code_value,
+                    plan: fft_plan,
+                    envelope: &mut self.local_state.envelope,
+                    envelope_order,
+                    hpss_frames,
+                    hpss_bands,
+                    hop_seconds,
                 };
-                let result = code_value.collect(0..len, &res);
+                let profile_5;
+                match code_value.output {
+                    Output::Mono => {
+                        let (inputs, evaluations) = match stereo_mode {
+                            StereoMode::Independent => ([left, right], num_inputs),
+                            StereoMode::Linked => ([mid, side], 1),
+                            StereoMode::MidSide => ([mid, side], 2),
+                        };
+                        for (channel, ((input, output), channel_state)) in inputs
+                            .into_iter()
+                            .zip(&mut self.local_state.outputs)
+                            .zip(&mut self.local_state.channels)
+                            .take(evaluations)
+                            .enumerate()
+                        {
+                            let res = channel_state.analyse(
+                                resource(input, channel, stereo_mode == StereoMode::MidSide),
+                                &mut analysis,
+                            );
+                            if error.is_none() {
+                                error = evaluate(
+                                    code_value,
+                                    &res,
+                                    !(staged && specialised.is_some()),
+                                    &mut self.local_state.mask,
+                                    &mut self.local_state.env,
+                                    &self.plugin_state,
+                                    output,
+                                )
+                                .err();
+                            }
+                            if error.is_some() {
+                                output.copy_from_slice(input);
+                            }
+                            channel_state.record(input, output.iter().copied(), decay);
+                        }
 
-                // Apply new magnitudes
-                let profile_5 = std::time::Instant::now();
-                for (val, complex) in result
-                    .into_iter()
-                    .zip(&mut self.local_state.complex_fft_buffer)
-                {
-                    *complex = val.into();
-                }
+                        // Turn the evaluated channels back into left and right
+                        profile_5 = std::time::Instant::now();
+                        let [out_0, out_1] = &mut self.local_state.outputs;
+                        match stereo_mode {
+                            StereoMode::Independent if windows.len() > num_inputs => {
+                                out_1.copy_from_slice(out_0);
+                            }
+                            StereoMode::Independent => {}
+                            StereoMode::Linked => {
+                                // Bins where the mid channel is silent pass through unchanged
+                                for ((o0, o1), ((l, r), m)) in out_0
+                                    .iter_mut()
+                                    .zip(out_1.iter_mut())
+                                    .zip(left.iter().zip(right).zip(mid))
+                                {
+                                    let gain = if m.norm_sqr() > f32::MIN_POSITIVE {
+                                        *o0 / m
+                                    } else {
+                                        Complex32::new(1.0, 0.0)
+                                    };
+                                    *o0 = l * gain;
+                                    *o1 = r * gain;
+                                }
+                            }
+                            StereoMode::MidSide => {
+                                for (m, s) in out_0.iter_mut().zip(out_1.iter_mut()) {
+                                    (*m, *s) = (*m + *s, *m - *s);
+                                }
+                            }
+                        }
+                    }
+                    Output::Stereo => {
+                        // Stereo input is summed, the code can still read each channel
+                        let input = if stereo { mid } else { left };
+                        let channel_state = &mut self.local_state.channels[0];
+                        let res = channel_state.analyse(resource(input, 0, false), &mut analysis);
+                        let [out_0, out_1] = &mut self.local_state.outputs;
+                        if error.is_none() {
+                            error = code_value
+                                .collect_stereo_into(&mut self.local_state.env, &res, out_0, out_1)
+                                .err();
+                        }
+                        if error.is_some() {
+                            out_0.copy_from_slice(left);
+                            out_1.copy_from_slice(if stereo { right } else { left });
+                        }
+
+                        // The program is fed back the average of both channels
+                        let output = out_0.iter().zip(out_1.iter()).map(|(l, r)| (l + r) * 0.5);
+                        channel_state.record(input, output, decay);
 
-                // Remove extreme value
-                self.local_state.complex_fft_buffer[0] = Complex32::default();
-                self.local_state.complex_fft_buffer[len - 1] = Complex32::default();
+                        // Mono output gets both channels
+                        profile_5 = std::time::Instant::now();
+                        if windows.len() == 1 {
+                            for (l, r) in out_0.iter_mut().zip(out_1.iter()) {
+                                *l = (*l + r) * 0.5;
+                            }
+                        }
+                    }
+                }
 
-                // Inverse FFT back into the scratch buffer. This will be added to a ring buffer
-                // which gets written back to the host at a one block delay.
+                // Inverse FFT back into the windows. These will be added to a ring buffer which
+                // gets written back to the host at a one block delay.
                 let profile_6 = std::time::Instant::now();
-                fft_plan
-                    .c2r_plan
-                    .process_with_scratch(
-                        &mut self.local_state.complex_fft_buffer,
-                        real_fft_buffer,
-                        &mut [],
-                    )
-                    .unwrap();
-
-                // Apply the window function once more to reduce time domain aliasing. The gain
-                // compensation compensates for the squared Hann window that would be applied if we
-                // didn't do any processing at all as well as the FFT+IFFT itself.
-                let profile_7 = std::time::Instant::now();
-                for (sample, window_sample) in real_fft_buffer
-                    .iter_mut()
-                    .zip(self.local_state.window_function.iter())
-                {
-                    *sample *= window_sample * output_gain;
+                for (window, output) in windows.iter_mut().zip(&mut self.local_state.outputs) {
+                    // Replace non-finite and overly loud bins
+                    if safety && sanitise(output, bin_ceiling) {
+                        self.plugin_state.sanitised.store(true, Ordering::Relaxed);
+                    }
+
+                    // Remove extreme value
+                    output[0] = Complex32::default();
+                    output[len - 1] = Complex32::default();
+
+                    fft_plan
+                        .c2r_plan
+                        .process_with_scratch(output, window, &mut [])
+                        .unwrap();
+
+                    // Apply the window function once more to reduce time domain aliasing. The
+                    // gain compensation compensates for the squared Hann window that would be
+                    // applied if we didn't do any processing at all as well as the FFT+IFFT
+                    // itself.
+                    for (sample, window_sample) in
+                        window.iter_mut().zip(self.local_state.window_function.iter())
+                    {
+                        *sample *= window_sample * output_gain;
+                    }
                 }
 
                 // Store profiling result
                 let profile = format!(
-                    "Profile: {} us, {} us, {} us, {} us, {} us, {} us",
+                    "Profile: {} us, {} us, {} us, {} us, {} us",
                     profile_0.elapsed().as_micros(),
                     profile_1.elapsed().as_micros(),
                     profile_4.elapsed().as_micros(),
                     profile_5.elapsed().as_micros(),
                     profile_6.elapsed().as_micros(),
-                    profile_7.elapsed().as_micros(),
                 );
                 *self.plugin_state.profiler.lock().unwrap() = profile;
 
@@ -630,7 +1597,35 @@ impl Plugin for DuskPhantom {
                 *self.plugin_state.debug.lock().unwrap() = format!("complex_len = {}", len);
             },
         );
-        ProcessStatus::Normal
+
+        // Keep the overlap-added output within the limiter ceiling
+        if safety
+            && self
+                .local_state
+                .limiter
+                .process(buffer.as_slice(), limiter_ceiling)
+        {
+            self.plugin_state.sanitised.store(true, Ordering::Relaxed);
+        }
+
+        // Bypass the code until it is updated, and report why off the audio thread
+        if let Some(error) = error {
+            if !self.plugin_state.bypassed.swap(true, Ordering::SeqCst) {
+                context.execute_background(Task::Report {
+                    profile: self.params.global.profile.value(),
+                    error,
+                });
+            }
+        }
+
+        // Past frames and feedback keep the output going after the input stops, other programs
+        // only delay it, which the latency already accounts for
+        match &*self.plugin_state.code_value.load() {
+            Some(program) if program.uses_history || program.uses_feedback => {
+                ProcessStatus::Tail(self.tail_samples(program, window_size, overlap_times))
+            }
+            _ => ProcessStatus::Normal,
+        }
     }
 }
 
@@ -0,0 +1,141 @@
+use realfft::num_complex::Complex32;
+
+/// Replace non-finite bins with silence and scale bins louder than `ceiling` down to it,
+/// keeping their phase. Returns whether any bin was changed.
+pub fn sanitise(bins: &mut [Complex32], ceiling: f32) -> bool {
+    let mut changed = false;
+    for bin in bins {
+        if !bin.re.is_finite() || !bin.im.is_finite() {
+            *bin = Complex32::default();
+            changed = true;
+            continue;
+        }
+        let norm = bin.norm();
+        if norm > ceiling {
+            *bin *= ceiling / norm;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// The feedback of a bin for the next frame, which is `new` scaled down to at most the `old`
+/// feedback faded by `decay` plus the `input` of this frame. Once the input stops, feedback
+/// fades at least by `decay` each frame, whatever the gain of the loop. Non-finite bins are
+/// silenced.
+pub fn decay_floor(old: Complex32, new: Complex32, input: Complex32, decay: f32) -> Complex32 {
+    let bound = old.norm() * decay + input.norm();
+    let norm = new.norm();
+    if !norm.is_finite() {
+        Complex32::default()
+    } else if norm > bound {
+        new * (bound / norm)
+    } else {
+        new
+    }
+}
+
+/// A brickwall limiter without lookahead, sharing its gain between channels so the stereo image
+/// doesn't shift. Attack is instant and release is exponential.
+pub struct Limiter {
+    gain: f32,
+    /// How much of the gain reduction is kept after each sample
+    release: f32,
+}
+
+impl Limiter {
+    pub fn new(release_seconds: f32, sample_rate: f32) -> Self {
+        Self {
+            gain: 1.0,
+            release: (-1.0 / (release_seconds * sample_rate)).exp(),
+        }
+    }
+
+    /// Keep all samples within `ceiling`, replacing non-finite ones with silence.
+    /// Returns whether the signal was changed.
+    pub fn process(&mut self, channels: &mut [&mut [f32]], ceiling: f32) -> bool {
+        let mut changed = false;
+        let len = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+        for i in 0..len {
+            let mut peak: f32 = 0.0;
+            for channel in channels.iter_mut() {
+                if !channel[i].is_finite() {
+                    channel[i] = 0.0;
+                    changed = true;
+                }
+                peak = peak.max(channel[i].abs());
+            }
+            let target = if peak > ceiling { ceiling / peak } else { 1.0 };
+            self.gain = target.min(1.0 - (1.0 - self.gain) * self.release);
+            if self.gain < 1.0 {
+                for channel in channels.iter_mut() {
+                    channel[i] *= self.gain;
+                }
+                changed |= peak > 0.0;
+            }
+        }
+        changed
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_safety {
+    use super::*;
+
+    #[test]
+    fn test_sanitise() {
+        let mut bins = vec![
+            Complex32::new(f32::NAN, 0.0),
+            Complex32::new(0.0, f32::INFINITY),
+            Complex32::new(30.0, 40.0),
+            Complex32::new(0.3, 0.4),
+        ];
+        assert!(sanitise(&mut bins, 5.0));
+        assert_eq!(bins[0], Complex32::default());
+        assert_eq!(bins[1], Complex32::default());
+        assert!((bins[2] - Complex32::new(3.0, 4.0)).norm() < 1e-5);
+        assert_eq!(bins[3], Complex32::new(0.3, 0.4));
+        assert!(!sanitise(&mut bins, 5.0));
+    }
+
+    #[test]
+    fn test_decay_floor() {
+        // A loop with a gain of 2 still fades once the input stops
+        let input = Complex32::new(0.0, 1.0);
+        let mut feedback = decay_floor(Complex32::default(), input, input, 0.5);
+        assert_eq!(feedback, input);
+        let mut norm = 1.0;
+        for _ in 0..4 {
+            feedback = decay_floor(feedback, feedback * 2.0, Complex32::default(), 0.5);
+            norm *= 0.5;
+            assert!((feedback - Complex32::new(0.0, norm)).norm() < 1e-6);
+        }
+
+        // Quieter output passes through, non-finite output is silenced
+        let quiet = Complex32::new(0.5, 0.0);
+        assert_eq!(decay_floor(feedback, quiet, input, 0.5), quiet);
+        let nan = Complex32::new(f32::NAN, 0.0);
+        assert_eq!(decay_floor(feedback, nan, input, 0.5), Complex32::default());
+    }
+
+    #[test]
+    fn test_limiter() {
+        let mut limiter = Limiter::new(0.01, 1000.0);
+        let mut left = vec![0.5, 4.0, f32::NAN, 0.5, 0.5];
+        let mut right = vec![0.5, -2.0, 0.5, 0.5, 0.5];
+        assert!(limiter.process(&mut [&mut left, &mut right], 1.0));
+        assert_eq!(left[0], 0.5);
+        assert_eq!(left[1], 1.0);
+        assert_eq!(right[1], -0.5);
+        assert_eq!(left[2], 0.0);
+        for sample in left.iter().chain(&right) {
+            assert!(sample.abs() <= 1.0);
+        }
+
+        // The gain recovers after the release
+        let mut quiet = vec![0.5; 200];
+        limiter.process(&mut [&mut quiet], 1.0);
+        assert!((quiet[199] - 0.5).abs() < 1e-3);
+    }
+}
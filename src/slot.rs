@@ -0,0 +1,66 @@
+use arc_swap::{ArcSwapOption, Guard};
+use std::sync::{Arc, Mutex};
+
+/// A value produced on a background thread and read by the audio thread without locking.
+pub struct Slot<T> {
+    current: ArcSwapOption<T>,
+    /// Keeps replaced values alive while a reader may still hold them, so the audio thread never
+    /// drops the final reference.
+    retired: Mutex<Vec<Arc<T>>>,
+}
+
+impl<T> Default for Slot<T> {
+    fn default() -> Self {
+        Self {
+            current: ArcSwapOption::empty(),
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<T> Slot<T> {
+    /// Read the current value, this doesn't lock or allocate.
+    pub fn load(&self) -> Guard<Option<Arc<T>>> {
+        self.current.load()
+    }
+
+    /// Take a reference to the current value, for threads other than the audio thread which may
+    /// hold it for a while.
+    pub fn load_full(&self) -> Option<Arc<T>> {
+        self.current.load_full()
+    }
+
+    /// Replace the current value, never call this from the audio thread.
+    pub fn store(&self, value: Option<T>) {
+        let old = self.current.swap(value.map(Arc::new));
+        let mut retired = self.retired.lock().unwrap();
+        retired.extend(old);
+
+        // Once swapped out a value can't be loaded again, and the swap turns the loads still
+        // reading it into references, so values only referenced here are no longer read
+        retired.retain(|value| Arc::strong_count(value) > 1);
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_slot {
+    use super::*;
+
+    #[test]
+    fn test_slot_retires() {
+        let slot = Slot::default();
+        slot.store(Some(1));
+        let guard = slot.load();
+        slot.store(Some(2));
+        slot.store(Some(3));
+
+        // The first value is still read, the second one never was
+        assert_eq!(guard.as_deref(), Some(&1));
+        assert_eq!(slot.retired.lock().unwrap().len(), 1);
+        drop(guard);
+        slot.store(None);
+        assert!(slot.retired.lock().unwrap().is_empty());
+        assert_eq!(slot.load().as_deref(), None);
+    }
+}
@@ -0,0 +1,158 @@
+/// An overlap-add STFT that hands the windows of every channel to the callback at once, so that
+/// programs can read the spectrum of the other channel and the sidechain
+pub struct Stft {
+    /// Ring buffers of the last `block_size` input samples of each channel
+    input: Vec<Vec<f32>>,
+    /// Ring buffers of overlap-added output, read back one block after being written
+    output: Vec<Vec<f32>>,
+    /// The current window of each channel, passed to the callback
+    scratch: Vec<Vec<f32>>,
+    /// Input ring buffers and windows of the sidechain channels, which are only analysed
+    sidechain_input: Vec<Vec<f32>>,
+    sidechain_scratch: Vec<Vec<f32>>,
+    block_size: usize,
+    /// Position in the ring buffers of the next sample
+    pos: usize,
+}
+
+impl Stft {
+    /// All buffers are allocated with `max_block_size` capacity, so resizing up to it won't
+    /// allocate
+    pub fn new(num_channels: usize, num_sidechain_channels: usize, max_block_size: usize) -> Self {
+        let buffers = |n| vec![Vec::with_capacity(max_block_size); n];
+        let mut stft = Self {
+            input: buffers(num_channels),
+            output: buffers(num_channels),
+            scratch: buffers(num_channels),
+            sidechain_input: buffers(num_sidechain_channels),
+            sidechain_scratch: buffers(num_sidechain_channels),
+            block_size: 0,
+            pos: 0,
+        };
+        stft.set_block_size(max_block_size);
+        stft
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.input.len()
+    }
+
+    /// Change the window size, clearing all buffered audio
+    pub fn set_block_size(&mut self, block_size: usize) {
+        for buffer in self
+            .input
+            .iter_mut()
+            .chain(&mut self.output)
+            .chain(&mut self.scratch)
+            .chain(&mut self.sidechain_input)
+            .chain(&mut self.sidechain_scratch)
+        {
+            buffer.clear();
+            buffer.resize(block_size, 0.0);
+        }
+        self.block_size = block_size;
+        self.pos = 0;
+    }
+
+    pub fn latency_samples(&self) -> u32 {
+        self.block_size as u32
+    }
+
+    /// Run the channels through the ring buffers, calling `process` with the windows of every
+    /// channel and sidechain channel each `block_size / overlap_times` samples. Whatever is left in
+    /// the main windows is added back to the output one block later. Missing sidechain channels
+    /// are silent.
+    pub fn process_overlap_add<F>(
+        &mut self,
+        channels: &mut [&mut [f32]],
+        sidechain: &[&mut [f32]],
+        overlap_times: usize,
+        mut process: F,
+    ) where
+        F: FnMut(&mut [Vec<f32>], &mut [Vec<f32>]),
+    {
+        let block_size = self.block_size;
+        let hop = (block_size / overlap_times.max(1)).max(1);
+        let len = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+        let mut done = 0;
+        while done < len {
+            let until_window = hop - self.pos % hop;
+            let count = until_window.min(len - done);
+            let range = self.pos..self.pos + count;
+            for ((channel, input), output) in
+                channels.iter_mut().zip(&mut self.input).zip(&mut self.output)
+            {
+                let samples = &mut channel[done..done + count];
+                input[range.clone()].copy_from_slice(samples);
+                samples.copy_from_slice(&output[range.clone()]);
+                output[range.clone()].fill(0.0);
+            }
+            for (c, input) in self.sidechain_input.iter_mut().enumerate() {
+                match sidechain.get(c).and_then(|samples| samples.get(done..done + count)) {
+                    Some(samples) => input[range.clone()].copy_from_slice(samples),
+                    None => input[range.clone()].fill(0.0),
+                }
+            }
+            self.pos = (self.pos + count) % block_size;
+            done += count;
+            if count < until_window {
+                break;
+            }
+
+            // The oldest sample is the one that will be overwritten next
+            for (scratch, input) in self
+                .scratch
+                .iter_mut()
+                .zip(&self.input)
+                .chain(self.sidechain_scratch.iter_mut().zip(&self.sidechain_input))
+            {
+                let (newer, older) = input.split_at(self.pos);
+                scratch[..older.len()].copy_from_slice(older);
+                scratch[older.len()..].copy_from_slice(newer);
+            }
+            process(&mut self.scratch, &mut self.sidechain_scratch);
+            for (scratch, output) in self.scratch.iter().zip(&mut self.output) {
+                let (newer, older) = output.split_at_mut(self.pos);
+                for (sample, processed) in older.iter_mut().chain(newer).zip(scratch) {
+                    *sample += processed;
+                }
+            }
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+pub mod tests_stft {
+    use super::*;
+
+    #[test]
+    fn test_stft_delay() {
+        // Without processing, every sample is added back once per overlapping window
+        let mut stft = Stft::new(2, 2, 16);
+        stft.set_block_size(8);
+        let mut left: Vec<f32> = (0..40).map(|i| i as f32).collect();
+        let mut right: Vec<f32> = (0..40).map(|i| -(i as f32)).collect();
+        let mut sidechain: Vec<f32> = (0..40).map(|i| i as f32 * 2.0).collect();
+        let mut windows = 0;
+        for ((left, right), sidechain) in left
+            .chunks_mut(7)
+            .zip(right.chunks_mut(7))
+            .zip(sidechain.chunks_mut(7))
+        {
+            stft.process_overlap_add(&mut [left, right], &[sidechain], 4, |scratch, aux| {
+                assert_eq!(scratch.len(), 2);
+                assert_eq!(scratch[0][7], -scratch[1][7]);
+                assert_eq!(aux[0][7], scratch[0][7] * 2.0);
+                assert_eq!(aux[1][7], 0.0);
+                windows += 1;
+            });
+        }
+        assert_eq!(windows, 20);
+        assert_eq!(stft.latency_samples(), 8);
+        for i in 16..40 {
+            assert_eq!(left[i], 4.0 * (i - 8) as f32);
+            assert_eq!(right[i], -4.0 * (i - 8) as f32);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+#![allow(dead_code)]
+
+#[cfg(feature = "jit")]
+use dusk_phantom::lang::jit::{JitProgram, ResourceAbi};
+#[cfg(feature = "jit")]
+use dusk_phantom::lang::{Program, Resource};
+use rand::Rng;
+#[cfg(feature = "jit")]
+use realfft::num_complex::Complex32;
+
+/// Generates random well-typed programs of type `Float -> (Float, Float)`
+pub struct ProgramGen<'a, R: Rng> {
+    rng: &'a mut R,
+    floats: Vec<String>,
+    next_var: usize,
+}
+
+impl<'a, R: Rng> ProgramGen<'a, R> {
+    pub fn new(rng: &'a mut R) -> Self {
+        Self {
+            rng,
+            floats: Vec::new(),
+            next_var: 0,
+        }
+    }
+
+    pub fn program(&mut self, depth: usize) -> String {
+        self.floats.push("(i * 0.01)".into());
+        let body = self.complex(depth);
+        self.floats.pop();
+        format!("(i: Float) => {}", body)
+    }
+
+    fn literal(&mut self) -> String {
+        match self.rng.gen_range(0..8) {
+            0 => "0".into(),
+            1 => "1".into(),
+            _ => format!("{:.3}", self.rng.gen_range(0.0..4.0)),
+        }
+    }
+
+    /// Band index that keeps the interpreter on its fractional lookup path
+    fn index(&mut self) -> String {
+        match self.rng.gen_range(0..3) {
+            0 => "i".into(),
+            1 => format!("(i * {})", self.literal()),
+            _ => format!("(i + {})", self.literal()),
+        }
+    }
+
+    pub fn float(&mut self, depth: usize) -> String {
+        let leaf = depth == 0 || self.rng.gen_bool(0.2);
+        if leaf {
+            return match self.rng.gen_range(0..6) {
+                0 => self.literal(),
+                1 => "beat".into(),
+                2 => "sec".into(),
+                3 => format!("param({})", self.rng.gen_range(0..18)),
+                4 => {
+                    let proj = ["re", "im", "norm", "angle"][self.rng.gen_range(0..4)];
+                    format!("fft({}).{}", self.index(), proj)
+                }
+                _ => {
+                    let i = self.rng.gen_range(0..self.floats.len());
+                    self.floats[i].clone()
+                }
+            };
+        }
+        match self.rng.gen_range(0..7) {
+            0 | 1 => {
+                let op = ["+", "-", "*", "/", "%"][self.rng.gen_range(0..5)];
+                format!("({} {} {})", self.float(depth - 1), op, self.float(depth - 1))
+            }
+            2 => {
+                let func = ["sin", "cos", "tan"][self.rng.gen_range(0..3)];
+                format!("{}({})", func, self.float(depth - 1))
+            }
+            3 => format!(
+                "(if {} then {} else {})",
+                self.boolean(depth - 1),
+                self.float(depth - 1),
+                self.float(depth - 1),
+            ),
+            4 => {
+                let name = format!("x{}", self.next_var);
+                self.next_var += 1;
+                let value = self.float(depth - 1);
+                self.floats.push(name.clone());
+                let next = self.float(depth - 1);
+                self.floats.pop();
+                format!("(let {}: Float = {} in {})", name, value, next)
+            }
+            5 => {
+                let name = format!("x{}", self.next_var);
+                self.next_var += 1;
+                let arg = self.float(depth - 1);
+                self.floats.push(name.clone());
+                let body = self.float(depth - 1);
+                self.floats.pop();
+                format!("(({}: Float) => {})({})", name, body, arg)
+            }
+            _ => {
+                let proj = ["re", "im", "norm", "angle"][self.rng.gen_range(0..4)];
+                format!("{}.{}", self.complex(depth - 1), proj)
+            }
+        }
+    }
+
+    pub fn boolean(&mut self, depth: usize) -> String {
+        if depth == 0 || self.rng.gen_bool(0.2) {
+            return ["true", "false"][self.rng.gen_range(0..2)].into();
+        }
+        let op = ["<", ">", "<=", ">="][self.rng.gen_range(0..4)];
+        format!("({} {} {})", self.float(depth - 1), op, self.float(depth - 1))
+    }
+
+    pub fn complex(&mut self, depth: usize) -> String {
+        if depth == 0 || self.rng.gen_bool(0.2) {
+            return format!("fft({})", self.index());
+        }
+        match self.rng.gen_range(0..4) {
+            0 => format!("({}, {})", self.float(depth - 1), self.float(depth - 1)),
+            1 => format!("({}, {}).polar", self.float(depth - 1), self.float(depth - 1)),
+            2 => format!(
+                "(if {} then {} else {})",
+                self.boolean(depth - 1),
+                self.complex(depth - 1),
+                self.complex(depth - 1),
+            ),
+            _ => format!("fft({})", self.index()),
+        }
+    }
+}
+
+/// Float comparison treating matching non-finite values as equal
+pub fn close(a: f32, b: f32) -> bool {
+    if a.is_nan() || b.is_nan() {
+        return a.is_nan() && b.is_nan();
+    }
+    a == b || (a - b).abs() <= 1e-4 * a.abs().max(b.abs()).max(1.0)
+}
+
+/// Check that native code gives the same bins as the interpreter, returning the native ones
+#[cfg(feature = "jit")]
+pub fn assert_jit_matches(program: &Program, res: &Resource) -> Vec<Complex32> {
+    assert_jit_matches_on(program, res, res)
+}
+
+/// Like `assert_jit_matches`, with native code reading `jit_res`, for resources whose state is
+/// advanced by evaluation and so can't be shared
+#[cfg(feature = "jit")]
+pub fn assert_jit_matches_on(
+    program: &Program,
+    res: &Resource,
+    jit_res: &Resource,
+) -> Vec<Complex32> {
+    let jit = JitProgram::compile(program).unwrap();
+    let len = res.fft.len();
+    let expected = program.collect(0..len, res).unwrap();
+    let mut result = vec![Complex32::default(); len];
+    jit.collect_into(&ResourceAbi::new(jit_res), &mut result);
+    for (i, (value, actual)) in expected.into_iter().zip(&result).enumerate() {
+        let value: Complex32 = value.try_into().unwrap();
+        assert!(
+            close(value.re, actual.re) && close(value.im, actual.im),
+            "bin {} of {}: eval gives {}, jit gives {}",
+            i,
+            program.pretty_term(),
+            value,
+            actual,
+        );
+    }
+    result
+}
@@ -1,5 +1,10 @@
-use dusk_phantom::lang::{run, Resource, Value};
+use std::f32::consts::TAU;
+
+use dusk_phantom::lang::{
+    frame_seed, noise_at, rand_at, run, Clock, Features, History, OnsetDetector, Output, Resource, State, Stereo, Value,
+};
 use realfft::num_complex::Complex32;
+use realfft::RealFftPlanner;
 
 #[test]
 fn test_lp() {
@@ -11,13 +16,15 @@ fn test_lp() {
         Err(err) => panic!("failed to run code: {}", err),
     };
 
+    let modulation = vec![];
     let resource = Resource { 
         fft: &complex,
-        modulation: &vec![],
+        modulation: &modulation,
         beat: 0.0,
         second: 0.0,
+        ..Resource::new(&complex, &modulation)
     };
-    let result = code_value.collect(0..len, &resource);
+    let result = code_value.collect(0..len, &resource).unwrap();
     for res in &result[0..800] {
         let Value::Tuple(xs) = res else {
             panic!("result is not complex: {}", res);
@@ -44,4 +51,716 @@ fn test_lp() {
         assert_eq!(re, 0.0);
         assert_eq!(im, 0.0);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_hoist() {
+    let len = 64;
+    let complex: Vec<Complex32> = (0..len).map(|i| Complex32::new(i as f32, 1.0)).collect();
+    let code = "(i: Float) => (fft(i).re * (param(1) * 2), i * sin(beat))";
+    let code_value = match run(code) {
+        Ok(x) => x,
+        Err(err) => panic!("failed to run code: {}", err),
+    };
+    assert_eq!(code_value.prologue.len(), 2);
+
+    let modulation = vec![0.0, 0.25];
+    let resource = Resource {
+        beat: 1.5,
+        ..Resource::new(&complex, &modulation)
+    };
+    let result = code_value.collect(0..len, &resource).unwrap();
+    for (i, res) in result.iter().enumerate() {
+        let Value::Tuple(xs) = res else {
+            panic!("result is not complex: {}", res);
+        };
+        let Value::Float(re) = xs[0] else {
+            panic!("real part is not float: {}", xs[0]);
+        };
+        let Value::Float(im) = xs[1] else {
+            panic!("imaginary part is not float: {}", xs[1]);
+        };
+        assert_eq!(re, i as f32 * 0.5);
+        assert_eq!(im, i as f32 * 1.5f32.sin());
+    }
+}
+
+#[test]
+fn test_mask() {
+    let len = 256;
+    let complex: Vec<Complex32> = (0..len)
+        .map(|i| Complex32::new((i as f32 * 0.3).sin(), (i as f32 * 0.7).cos()))
+        .collect();
+    let code = "(i: Float) => if param(0) > 0 then (fft(i).norm * (i / 256 + param(1)), fft(i).angle + 0.5).polar else fft(i)";
+    let code_value = match run(code) {
+        Ok(x) => x,
+        Err(err) => panic!("failed to run code: {}", err),
+    };
+    let Some(mask) = &code_value.mask else {
+        panic!("program is not a mask: {}", code_value.pretty_term());
+    };
+
+    for modulation in [vec![1.0, 0.25], vec![-1.0, 0.25]] {
+        let resource = Resource::new(&complex, &modulation);
+        let mut gain = vec![Complex32::default(); len];
+        mask.compute(&mut Vec::new(), &resource, &mut gain).unwrap();
+        let result = code_value.collect(0..len, &resource).unwrap();
+        for (i, res) in result.into_iter().enumerate() {
+            let expected: Complex32 = res.try_into().unwrap();
+            let masked = complex[i] * gain[i];
+            assert!(
+                (expected - masked).norm() < 1e-5,
+                "bin {}: program gives {}, mask gives {}",
+                i,
+                expected,
+                masked,
+            );
+        }
+    }
+}
+
+/// Interpolate the spectrum of a delayed impulse, whose bins rotate by `step` radians,
+/// returning the largest magnitude and phase errors halfway between bins
+fn interp_errors(mode: &str, step: f32) -> (f32, f32) {
+    let len = 128;
+    let complex: Vec<Complex32> = (0..len)
+        .map(|k| Complex32::from_polar(1.0, -step * k as f32))
+        .collect();
+    let code = format!("(i: Float) => fft_{}(i + 0.5)", mode);
+    let code_value = run(&code).unwrap_or_else(|err| panic!("failed to run {}: {}", code, err));
+    let modulation = vec![];
+    let resource = Resource::new(&complex, &modulation);
+    let result = code_value.collect(0..len, &resource).unwrap();
+
+    // Stay away from the edges, where windowed sinc reads zeros
+    let (mut magnitude, mut phase) = (0.0f32, 0.0f32);
+    for (k, value) in result.into_iter().enumerate().take(len - 16).skip(16) {
+        let actual: Complex32 = value.try_into().unwrap();
+        let expected = Complex32::from_polar(1.0, -step * (k as f32 + 0.5));
+        magnitude = magnitude.max((actual.norm() - 1.0).abs());
+        phase = phase.max((actual * expected.conj()).arg().abs());
+    }
+    (magnitude, phase)
+}
+
+#[test]
+fn test_interp_modes() {
+    let step = 0.4;
+    let half = (step / 2.0f32).cos();
+    let bounds = [
+        ("nearest", 1e-5, step / 2.0 + 1e-4),
+        ("linear", 1.0 - half + 1e-4, 1e-4),
+        ("cosine", 1.0 - half + 1e-4, 1e-4),
+        ("cubic", 1e-3, 1e-4),
+        ("sinc", 2e-3, 1e-4),
+    ];
+    for (mode, max_magnitude, max_phase) in bounds {
+        let (magnitude, phase) = interp_errors(mode, step);
+        let errors = format!("{}: magnitude error {}, phase error {}", mode, magnitude, phase);
+        assert!(magnitude <= max_magnitude, "{}", errors);
+        assert!(phase <= max_phase, "{}", errors);
+    }
+
+    // Interpolating between rotating bins shrinks them, unless the kernel is wide enough
+    assert!(interp_errors("cubic", step).0 < interp_errors("linear", step).0 / 10.0);
+    assert!(interp_errors("sinc", step).0 < interp_errors("linear", step).0 / 4.0);
+}
+
+#[test]
+fn test_interp_param() {
+    let code = "(i: Float) => (param_nearest(1.4) + param_linear(0.25), param_cubic(2) + param_sinc(1))";
+    let code_value = run(code).unwrap();
+    let fft = vec![];
+    let modulation = vec![0.0, 2.0, 4.0, 6.0];
+    let resource = Resource::new(&fft, &modulation);
+    let result: Complex32 = code_value.collect(0..1, &resource).unwrap()[0].clone().try_into().unwrap();
+    assert!((result.re - 2.5).abs() < 1e-5);
+    assert!((result.im - 6.0).abs() < 1e-5);
+}
+
+#[test]
+fn test_stft_settings() {
+    let code = "(i: Float) => (hz(i), bin(nyquist) + wsize / hop + srate)";
+    let code_value = run(code).unwrap();
+    let complex = vec![Complex32::default(); 1025];
+    let modulation = vec![];
+    let resource = Resource {
+        sample_rate: 48000.0,
+        overlap: 8,
+        ..Resource::new(&complex, &modulation)
+    };
+    let result = code_value.collect(0..1025, &resource).unwrap();
+    for (i, value) in result.into_iter().enumerate() {
+        let value: Complex32 = value.try_into().unwrap();
+        assert!((value.re - i as f32 * 48000.0 / 2048.0).abs() < 1e-2);
+        assert_eq!(value.im, 1024.0 + 8.0 + 48000.0);
+    }
+}
+
+#[test]
+fn test_domain() {
+    let programs = [
+        "#domain hz\n(f: Float) => if f < 1000 then fft(f) else (0, 0)",
+        "#domain norm\n(f: Float) => if f < 1000 / 24000 then fft(f) else (0, 0)",
+    ];
+    let modulation = vec![];
+    for code in programs {
+        let code_value = run(code).unwrap();
+        assert!(code_value.mask.is_some(), "{} is a mask", code);
+        for window_size in [1024, 32768] {
+            let len = window_size / 2 + 1;
+            let complex: Vec<Complex32> = (0..len).map(|i| Complex32::new(1.0, i as f32)).collect();
+            let resource = Resource {
+                sample_rate: 48000.0,
+                window_size,
+                ..Resource::new(&complex, &modulation)
+            };
+            let result = code_value.collect(0..len, &resource).unwrap();
+            for (i, value) in result.into_iter().enumerate() {
+                let value: Complex32 = value.try_into().unwrap();
+                let hz = i as f32 * 48000.0 / window_size as f32;
+                let expected = if hz < 1000.0 { complex[i] } else { Complex32::default() };
+                assert_eq!(value, expected, "bin {} at window size {}", i, window_size);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_stereo() {
+    let len = 64;
+    let left: Vec<Complex32> = (0..len).map(|i| Complex32::new(i as f32, 1.0)).collect();
+    let right: Vec<Complex32> = (0..len).map(|i| Complex32::new(1.0, i as f32)).collect();
+    let mid: Vec<Complex32> = left.iter().zip(&right).map(|(l, r)| (l + r) * 0.5).collect();
+    let side: Vec<Complex32> = left.iter().zip(&right).map(|(l, r)| (l - r) * 0.5).collect();
+    let code = "(i: Float) => if is_side then (channel, fft_side(i).re) else (if is_mid then fft_r(i) else fft(i))";
+    let code_value = run(code).unwrap();
+    let modulation = vec![];
+    let resource = |fft, channel, mid_side| Resource {
+        stereo: Some(Stereo {
+            left: &left,
+            right: &right,
+            mid: &mid,
+            side: &side,
+            channel,
+            mid_side,
+        }),
+        ..Resource::new(fft, &modulation)
+    };
+    let cases = [
+        (resource(&left, 0, false), left.clone()),
+        (resource(&right, 1, false), right.clone()),
+        (resource(&mid, 0, true), right.clone()),
+        (
+            resource(&side, 1, true),
+            side.iter().map(|x| Complex32::new(1.0, x.re)).collect(),
+        ),
+    ];
+    for (res, expected) in cases {
+        let result = code_value.collect(0..len, &res).unwrap();
+        for (value, expected) in result.into_iter().zip(expected) {
+            assert_eq!(Complex32::try_from(value).unwrap(), expected);
+        }
+    }
+
+    // Mono input has a silent side, and channel-dependent programs are never masks
+    let mono = Resource {
+        stereo: None,
+        ..resource(&left, 0, false)
+    };
+    let code_value = run("(i: Float) => if channel < 1 then fft(i) else fft_side(i)").unwrap();
+    assert!(code_value.mask.is_none());
+    let result = code_value.collect(0..len, &mono).unwrap();
+    assert_eq!(Complex32::try_from(result[5].clone()).unwrap(), left[5]);
+    let code_value = run("(i: Float) => fft_side(i)").unwrap();
+    let result = code_value.collect(0..len, &mono).unwrap();
+    assert_eq!(Complex32::try_from(result[5].clone()).unwrap(), Complex32::default());
+}
+
+#[test]
+fn test_stereo_output() {
+    let len = 64;
+    let complex: Vec<Complex32> = (0..len).map(|i| Complex32::new(1.0, i as f32)).collect();
+    let code = "(i: Float) => let g: Float = i / 64 in ((fft(i).re * g, fft(i).im * g), (fft(i).re * (1 - g), fft(i).im * (1 - g)))";
+    let code_value = run(code).unwrap();
+    assert_eq!(code_value.output, Output::Stereo);
+    assert!(code_value.mask.is_none());
+    assert_eq!(run("(i: Float) => fft(i)").unwrap().output, Output::Mono);
+
+    let modulation = vec![];
+    let resource = Resource::new(&complex, &modulation);
+    let result = code_value.collect(0..len, &resource).unwrap();
+    for (i, value) in result.into_iter().enumerate() {
+        let (left, right): (Complex32, Complex32) = value.try_into().unwrap();
+        let g = i as f32 / 64.0;
+        assert!((left - complex[i] * g).norm() < 1e-4);
+        assert!((right - complex[i] * (1.0 - g)).norm() < 1e-4);
+    }
+
+    // Neither target type
+    let err = run("(i: Float) => (fft(i), 1)").unwrap_err();
+    assert!(err.contains("Type mismatch"), "{}", err);
+}
+
+#[test]
+fn test_sidechain() {
+    let len = 64;
+    let complex: Vec<Complex32> = (0..len).map(|i| Complex32::from_polar(1.0, i as f32 * 0.1)).collect();
+    let sidechain: Vec<Complex32> = (0..len).map(|i| Complex32::new(0.0, i as f32)).collect();
+    let code = "(i: Float) => (side(i).norm, fft(i).angle).polar";
+    let code_value = run(code).unwrap();
+    assert!(code_value.mask.is_none());
+    let modulation = vec![];
+    for sidechain in [&sidechain[..], &[]] {
+        let resource = Resource {
+            sidechain,
+            ..Resource::new(&complex, &modulation)
+        };
+        let result = code_value.collect(0..len, &resource).unwrap();
+        for (i, value) in result.into_iter().enumerate() {
+            let value: Complex32 = value.try_into().unwrap();
+            let norm = sidechain.get(i).map_or(0.0, |x| x.norm());
+            assert!((value - Complex32::from_polar(norm, i as f32 * 0.1)).norm() < 1e-3);
+        }
+    }
+}
+
+#[test]
+fn test_history() {
+    let len = 16;
+    let frame = |x: f32| -> Vec<Complex32> { (0..len).map(|i| Complex32::new(x, i as f32)).collect() };
+    let mut history = History::new(8, len);
+    history.set_depth(4);
+    for x in 1..=3 {
+        history.push(&frame(x as f32));
+    }
+    let complex = frame(4.0);
+    let code_value = run("(i: Float) => (hist(param(0))(i).re + fft_prev(param(1), i).re, hist(0.5)(i).im)").unwrap();
+    assert!(code_value.mask.is_none());
+    let cases = [
+        (0.0, 1.0, 4.0 + 3.0),
+        (1.0, 2.5, 3.0 + 1.5),
+        (3.0, 4.0, 1.0),
+        (-1.0, 8.0, 4.0),
+    ];
+    for (k0, k1, expected) in cases {
+        let modulation = vec![k0, k1];
+        let resource = Resource {
+            history: Some(&history),
+            ..Resource::new(&complex, &modulation)
+        };
+        let result = code_value.collect(0..len, &resource).unwrap();
+        for (i, value) in result.into_iter().enumerate() {
+            let value: Complex32 = value.try_into().unwrap();
+            assert_eq!(value, Complex32::new(expected, i as f32), "bin {} at frames {} and {}", i, k0, k1);
+        }
+    }
+}
+
+#[test]
+fn test_feedback() {
+    let len = 16;
+    let complex: Vec<Complex32> = (0..len).map(|i| Complex32::new(i as f32, 1.0)).collect();
+    let feedback: Vec<Complex32> = (0..len / 2).map(|i| Complex32::new(0.0, i as f32)).collect();
+    let code_value = run("(i: Float) => (fft(i).re + out_prev(i).re * 0.5, out_prev(i).im * 0.5)").unwrap();
+    assert!(code_value.mask.is_none());
+    let modulation = vec![];
+    let resource = Resource {
+        feedback: &feedback,
+        ..Resource::new(&complex, &modulation)
+    };
+    let result = code_value.collect(0..len, &resource).unwrap();
+    for (i, value) in result.into_iter().enumerate() {
+        let value: Complex32 = value.try_into().unwrap();
+        let expected = if i < len / 2 { i as f32 * 0.5 } else { 0.0 };
+        assert_eq!(value, Complex32::new(i as f32, expected), "bin {}", i);
+    }
+}
+
+#[test]
+fn test_state() {
+    let len = 16;
+    let complex: Vec<Complex32> = vec![Complex32::new(1.0, 0.0); len];
+    let state = State::new(len);
+    let modulation = vec![];
+
+    // Frames are a tenth of a second apart, and the accumulator fades by 60 dB over one frame
+    let code_value = run("(i: Float) => (slew(2, fft(i).re) + accum(0.1, 1), accum(1e9, i))").unwrap();
+    assert!(code_value.mask.is_none());
+    let resource = |state| Resource {
+        sample_rate: 1000.0,
+        window_size: 400,
+        overlap: 4,
+        state,
+        ..Resource::new(&complex, &modulation)
+    };
+    let frames = [(0.2 + 1.0, 1.0), (0.4 + 1.001, 2.0), (0.6 + 1.001001, 3.0)];
+    for run in 0..2 {
+        for (frame, (re, im)) in frames.into_iter().enumerate() {
+            let result = code_value.collect(0..len, &resource(Some(&state))).unwrap();
+            for (i, value) in result.into_iter().enumerate() {
+                let value: Complex32 = value.try_into().unwrap();
+                let message = format!("bin {} of frame {} in run {}", i, frame, run);
+                assert!((value.re - re).abs() < 1e-4, "{}: {}", message, value);
+                assert!((value.im - im * i as f32).abs() < 1e-3, "{}: {}", message, value);
+            }
+        }
+        state.reset();
+    }
+
+    // Without state every call passes its input through
+    let result = code_value.collect(0..len, &resource(None)).unwrap();
+    for (i, value) in result.into_iter().enumerate() {
+        let value: Complex32 = value.try_into().unwrap();
+        assert_eq!(value, Complex32::new(2.0, i as f32), "bin {}", i);
+    }
+}
+
+#[test]
+fn test_machine() {
+    let len = 8;
+    let code = "{ init: (0, 0), step: (s: (Float, Float), i: Float) => let (peak: Float, count: Float) = s in let level: Float = fft(i).norm in let next: Float = if level > peak then level else peak * 0.5 in ((next, count + 1), (next, count)) }";
+    let code_value = run(code).unwrap();
+    assert_eq!(code_value.output, Output::Mono);
+    assert!(code_value.mask.is_none());
+    let state = State::new(len);
+    let modulation = vec![];
+    let frame = |level: f32, state| {
+        let complex = vec![Complex32::new(0.0, level); len];
+        let resource = Resource {
+            state,
+            ..Resource::new(&complex, &modulation)
+        };
+        let result = code_value.collect(0..len, &resource).unwrap();
+        let first: Complex32 = result[0].clone().try_into().unwrap();
+        for value in result {
+            assert_eq!(Complex32::try_from(value).unwrap(), first);
+        }
+        first
+    };
+
+    // Peaks are held at half their level, and the state counts frames
+    assert_eq!(frame(1.0, Some(&state)), Complex32::new(1.0, 0.0));
+    assert_eq!(frame(0.0, Some(&state)), Complex32::new(0.5, 1.0));
+    assert_eq!(frame(0.75, Some(&state)), Complex32::new(0.75, 2.0));
+    state.reset();
+    assert_eq!(frame(0.0, Some(&state)), Complex32::new(0.0, 0.0));
+
+    // Without state every frame starts over
+    assert_eq!(frame(0.75, None), Complex32::new(0.75, 0.0));
+    assert_eq!(frame(0.5, None), Complex32::new(0.5, 0.0));
+
+    // Programs with state can give stereo bins too
+    let code = "{ init: true, step: (s: Bool, i: Float) => (if s then false else true, if s then (fft(i), (0, 0)) else ((0, 0), fft(i))) }";
+    assert_eq!(run(code).unwrap().output, Output::Stereo);
+}
+
+/// Spectra of Hann-windowed frames of a sine at `frequency` bins, `hop` samples apart
+fn sine_frames(frequency: f32, window_size: usize, hop: usize, count: usize) -> Vec<Vec<Complex32>> {
+    let plan = RealFftPlanner::<f32>::new().plan_fft_forward(window_size);
+    (0..count)
+        .map(|t| {
+            let mut window: Vec<f32> = (0..window_size)
+                .map(|n| {
+                    let hann = 0.5 - 0.5 * (TAU * n as f32 / window_size as f32).cos();
+                    hann * (TAU * frequency * (t * hop + n) as f32 / window_size as f32).sin()
+                })
+                .collect();
+            let mut spectrum = plan.make_output_vec();
+            plan.process(&mut window, &mut spectrum).unwrap();
+            spectrum
+        })
+        .collect()
+}
+
+#[test]
+fn test_phase_vocoder() {
+    let (window_size, overlap, sample_rate) = (512, 4, 48000.0);
+    let len = window_size / 2 + 1;
+    let bin_width = sample_rate / window_size as f32;
+    let frames = sine_frames(40.3, window_size, window_size / overlap, 6);
+    let modulation = vec![];
+    let collect = |code_value: &dusk_phantom::lang::Program, fft: &Vec<Complex32>, state: &State| {
+        let resource = Resource {
+            sample_rate,
+            window_size,
+            overlap,
+            state: Some(state),
+            ..Resource::new(fft, &modulation)
+        };
+        let result = code_value.collect(0..len, &resource).unwrap();
+        state.push(fft);
+        result
+            .into_iter()
+            .map(|value| Complex32::try_from(value).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    // The sine is measured at its own frequency from the second frame on, before which every bin
+    // is at its centre
+    let analysis = run("(i: Float) => (inst_freq(i), mag(i))").unwrap();
+    let state = State::new(len);
+    for (t, frame) in frames.iter().enumerate() {
+        let result = collect(&analysis, frame, &state);
+        for k in [39, 40, 41] {
+            let expected = if t == 0 { k as f32 } else { 40.3 };
+            assert!((result[k].re / bin_width - expected).abs() < 0.01, "bin {} of frame {}: {}", k, t, result[k]);
+        }
+        assert!((result[40].im - frame[40].norm()).abs() < 1e-3);
+    }
+
+    // Shifted up by a fifth, the output peaks and turns at the shifted frequency
+    let shifter = run("(i: Float) => synth(mag(i / 1.5), inst_freq(i / 1.5) * 1.5)").unwrap();
+    let (state, measure) = (State::new(len), State::new(len));
+    for (t, frame) in frames.iter().enumerate() {
+        let output = collect(&shifter, frame, &state);
+        let peak = (0..len).max_by(|a, b| output[*a].norm().total_cmp(&output[*b].norm())).unwrap();
+        assert_eq!(peak, 60, "frame {}", t);
+        let measured = collect(&analysis, &output, &measure)[peak].re / bin_width;
+        if t == 0 {
+            continue;
+        }
+
+        // Bins around the peak keep the alternating phases of a windowed sine
+        for k in peak - 1..=peak {
+            let turn = (output[k + 1] / output[k]).arg();
+            assert!(turn.abs() > TAU / 2.0 - 0.01, "bins {} and {} turn by {}", k, k + 1, turn);
+        }
+        if t >= 2 {
+            assert!((measured - 40.3 * 1.5).abs() < 0.01, "frame {} measured at {}", t, measured);
+        }
+    }
+
+    // A constant partial is still synthesised bin by bin, turning by half a cycle between them
+    let partial = run("(i: Float) => synth(0.5, 40)").unwrap();
+    let state = State::new(len);
+    for frame in &frames {
+        let output = collect(&partial, frame, &state);
+        let turn = (output[41] / output[40]).arg();
+        assert!(turn.abs() > TAU / 2.0 - 0.01, "bins 40 and 41 turn by {}", turn);
+    }
+}
+
+#[test]
+fn test_envelope() {
+    let len = 8;
+    let complex: Vec<Complex32> = (0..len).map(|i| Complex32::from_polar(i as f32, i as f32)).collect();
+    let envelope: Vec<f32> = (0..len).map(|i| 2.0 * i as f32).collect();
+    let modulation = vec![];
+    let resource = |envelope| Resource {
+        envelope,
+        ..Resource::new(&complex, &modulation)
+    };
+
+    // Shifting the fine structure under the envelope, the envelope is interpolated linearly
+    let code_value = run("(i: Float) => (fine(i * 0.5) * env(i), env(i + 0.25))").unwrap();
+    assert!(code_value.mask.is_none());
+    let result = code_value.collect(0..len, &resource(&envelope)).unwrap();
+    for (i, value) in result.into_iter().enumerate() {
+        let value: Complex32 = value.try_into().unwrap();
+        let fine = if i == 0 { 0.0 } else { 0.5 };
+        let expected = Complex32::new(fine * 2.0 * i as f32, if i + 1 < len { 2.0 * i as f32 + 0.5 } else { 0.0 });
+        assert!((value - expected).norm() < 1e-5, "bin {}: {} != {}", i, value, expected);
+    }
+
+    // Without an envelope both are silent
+    let result = code_value.collect(0..len, &resource(&[])).unwrap();
+    assert!(result.into_iter().all(|value| Complex32::try_from(value).unwrap() == Complex32::default()));
+}
+
+#[test]
+fn test_hpss() {
+    let len = 8;
+    let complex: Vec<Complex32> = (0..len).map(|i| Complex32::from_polar(i as f32, i as f32)).collect();
+    let harmonic: Vec<f32> = (0..len).map(|i| i as f32 / len as f32).collect();
+    let modulation = vec![];
+    let resource = |harmonic| Resource {
+        harmonic,
+        ..Resource::new(&complex, &modulation)
+    };
+
+    // Each part takes its fraction of the bin, and together they give it back
+    let harm = run("(i: Float) => harm(i)").unwrap();
+    let perc = run("(i: Float) => perc(i)").unwrap();
+    let sum = run("(i: Float) => (harm(i).re + perc(i).re, harm(i).im + perc(i).im)").unwrap();
+    assert!(harm.mask.is_none() && perc.mask.is_none());
+    let results = [&harm, &perc, &sum].map(|code_value| code_value.collect(0..len, &resource(&harmonic)).unwrap());
+    for (i, ((harm, perc), sum)) in results[0].iter().zip(&results[1]).zip(&results[2]).enumerate() {
+        let [harm, perc, sum]: [Complex32; 3] = [harm, perc, sum].map(|value| value.clone().try_into().unwrap());
+        let fraction = i as f32 / len as f32;
+        assert!((harm - complex[i] * fraction).norm() < 1e-5, "bin {}: {}", i, harm);
+        assert!((perc - complex[i] * (1.0 - fraction)).norm() < 1e-5, "bin {}: {}", i, perc);
+        assert!((sum - complex[i]).norm() < 1e-5, "bin {}: {}", i, sum);
+    }
+
+    // Without a separation everything is harmonic
+    let result = perc.collect(0..len, &resource(&[])).unwrap();
+    assert!(result.into_iter().all(|value| Complex32::try_from(value).unwrap() == Complex32::default()));
+}
+
+#[test]
+fn test_features() {
+    let len = 8;
+    let complex: Vec<Complex32> = (0..len).map(|i| Complex32::new(if i == 2 || i == 6 { 1.0 } else { 0.0 }, 0.0)).collect();
+    let modulation = vec![];
+    let resource = Resource {
+        features: Features::analyse(&complex, &[0.0, 0.0, 2.0]),
+        ..Resource::new(&complex, &modulation)
+    };
+
+    // Every descriptor is read as a symbol, the centroid also in Hz
+    let code = "(i: Float) => (centroid + centroid_hz / 10000 + flatness + flux * 10 + rolloff * 100, energy + rms * 10 + peak_bin * 100)";
+    let code_value = run(code).unwrap();
+    assert!(code_value.mask.is_none());
+    let bin_width = 44100.0 / 2048.0;
+    let expected = Complex32::new(
+        4.0 + 4.0 * bin_width / 10000.0 + 0.0 + 10.0 + 600.0,
+        2.0 + 5.0 + 200.0,
+    );
+    for value in code_value.collect(0..len, &resource).unwrap() {
+        let value: Complex32 = value.try_into().unwrap();
+        assert!((value - expected).norm() < 1e-3, "{} != {}", value, expected);
+    }
+
+    // Brighten when the centroid drops
+    let code = "(i: Float) => (fft(i).norm * (if centroid < 16 then 1 + i / 8 else 1), fft(i).angle).polar";
+    let code_value = run(code).unwrap();
+    let value: Complex32 = code_value.collect(6..7, &resource).unwrap().remove(0).try_into().unwrap();
+    assert!((value - Complex32::new(1.75, 0.0)).norm() < 1e-5, "{}", value);
+}
+
+#[test]
+fn test_pitch() {
+    // Harmonics of 8 bins, each quieter than the one below it
+    let len = 128;
+    let complex: Vec<Complex32> = (0..len)
+        .map(|i| Complex32::new(if i > 0 && i % 8 == 0 { 8.0 / i as f32 } else { 0.0 }, 0.0))
+        .collect();
+    let modulation = vec![];
+    let resource = Resource {
+        features: Features::analyse(&complex, &[]),
+        ..Resource::new(&complex, &modulation)
+    };
+
+    // The fundamental is read in Hz, and bands relative to it in bins
+    let code = "(i: Float) => (f0 + f0_conf * 1000, harmonic_of(i) * 100 + harmonic_dist(i))";
+    let code_value = run(code).unwrap();
+    let bin_width = 44100.0 / 2048.0;
+    for (i, harmonic, dist) in [(8, 1.0, 0.0), (21, 3.0, -3.0), (35, 4.0, 3.0), (2, 0.0, 2.0)] {
+        let value = Complex32::new(8.0 * bin_width + 1000.0, harmonic * 100.0 + dist);
+        let actual: Complex32 = code_value.collect(i..i + 1, &resource).unwrap().remove(0).try_into().unwrap();
+        assert!((actual - value).norm() < 1e-2, "{}: {} != {}", i, actual, value);
+    }
+
+    // Keep only the partials, with a band either side
+    let code = "(i: Float) => (fft(i).norm * (if harmonic_dist(i) < 2 then if harmonic_dist(i) > -2 then 1 else 0 else 0), fft(i).angle).polar";
+    let code_value = run(code).unwrap();
+    let values = code_value.collect(0..len, &resource).unwrap();
+    for (i, value) in values.into_iter().enumerate() {
+        let value: Complex32 = value.try_into().unwrap();
+        assert!((value - complex[i]).norm() < 1e-5, "{}: {}", i, value);
+    }
+}
+
+#[test]
+fn test_onset() {
+    let len = 16;
+    let complex: Vec<Complex32> = (0..len).map(|i| Complex32::new(1.0, i as f32)).collect();
+    let modulation = vec![];
+    let mut detector = OnsetDetector::default();
+    detector.detect(&vec![Complex32::default(); len], &[], 0.1);
+    let onset = detector.detect(&complex, &[], 0.1);
+    assert!(onset.detected);
+    let steady = detector.detect(&complex, &[0.0; 16], 0.1);
+    assert!(!steady.detected);
+    for (onset, expected) in [(onset, Complex32::new(0.0, 1.0)), (steady, Complex32::new(0.1, 0.0))] {
+        let resource = Resource {
+            features: Features { onset, ..Default::default() },
+            ..Resource::new(&complex, &modulation)
+        };
+
+        // Silence everything but attacks, reading how long ago the last one was otherwise
+        let code = "(i: Float) => if onset then (0, onset_strength) else (since_onset, 0)";
+        let code_value = run(code).unwrap();
+        for value in code_value.collect(0..len, &resource).unwrap() {
+            let value: Complex32 = value.try_into().unwrap();
+            assert!((value - expected).norm() < 1e-5, "{} != {}", value, expected);
+        }
+    }
+}
+
+#[test]
+fn test_noise() {
+    let len = 64;
+    let complex: Vec<Complex32> = vec![Complex32::new(1.0, 0.0); len];
+    let modulation = vec![];
+    let resource = |frame_seed| Resource {
+        frame_seed,
+        ..Resource::new(&complex, &modulation)
+    };
+
+    // Noise with a random phase in every band, the same for the same frame
+    let code = "(i: Float) => (20 / (i + 50), rand_phase(i)).polar";
+    let code_value = run(code).unwrap();
+    let collect = |frame| -> Vec<Complex32> {
+        let values = code_value.collect(0..len, &resource(frame_seed(1, frame))).unwrap();
+        values.into_iter().map(|value| value.try_into().unwrap()).collect()
+    };
+    let first = collect(10);
+    assert_eq!(first, collect(10));
+    assert_ne!(first, collect(11));
+    for (i, value) in first.iter().enumerate() {
+        assert!((value.norm() - 20.0 / (i as f32 + 50.0)).abs() < 1e-5);
+        assert!(*value != first[0] || i == 0);
+    }
+
+    // Each seed of `rand` is a stream of its own
+    let code = "(i: Float) => (rand(1, i) + noise(i) * 2, rand(2, i))";
+    let code_value = run(code).unwrap();
+    let resource = resource(frame_seed(1, 10));
+    for (i, value) in code_value.collect(0..len, &resource).unwrap().into_iter().enumerate() {
+        let value: Complex32 = value.try_into().unwrap();
+        let (key, f) = (resource.frame_seed, i as f32);
+        let expected = Complex32::new(rand_at(key, 0, 1.0, f) + noise_at(key, 0, f) * 2.0, rand_at(key, 0, 2.0, f));
+        assert_eq!(value, expected);
+    }
+}
+
+#[test]
+fn test_transport() {
+    let len = 8;
+    let complex: Vec<Complex32> = vec![Complex32::new(1.0, 0.0); len];
+    let modulation = vec![];
+    let resource = |beat, clock| Resource {
+        beat,
+        clock,
+        ..Resource::new(&complex, &modulation)
+    };
+    let value = |code: &str, beat, clock| -> Complex32 {
+        let code_value = run(code).unwrap();
+        code_value.collect(0..1, &resource(beat, clock)).unwrap().remove(0).try_into().unwrap()
+    };
+
+    // Bars follow the time signature, in quarter notes
+    let clock = Clock {
+        loop_range: Some((16.0, 32.0)),
+        ..Clock::new(140.0, true, 6, 8, 10.5)
+    };
+    let code = "(i: Float) => (tempo + bar * 1000 + bar_beat / 10, time_sig_num * 10 + time_sig_den)";
+    assert_eq!(value(code, 10.5, clock), Complex32::new(3140.15, 68.0));
+    let code = "(i: Float) => (if playing then loop_start else 1, if loop then loop_end else 1)";
+    assert_eq!(value(code, 10.5, clock), Complex32::new(16.0, 32.0));
+    assert_eq!(value(code, 10.5, Clock::default()), Complex32::new(1.0, 1.0));
+
+    // LFOs and sequences follow the beat
+    let code = "(i: Float) => (lfo_sin(2) + lfo_saw(4) * 10, lfo_square(1) + step_seq(3, 0.5) * 10)";
+    for (beat, expected) in [(0.0, Complex32::new(-10.0, 1.0)), (2.5, Complex32::new(3.5, 19.0))] {
+        let actual = value(code, beat, clock);
+        assert!((actual - expected).norm() < 1e-4, "{}: {} != {}", beat, actual, expected);
+    }
+
+    // Gate every other beat
+    let code = "(i: Float) => (fft(i).norm * (if lfo_square(2) > 0 then 1 else 0), fft(i).angle).polar";
+    assert_eq!(value(code, 0.5, clock), Complex32::new(1.0, 0.0));
+    assert_eq!(value(code, 1.5, clock), Complex32::new(0.0, 0.0));
+}
@@ -0,0 +1,212 @@
+mod common;
+
+use common::ProgramGen;
+use dusk_phantom::lang::{
+    run, simp, specialise, Clock, Feature, Features, History, Lib, Onset, Program, Resource, Source, State, StateOp, Stereo,
+    Term, Timing, ValueType, Wave,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use realfft::num_complex::Complex32;
+
+/// Evaluate every bin of a program, converting results like the plugin does
+fn evaluate(program: &Program, rng: &mut StdRng) {
+    let len = 32;
+    let fft: Vec<Complex32> = (0..len)
+        .map(|_| Complex32::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+        .collect();
+    let modulation: Vec<f32> = (0..16).map(|_| rng.gen_range(-1.0..1.0)).collect();
+    let side: Vec<Complex32> = fft.iter().map(|x| x * 0.5).collect();
+    let stereo = rng.gen_bool(0.5).then(|| Stereo {
+        left: &fft,
+        right: &fft[..len / 2],
+        mid: &fft,
+        side: &side,
+        channel: rng.gen_range(0..2),
+        mid_side: rng.gen(),
+    });
+    let mut history = History::new(4, len);
+    for _ in 0..rng.gen_range(0..6) {
+        history.push(&side[..rng.gen_range(0..len)]);
+    }
+    let state = State::new(rng.gen_range(0..=len));
+    if rng.gen() {
+        state.push(&side[..rng.gen_range(0..len)]);
+    }
+    let beat = rng.gen_range(0.0..64.0);
+    let resource = Resource {
+        beat,
+        second: rng.gen_range(0.0..32.0),
+        clock: Clock {
+            loop_range: rng.gen_bool(0.5).then_some((0.0, 8.0)),
+            ..Clock::new(rng.gen_range(40.0..200.0), rng.gen(), rng.gen_range(-1..9), rng.gen_range(-1..17), beat)
+        },
+        stereo,
+        sidechain: &side[..rng.gen_range(0..len)],
+        history: rng.gen_bool(0.5).then_some(&history),
+        feedback: &fft[..rng.gen_range(0..len)],
+        state: rng.gen_bool(0.5).then_some(&state),
+        envelope: &modulation[..rng.gen_range(0..modulation.len())],
+        harmonic: &modulation[..rng.gen_range(0..modulation.len())],
+        frame_seed: rng.gen(),
+        features: Features {
+            onset: Onset {
+                detected: rng.gen(),
+                strength: rng.gen_range(0.0..1.0),
+                since: rng.gen_range(0.0..8.0),
+            },
+            ..Features::analyse(&fft, &modulation)
+        },
+        ..Resource::new(&fft, &modulation)
+    };
+    if let Ok(values) = program.collect(0..len, &resource) {
+        for value in values {
+            let _ = Complex32::try_from(value);
+        }
+    }
+    if let Some(mask) = &program.mask {
+        let mut gain = vec![Complex32::default(); len];
+        let _ = mask.compute(&mut Vec::new(), &resource, &mut gain);
+    }
+    let _ = specialise(&program.term, &resource);
+}
+
+/// Replace, drop or insert tokens in the source, which mostly makes it ill-typed
+fn mutate(code: &str, rng: &mut StdRng) -> String {
+    const TOKENS: [&str; 16] = [
+        "true", "false", "0", "-1", "1e30", "i", "fft", "param", "beat", "(0, 0)", "(i: Float) => i",
+        ".re", ".polar", "if", ")", "*",
+    ];
+    let mut tokens: Vec<String> = code.split(' ').map(String::from).collect();
+    for _ in 0..rng.gen_range(1..4) {
+        let k = rng.gen_range(0..tokens.len());
+        let token = TOKENS[rng.gen_range(0..TOKENS.len())].to_string();
+        match rng.gen_range(0..3) {
+            0 => tokens[k] = token,
+            1 => tokens[k] = tokens[k].replace(['(', ')', ','], ""),
+            _ => tokens.insert(k, token),
+        }
+    }
+    tokens.join(" ")
+}
+
+/// Random terms that skip the type checker, functions are never applied through variables so
+/// that evaluation terminates
+fn term(rng: &mut StdRng, depth: usize) -> Term {
+    const LIBS: [Lib; 46] = [
+        Lib::Fft,
+        Lib::Param,
+        Lib::Beat,
+        Lib::Sec,
+        Lib::Add,
+        Lib::Sub,
+        Lib::Mul,
+        Lib::Div,
+        Lib::Mod,
+        Lib::Sin,
+        Lib::Tan,
+        Lib::Re,
+        Lib::Norm,
+        Lib::Polar,
+        Lib::Lt,
+        Lib::Ge,
+        Lib::Add1(1.0),
+        Lib::Lt1(0.5),
+        Lib::AddI(i32::MAX),
+        Lib::MulI(i32::MIN),
+        Lib::ModI(-1),
+        Lib::DivI(0),
+        Lib::Channel,
+        Lib::IsSide,
+        Lib::FftOf(Source::Right),
+        Lib::Hist,
+        Lib::FftPrev,
+        Lib::FftOf(Source::Output),
+        Lib::State(StateOp::Follow, 0),
+        Lib::State(StateOp::Accum, 20),
+        Lib::InstFreq,
+        Lib::Synth,
+        Lib::Fine,
+        Lib::Perc,
+        Lib::Feature(Feature::CentroidHz),
+        Lib::Feature(Feature::Flux),
+        Lib::Feature(Feature::F0),
+        Lib::HarmonicDist,
+        Lib::Onset,
+        Lib::Feature(Feature::SinceOnset),
+        Lib::Noise,
+        Lib::Rand,
+        Lib::Timing(Timing::BarBeat),
+        Lib::Loop,
+        Lib::Lfo(Wave::Saw),
+        Lib::StepSeq,
+    ];
+    let leaf = depth == 0 || rng.gen_bool(0.2);
+    if leaf {
+        return match rng.gen_range(0..4) {
+            0 => Term::Float(rng.gen_range(-4.0..40.0)),
+            1 => Term::Bool(rng.gen()),
+            2 => Term::Var(rng.gen_range(-1..3)),
+            _ => Term::Lib(LIBS[rng.gen_range(0..LIBS.len())].clone()),
+        };
+    }
+    match rng.gen_range(0..5) {
+        0 => Term::Tuple((0..rng.gen_range(0..4)).map(|_| term(rng, depth - 1)).collect()),
+        1 => {
+            let func = match term(rng, depth - 1) {
+                Term::Var(_) => Term::Lib(Lib::Norm),
+                func => func,
+            };
+            Term::Apply(func.into(), term(rng, depth - 1).into())
+        }
+        2 => Term::Func(ValueType::Float.into(), "x".into(), term(rng, depth - 1).into()),
+        3 => Term::Let(
+            ValueType::Float.into(),
+            "x".into(),
+            term(rng, depth - 1).into(),
+            term(rng, depth - 1).into(),
+        ),
+        _ => Term::Alt(
+            term(rng, depth - 1).into(),
+            term(rng, depth - 1).into(),
+            term(rng, depth - 1).into(),
+        ),
+    }
+}
+
+#[test]
+fn test_fuzz_well_typed() {
+    let mut rng = StdRng::seed_from_u64(31);
+    for _ in 0..2000 {
+        let code = ProgramGen::new(&mut rng).program(5);
+        let program = run(&code).unwrap_or_else(|err| panic!("failed to run {}: {}", code, err));
+        evaluate(&program, &mut rng);
+    }
+}
+
+#[test]
+fn test_fuzz_ill_typed() {
+    let mut rng = StdRng::seed_from_u64(31);
+    let mut accepted = 0;
+    for _ in 0..3000 {
+        let code = ProgramGen::new(&mut rng).program(4);
+        let code = mutate(&code, &mut rng);
+        if let Ok(program) = run(&code) {
+            accepted += 1;
+            evaluate(&program, &mut rng);
+        }
+    }
+    assert!(accepted > 0, "no mutated program was accepted");
+}
+
+#[test]
+fn test_fuzz_unchecked_terms() {
+    let mut rng = StdRng::seed_from_u64(31);
+    for _ in 0..3000 {
+        let term = term(&mut rng, 5);
+        if let Ok(term) = simp(term.clone()) {
+            evaluate(&Program::new(term), &mut rng);
+        }
+        evaluate(&Program::new(term), &mut rng);
+    }
+}
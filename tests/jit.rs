@@ -0,0 +1,254 @@
+#![cfg(feature = "jit")]
+
+mod common;
+
+use common::{assert_jit_matches, assert_jit_matches_on, ProgramGen};
+use dusk_phantom::lang::jit::JitProgram;
+use dusk_phantom::lang::{run, Clock, Features, History, OnsetDetector, Resource, Source, State, Stereo};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use realfft::num_complex::Complex32;
+
+#[test]
+fn test_jit_lp() {
+    let len = 1024;
+    let complex: Vec<Complex32> = vec![Complex32::new(1.0, 0.0); len];
+    let code = "let lp: Float -> Float -> Float = (l: Float) => (i: Float) => if i < l then 1 else 0 in (i: Float) => (fft(i).norm * lp(800)(i), fft(i).angle).polar";
+    let code_value = run(code).unwrap();
+    let modulation = vec![];
+    let resource = Resource::new(&complex, &modulation);
+    let result = assert_jit_matches(&code_value, &resource);
+    for (i, value) in result.iter().enumerate() {
+        let expected = if i < 800 { 1.0 } else { 0.0 };
+        assert_eq!(value.re, expected);
+        assert_eq!(value.im, 0.0);
+    }
+}
+
+#[test]
+fn test_jit_fallback() {
+    let code = "(i: Float) => ((if i < 10 then sin else cos)(i), 0)";
+    let code_value = run(code).unwrap();
+    assert!(JitProgram::compile(&code_value).is_err());
+}
+
+#[test]
+fn test_jit_prologue() {
+    let len = 64;
+    let code = "(i: Float) => if param(1) > 0.5 then (fft(i).norm * (param(3) * 2000), sin(beat * 6.28)) else (i, 0)";
+    let code_value = run(code).unwrap();
+    assert_eq!(code_value.prologue.len(), 3);
+    let fft: Vec<Complex32> = (0..len).map(|i| Complex32::new(i as f32, 1.0)).collect();
+    for gate in [0.0, 1.0] {
+        let modulation = vec![0.0, gate, 0.0, 0.25];
+        let resource = Resource {
+            beat: 1.5,
+            ..Resource::new(&fft, &modulation)
+        };
+        assert_jit_matches(&code_value, &resource);
+    }
+}
+
+#[test]
+fn test_jit_matches_eval() {
+    let mut rng = StdRng::seed_from_u64(26);
+    let len = 64;
+    let mut compiled = 0;
+    for _ in 0..300 {
+        let code = ProgramGen::new(&mut rng).program(4);
+        let code_value = run(&code).unwrap_or_else(|err| panic!("failed to run {}: {}", code, err));
+        if JitProgram::compile(&code_value).is_err() {
+            continue;
+        }
+        compiled += 1;
+
+        let fft: Vec<Complex32> = (0..len)
+            .map(|_| Complex32::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+            .collect();
+        let modulation: Vec<f32> = (0..16).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let resource = Resource {
+            beat: rng.gen_range(0.0..64.0),
+            second: rng.gen_range(0.0..32.0),
+            ..Resource::new(&fft, &modulation)
+        };
+        assert_jit_matches(&code_value, &resource);
+    }
+    assert!(compiled > 200, "only {} programs compiled", compiled);
+}
+
+#[test]
+fn test_jit_interp() {
+    let mut rng = StdRng::seed_from_u64(33);
+    let len = 64;
+    let fft: Vec<Complex32> = (0..len)
+        .map(|_| Complex32::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+        .collect();
+    let modulation: Vec<f32> = (0..16).map(|_| rng.gen_range(-1.0..1.0)).collect();
+    let resource = Resource::new(&fft, &modulation);
+    for mode in ["nearest", "linear", "cosine", "cubic", "sinc"] {
+        let code = format!("(i: Float) => (fft_{0}(i * 0.7).re * param_{0}(i * 0.3), fft_{0}(i * 0.7).im)", mode);
+        let code_value = run(&code).unwrap();
+        assert_jit_matches(&code_value, &resource);
+    }
+}
+
+#[test]
+fn test_jit_stft_settings() {
+    let code = "(i: Float) => (fft(bin(hz(i) * 0.5)).norm * (wsize / hop), nyquist - srate / 2)";
+    let code_value = run(code).unwrap();
+    let len = 257;
+    let fft: Vec<Complex32> = (0..len).map(|i| Complex32::new(i as f32, 1.0)).collect();
+    let modulation = vec![];
+    let resource = Resource {
+        sample_rate: 48000.0,
+        window_size: 512,
+        overlap: 4,
+        ..Resource::new(&fft, &modulation)
+    };
+    assert_jit_matches(&code_value, &resource);
+}
+
+#[test]
+fn test_jit_stereo() {
+    let code = "(i: Float) => if is_side then (channel, fft_mid(i).im + side(i).re + out_prev(i).im) else (if is_mid then fft_l(i) else fft_side(i))";
+    let code_value = run(code).unwrap();
+    let len = 64;
+    let left: Vec<Complex32> = (0..len).map(|i| Complex32::new(i as f32, 1.0)).collect();
+    let right: Vec<Complex32> = (0..len).map(|i| Complex32::new(1.0, i as f32)).collect();
+    let mid: Vec<Complex32> = left.iter().zip(&right).map(|(l, r)| (l + r) * 0.5).collect();
+    let side: Vec<Complex32> = left.iter().zip(&right).map(|(l, r)| (l - r) * 0.5).collect();
+    let modulation = vec![];
+    for (channel, mid_side) in [(0, false), (1, false), (0, true), (1, true)] {
+        let resource = Resource {
+            stereo: Some(Stereo {
+                left: &left,
+                right: &right,
+                mid: &mid,
+                side: &side,
+                channel,
+                mid_side,
+            }),
+            sidechain: &right[..len / 2],
+            feedback: &mid[..len / 4],
+            ..Resource::new(&left, &modulation)
+        };
+        assert_eq!(resource.spectrum(Source::Right), &right[..]);
+        assert_jit_matches(&code_value, &resource);
+    }
+}
+
+#[test]
+fn test_jit_stereo_output() {
+    let code_value = run("(i: Float) => (fft(i), fft_r(i))").unwrap();
+    assert!(JitProgram::compile(&code_value).is_err());
+}
+
+#[test]
+fn test_jit_history() {
+    let len = 64;
+    let mut rng = StdRng::seed_from_u64(39);
+    let mut random = || -> Vec<Complex32> {
+        (0..len)
+            .map(|_| Complex32::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+            .collect()
+    };
+    let mut history = History::new(4, len);
+    for _ in 0..6 {
+        history.push(&random());
+    }
+    let fft = random();
+    let modulation = vec![];
+    let code = "(i: Float) => let k: Float = i / 16 in (hist(k)(i * 0.7).re, fft_prev(4.5 - k, i).im + hist(2)(i).norm)";
+    let code_value = run(code).unwrap();
+    for history in [Some(&history), None] {
+        let resource = Resource {
+            history,
+            ..Resource::new(&fft, &modulation)
+        };
+        assert_jit_matches(&code_value, &resource);
+    }
+}
+
+#[test]
+fn test_jit_state() {
+    let len = 64;
+    let mut rng = StdRng::seed_from_u64(41);
+    let code = "(i: Float) => (follow(0.05, 0.2, fft(i).norm), slew(3, fft(i).angle) + accum(0.3, fft(i).re))";
+    let code_value = run(code).unwrap();
+    let (eval_state, jit_state) = (State::new(len), State::new(len));
+    let modulation = vec![];
+    for _ in 0..8 {
+        let fft: Vec<Complex32> = (0..len)
+            .map(|_| Complex32::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+            .collect();
+        let resource = |state| Resource {
+            sample_rate: 1000.0,
+            window_size: 400,
+            overlap: 4,
+            state: Some(state),
+            ..Resource::new(&fft, &modulation)
+        };
+        assert_jit_matches_on(&code_value, &resource(&eval_state), &resource(&jit_state));
+    }
+}
+
+#[test]
+fn test_jit_vocoder() {
+    let len = 64;
+    let mut rng = StdRng::seed_from_u64(43);
+    let code = "(i: Float) => let k: Float = i / 1.5 in synth(mag(k) + mag(i), inst_freq(k) * 1.5)";
+    let code_value = run(code).unwrap();
+    let (eval_state, jit_state) = (State::new(len), State::new(len));
+    let modulation = vec![];
+    for _ in 0..8 {
+        let fft: Vec<Complex32> = (0..len)
+            .map(|_| Complex32::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+            .collect();
+        let resource = |state| Resource {
+            window_size: 128,
+            state: Some(state),
+            ..Resource::new(&fft, &modulation)
+        };
+        assert_jit_matches_on(&code_value, &resource(&eval_state), &resource(&jit_state));
+        eval_state.push(&fft);
+        jit_state.push(&fft);
+    }
+}
+
+#[test]
+fn test_jit_analysis() {
+    let len = 64;
+    let mut rng = StdRng::seed_from_u64(44);
+    let fft: Vec<Complex32> = (0..len)
+        .map(|_| Complex32::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+        .collect();
+    let envelope: Vec<f32> = (0..len).map(|_| rng.gen_range(0.0..1.0)).collect();
+    let modulation = vec![];
+    let resource = Resource {
+        beat: 13.25,
+        clock: Clock {
+            loop_range: Some((8.0, 16.0)),
+            ..Clock::new(97.0, true, 7, 8, 13.25)
+        },
+        envelope: &envelope[..len / 2],
+        harmonic: &envelope[len / 4..],
+        frame_seed: rng.gen(),
+        features: Features {
+            onset: OnsetDetector::default().detect(&fft, &[], 0.5),
+            ..Features::analyse(&fft, &envelope)
+        },
+        ..Resource::new(&fft, &modulation)
+    };
+    for code in [
+        "(i: Float) => (fine(i * 1.3) * env(i), fft(i * 1.3).angle).polar",
+        "(i: Float) => (harm(i * 1.3).re + perc(i / 1.3).re, harm(i / 1.3).im - perc(i * 1.3).im)",
+        "(i: Float) => (centroid_hz * i + flatness - flux, rolloff * energy + rms / (peak_bin + centroid))",
+        "(i: Float) => (harmonic_of(i * 1.3) * f0_conf, harmonic_dist(i / 1.3) + f0)",
+        "(i: Float) => if onset then (onset_strength, i) else (since_onset, -i)",
+        "(i: Float) => (noise(i * 1.3) + rand(channel + 2, i), rand_phase(i / 1.3))",
+        "(i: Float) => (lfo_sin(i / 8) + lfo_saw(3) * lfo_square(i / 4) + step_seq(i / 4, 0.5), tempo + bar * bar_beat)",
+        "(i: Float) => if playing then (time_sig_num / time_sig_den, loop_end - loop_start) else (0, if loop then 1 else 0)",
+    ] {
+        assert_jit_matches(&run(code).unwrap(), &resource);
+    }
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use common::{close, ProgramGen};
+use dusk_phantom::lang::{normalise, rewrite, Output, Program, Resource, Term};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use realfft::num_complex::Complex32;
+
+#[test]
+fn test_rewrite_matches_normal_form() {
+    let mut rng = StdRng::seed_from_u64(29);
+    let len = 64;
+    let mut shared = 0;
+    for _ in 0..300 {
+        let code = ProgramGen::new(&mut rng).program(4);
+        let term = normalise(&code).unwrap_or_else(|err| panic!("failed to run {}: {}", code, err));
+
+        // Evaluate the normal form as is, without rewriting, hoisting or sharing
+        let before = Program {
+            term: term.clone(),
+            prologue: Vec::new(),
+            body: Term::Apply(term.clone().into(), Term::Var(0).into()),
+            mask: None,
+            output: Output::Mono,
+            machine: None,
+            uses_history: false,
+            uses_feedback: false,
+            uses_envelope: false,
+            uses_hpss: false,
+            uses_features: false,
+            uses_onset: false,
+        };
+        let after = Program::new(rewrite(term));
+        if matches!(after.body, Term::Let(_, _, _, _)) {
+            shared += 1;
+        }
+
+        let fft: Vec<Complex32> = (0..len)
+            .map(|_| Complex32::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0)))
+            .collect();
+        let modulation: Vec<f32> = (0..16).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let resource = Resource {
+            beat: rng.gen_range(0.0..64.0),
+            second: rng.gen_range(0.0..32.0),
+            ..Resource::new(&fft, &modulation)
+        };
+        let expected = before.collect(0..len, &resource).unwrap();
+        let actual = after.collect(0..len, &resource).unwrap();
+        for (i, (expected, actual)) in expected.into_iter().zip(actual).enumerate() {
+            let expected: Complex32 = expected.try_into().unwrap();
+            let actual: Complex32 = actual.try_into().unwrap();
+
+            assert!(
+                close(expected.re, actual.re) && close(expected.im, actual.im),
+                "bin {} of {}: normal form gives {}, rewritten gives {}\n{}",
+                i,
+                code,
+                expected,
+                actual,
+                after.body.pretty_term(),
+            );
+        }
+    }
+    assert!(shared > 20, "only {} programs had shared terms", shared);
+}